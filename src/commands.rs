@@ -1,72 +1,1203 @@
-use crate::filehost;
 use crate::textui;
+use crossterm::tty::IsTty;
+use matrix65::crt::Cartridge;
+use matrix65::filehost;
 use matrix65::io;
-use matrix65::serial;
-use parse_int::parse;
-use serialport::SerialPort;
-use std::io::{Read, Write};
+use matrix65::serial::{self, key_codes, ASCII_KEY_QUEUE};
+use matrix65::sid::SidTune;
+use matrix65::video::{self, VideoMode};
+use matrix65::{parse_address, parse_hex_bytes, LoadAddress, M65Communicator};
+use std::io::Write;
 
-pub fn reset<T: Read + Write>(port: &mut T, c64: bool) -> Result<(), anyhow::Error> {
-    serial::reset(port)?;
+/// How command results are printed
+#[derive(Debug, Clone, Copy)]
+pub struct Output {
+    /// Colour output with ANSI codes
+    pub color: bool,
+    /// Print JSON instead of text
+    pub json: bool,
+}
+
+pub fn reset(comm: &mut dyn M65Communicator, c64: bool, warm: bool) -> Result<(), anyhow::Error> {
+    if warm {
+        return Ok(comm.warm_reset()?);
+    }
+    comm.reset()?;
     if c64 {
-        serial::go64(port)?
+        comm.go64()?
     };
     Ok(())
 }
 
-pub fn peek<T: Read + Write>(
-    port: &mut T,
+/// Start machine code with SYS at a 16-bit address
+pub fn sys(comm: &mut dyn M65Communicator, address: &str) -> Result<(), anyhow::Error> {
+    let address = u16::try_from(parse_address(address)?)
+        .map_err(|_| anyhow::Error::msg("SYS address must be in the 16-bit address space"))?;
+    Ok(comm.sys(address)?)
+}
+
+/// Start machine code at `address` with the monitor, bypassing BASIC
+pub fn go(comm: &mut dyn M65Communicator, address: &str) -> Result<(), anyhow::Error> {
+    Ok(comm.jump(parse_address(address)?)?)
+}
+
+/// Transfer PRG to `address` instead of its load address, e.g. to banked RAM
+pub fn prg_at(
+    comm: &mut dyn M65Communicator,
+    file: &str,
+    address: &str,
+    reset: bool,
+    run: bool,
+    verify: bool,
+) -> Result<(), anyhow::Error> {
+    let (_, bytes) = io::load_prg(file)?;
+    let load_address = LoadAddress::at(parse_address(address)?);
+    Ok(comm.handle_prg_from_bytes(&bytes, load_address, reset, run, verify)?)
+}
+
+/// Start of BASIC in C64 mode, where `upload --stub` places its SYS line
+const C64_BASIC_START: u16 = 0x0801;
+
+/// Write PRG to its load address, or to `address`, without running it
+///
+/// With `raw`, the file has no load address header and is written as is.
+/// With `stub`, a BASIC line that SYSes to the start address is written
+/// to the start of C64 BASIC, so the code can be started with RUN.
+pub fn upload(
+    comm: &mut dyn M65Communicator,
+    file: &str,
+    address: Option<&str>,
+    raw: bool,
+    stub: bool,
+) -> Result<(), anyhow::Error> {
+    let (load_address, bytes) = match raw {
+        true => (None, io::load_bytes(file)?),
+        false => {
+            let (load_address, bytes) = io::load_prg(file)?;
+            (Some(load_address), bytes)
+        }
+    };
+    let start = match (address, load_address) {
+        (Some(address), _) => parse_address(address)?,
+        (None, Some(load_address)) => load_address.address(),
+        (None, None) => return Err(anyhow::Error::msg("raw files need an address to write to")),
+    };
+    if start as usize + bytes.len() > ADDRESS_SPACE_SIZE {
+        return Err(anyhow::Error::msg("upload beyond the 28-bit address space"));
+    }
+    let stub = match stub {
+        true => Some(basic_stub(start, bytes.len())?),
+        false => None,
+    };
+    comm.write_memory_far(start, &bytes)?;
+    match bytes.len() {
+        0 => println!("Nothing to write"),
+        n => println!(
+            "Wrote {} bytes to 0x{:07x}-0x{:07x}",
+            n,
+            start,
+            start as usize + n - 1
+        ),
+    }
+    if let Some(stub) = stub {
+        comm.write_memory(C64_BASIC_START, &stub)?;
+        println!(
+            "Wrote 10 SYS {} to 0x{:04x}; type RUN to start",
+            start, C64_BASIC_START
+        );
+    }
+    Ok(())
+}
+
+/// BASIC stub starting code at `start`, checked not to overlap the `length` bytes there
+fn basic_stub(start: u32, length: usize) -> Result<Vec<u8>, anyhow::Error> {
+    let entry = u16::try_from(start)
+        .map_err(|_| anyhow::Error::msg("a SYS stub needs code in the 16-bit address space"))?;
+    let stub = io::sys_stub(C64_BASIC_START, entry);
+    let stub_end = C64_BASIC_START as usize + stub.len();
+    if (start as usize) < stub_end && (C64_BASIC_START as usize) < start as usize + length {
+        return Err(anyhow::anyhow!(
+            "code at 0x{:04x} overlaps the SYS stub at 0x{:04x}-0x{:04x}",
+            start,
+            C64_BASIC_START,
+            stub_end - 1
+        ));
+    }
+    Ok(stub)
+}
+
+pub fn peek(
+    comm: &mut dyn M65Communicator,
     address: String,
     length: usize,
     outfile: Option<String>,
     disassemble: bool,
+    color: bool,
 ) -> Result<(), anyhow::Error> {
-    let start_address = parse::<u32>(&address)?;
-    let bytes = serial::read_memory(port, start_address, length)?;
+    let start_address = parse_address(&address)?;
     match outfile {
-        Some(name) => io::save_binary(&name, &bytes)?,
+        Some(name) if disassemble => {
+            let bytes = comm.read_memory(start_address, length)?;
+            let mut file = std::io::BufWriter::new(std::fs::File::create(&name)?);
+            for line in io::disassemble(&bytes, start_address)? {
+                writeln!(file, "{}", line)?;
+            }
+            file.flush()?;
+        }
+        // stream to the file so large regions need not fit in memory
+        Some(name) => {
+            let mut file = std::io::BufWriter::new(std::fs::File::create(&name)?);
+            comm.read_memory_to_writer(start_address, length, &mut file)?;
+            file.flush()?;
+        }
+        None if disassemble => {
+            let bytes = comm.read_memory(start_address, length)?;
+            print_disassembly(&bytes, start_address)?;
+        }
+        // print the hexdump as the bytes arrive
         None => {
-            if disassemble {
-                matrix65::io::disassemble(&bytes, start_address);
-            } else {
-                matrix65::io::hexdump(&bytes, 8);
+            let hexdump = match color {
+                true => io::hexdump_colored,
+                false => io::hexdump,
+            };
+            let mut address = start_address;
+            let mut result = Ok(());
+            comm.read_memory_with_progress(start_address, length, &mut |chunk| {
+                if result.is_ok() {
+                    result = hexdump(chunk, address, 8, &mut std::io::stdout());
+                }
+                address += chunk.len() as u32;
+            })?;
+            result?;
+        }
+    };
+    Ok(())
+}
+
+/// Restores the terminal when watching memory ends, also on errors
+struct RawModeGuard;
+
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        let _ = crossterm::terminal::disable_raw_mode();
+        let _ = crossterm::execute!(std::io::stdout(), crossterm::cursor::Show);
+    }
+}
+
+/// Repeatedly read memory and redraw it as a hexdump or disassembly
+///
+/// Stops when q, Esc, or Ctrl-C is pressed. The terminal is in raw mode
+/// meanwhile so that Ctrl-C is read as a key and does not abort a
+/// transfer with the CPU halted.
+pub fn watch(
+    comm: &mut dyn M65Communicator,
+    address: &str,
+    length: usize,
+    disassemble: bool,
+    color: bool,
+    interval: std::time::Duration,
+) -> Result<(), anyhow::Error> {
+    use crossterm::event::{self, Event, KeyCode, KeyModifiers};
+    use crossterm::{cursor, execute, terminal};
+    let start_address = parse_address(address)?;
+    terminal::enable_raw_mode()?;
+    let _guard = RawModeGuard;
+    execute!(std::io::stdout(), cursor::Hide)?;
+    loop {
+        let bytes = comm.read_memory(start_address, length)?;
+        let mut frame = Vec::new();
+        writeln!(
+            frame,
+            "Every {} ms: {} byte(s) at 0x{:07x} (q to stop)\n",
+            interval.as_millis(),
+            length,
+            start_address
+        )?;
+        if disassemble {
+            for line in io::disassemble(&bytes, start_address)? {
+                writeln!(frame, "{}", line)?;
+            }
+        } else if color {
+            io::hexdump_colored(&bytes, start_address, 8, &mut frame)?;
+        } else {
+            io::hexdump(&bytes, start_address, 8, &mut frame)?;
+        }
+        // raw mode needs explicit carriage returns
+        let frame = String::from_utf8_lossy(&frame).replace('\n', "\r\n");
+        let mut stdout = std::io::stdout();
+        execute!(
+            stdout,
+            cursor::MoveTo(0, 0),
+            terminal::Clear(terminal::ClearType::All)
+        )?;
+        write!(stdout, "{}", frame)?;
+        stdout.flush()?;
+        if event::poll(interval)? {
+            if let Event::Key(key) = event::read()? {
+                let ctrl_c =
+                    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                if ctrl_c || matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+                    return Ok(());
+                }
             }
         }
+    }
+}
+
+/// Print disassembled bytes
+fn print_disassembly(bytes: &[u8], start_address: u32) -> Result<(), anyhow::Error> {
+    for line in io::disassemble(bytes, start_address)? {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// Number of bytes from `address` up to and including `end`
+pub fn length_to_end(address: &str, end: &str) -> Result<usize, anyhow::Error> {
+    let (start, end) = (parse_address(address)?, parse_address(end)?);
+    if end < start {
+        return Err(anyhow::Error::msg("end address is before start address"));
+    }
+    Ok((end - start) as usize + 1)
+}
+
+/// Disassemble code by following jumps and branches from `address`
+///
+/// If `end` is given, code outside `address..=end` is not followed.
+/// Breaks in the flow are shown as empty lines.
+pub fn follow(
+    comm: &mut dyn M65Communicator,
+    address: String,
+    end: Option<String>,
+    max_instructions: usize,
+) -> Result<(), anyhow::Error> {
+    let start = u16::try_from(parse_address(&address)?)?;
+    let bounds = match end {
+        Some(end) => Some((start, u16::try_from(parse_address(&end)?)?)),
+        None => None,
     };
+    let listing = io::follow_code(start, max_instructions, bounds, &mut |address, length| {
+        comm.read_memory(address as u32, length)
+    })?;
+    let mut expected = None;
+    for (address, bytes) in listing {
+        if matches!(expected, Some(expected) if expected != address) {
+            println!();
+        }
+        print_disassembly(&bytes, address as u32)?;
+        expected = Some(address.wrapping_add(bytes.len() as u16));
+    }
     Ok(())
 }
 
-pub fn poke<T: Read + Write>(
+pub fn poke(
     file: Option<String>,
     value: Option<u8>,
+    hex_bytes: Option<String>,
     address: String,
-    port: &mut T,
+    confirm_critical: bool,
+    comm: &mut dyn M65Communicator,
 ) -> Result<(), anyhow::Error> {
-    let bytes = match file {
-        Some(f) => matrix65::io::load_bytes(&f)?,
-        None => vec![value.ok_or_else(|| anyhow::Error::msg("VALUE required for poking"))?],
+    let bytes = match (file, hex_bytes) {
+        (Some(f), _) => matrix65::io::load_bytes(&f)?,
+        (None, Some(hex)) => parse_hex_bytes(&hex)?,
+        (None, None) => vec![value
+            .ok_or_else(|| anyhow::Error::msg("VALUE, --bytes, or --file required for poking"))?],
     };
-    let parsed_address = parse::<u16>(&address)?;
-    if parsed_address.checked_add(bytes.len() as u16 - 1).is_none() {
-        // Merely a safety measure. Is this needed?
+    let parsed_address = parse_address(&address)?;
+    if parsed_address as usize + bytes.len() > ADDRESS_SPACE_SIZE {
+        return Err(anyhow::Error::msg("poking beyond the 28-bit address space"));
+    }
+    if confirm_critical {
+        if let Some(region) = matrix65::critical_region(parsed_address, bytes.len()) {
+            eprintln!(
+                "Warning: writing to the {} may crash a running program",
+                region
+            );
+            if !io::confirm("Continue?")? {
+                return Err(anyhow::Error::msg("poke cancelled"));
+            }
+        }
+    }
+    // progress is only worth showing for files
+    let show_progress = bytes.len() > 1 && std::io::stderr().is_tty();
+    comm.write_memory_with_progress(parsed_address, &bytes, &mut |written, total| {
+        if show_progress {
+            eprint!(
+                "\rWriting {} of {} bytes ({}%)",
+                written,
+                total,
+                written * 100 / total
+            );
+        }
+    })?;
+    if show_progress {
+        eprintln!();
+    }
+    Ok(())
+}
+
+/// Size of the MEGA65 address space
+const ADDRESS_SPACE_SIZE: usize = 1 << 28;
+
+/// Print the current video mode
+pub fn video(comm: &mut dyn M65Communicator) -> Result<(), anyhow::Error> {
+    println!("{}", VideoMode::read(comm)?);
+    Ok(())
+}
+
+/// Play SID tune file or url in C64 mode; `song` defaults to the tune's start song
+pub fn sid(
+    comm: &mut dyn M65Communicator,
+    file: &str,
+    song: Option<u16>,
+) -> Result<(), anyhow::Error> {
+    let tune = SidTune::parse(&io::load_bytes(file)?)?;
+    println!("{}", tune);
+    Ok(tune.play(comm, song.unwrap_or(tune.start_song))?)
+}
+
+/// Print header and chips of a CRT cartridge file or url
+pub fn crt_info(file: &str) -> Result<(), anyhow::Error> {
+    println!("{}", Cartridge::parse(&io::load_bytes(file)?)?);
+    Ok(())
+}
+
+/// Copy the ROM of a simple CRT cartridge to RAM in C64 mode
+pub fn crt_write(
+    comm: &mut dyn M65Communicator,
+    file: &str,
+    yes: bool,
+) -> Result<(), anyhow::Error> {
+    let cartridge = Cartridge::parse(&io::load_bytes(file)?)?;
+    println!("{}", cartridge);
+    println!("The ROM is copied to RAM in C64 mode, which is not the same as a cartridge.");
+    if !yes && !io::confirm("Continue?")? {
+        return Ok(());
+    }
+    cartridge.write(comm)?;
+    if let Some(address) = cartridge.cold_start() {
+        println!("Start it with: go 0x{:04x}", address);
+    }
+    Ok(())
+}
+
+/// Print FileHost entries, optionally filtered, sorted, and paginated
+pub fn list(
+    filter: Option<&str>,
+    sort: &str,
+    limit: Option<usize>,
+    offset: usize,
+) -> Result<(), anyhow::Error> {
+    let filter = filter.map(str::to_lowercase);
+    let mut entries: Vec<_> = filehost::get_file_list()?
+        .into_iter()
+        .filter(|item| match &filter {
+            Some(text) => {
+                item.title.to_lowercase().contains(text)
+                    || item.author.to_lowercase().contains(text)
+            }
+            None => true,
+        })
+        .collect();
+    let number = |value: &str| value.parse::<f64>().unwrap_or(0.0);
+    match sort {
+        "title" => entries.sort_by_key(|i| i.title.to_lowercase()),
+        "newest" => entries.sort_by(|a, b| b.sortdate.cmp(&a.sortdate)),
+        "rating" => entries.sort_by(|a, b| number(&b.rating).total_cmp(&number(&a.rating))),
+        "downloads" => {
+            entries.sort_by(|a, b| number(&b.downloads).total_cmp(&number(&a.downloads)))
+        }
+        _ => {
+            return Err(anyhow::anyhow!(
+                "unknown sort key '{}'; use title, newest, rating, or downloads",
+                sort
+            ))
+        }
+    }
+    let total = entries.len();
+    let shown = entries
+        .iter()
+        .skip(offset)
+        .take(limit.unwrap_or(usize::MAX));
+    let mut count = 0;
+    for item in shown {
+        println!(
+            "{:<40} {:<10} {:<20} {}",
+            item.title, item.kind, item.author, item.published
+        );
+        count += 1;
+    }
+    if count < total {
+        eprintln!(
+            "Showing {}-{} of {} entries",
+            offset.min(total) + usize::from(count > 0),
+            offset.min(total) + count,
+            total
+        );
+    }
+    Ok(())
+}
+
+/// Upper bound of keys to dequeue before each test key
+const KEY_QUEUE_LENGTH: usize = 16;
+
+/// Type each printable ASCII character and Return, and compare with the
+/// MEGA65's hardware keyboard queue
+///
+/// Characters without a keyboard matrix mapping are reported but not typed.
+pub fn kbtest(comm: &mut dyn M65Communicator, yes: bool, delay: u64) -> Result<(), anyhow::Error> {
+    println!("This types every key into whatever has focus on the MEGA65.");
+    if !yes && !io::confirm("Continue?")? {
+        return Ok(());
+    }
+    let keys = std::iter::once('\r').chain((0x20u8..0x7f).map(char::from));
+    let (mut unmapped, mut failures) = (Vec::new(), 0);
+    for key in keys {
+        if key_codes(key) == serial::NO_KEY {
+            unmapped.push(key);
+            continue;
+        }
+        // empty the queue so that only this key is seen
+        for _ in 0..KEY_QUEUE_LENGTH {
+            if comm.peek(ASCII_KEY_QUEUE)? == 0 {
+                break;
+            }
+            comm.poke_far(ASCII_KEY_QUEUE, 0)?;
+        }
+        comm.type_text(&key.to_string())?;
+        std::thread::sleep(std::time::Duration::from_millis(delay));
+        let received = comm.peek(ASCII_KEY_QUEUE)?;
+        if received != key as u8 {
+            println!(
+                "{:?}: expected 0x{:02x}, got 0x{:02x}",
+                key, key as u8, received
+            );
+            failures += 1;
+        }
+    }
+    comm.poke_far(ASCII_KEY_QUEUE, 0)?;
+    if !unmapped.is_empty() {
+        println!("No mapping for: {}", unmapped.iter().collect::<String>());
+    }
+    match failures {
+        0 => {
+            println!("All mapped keys registered correctly");
+            Ok(())
+        }
+        n => Err(anyhow::anyhow!("{} key(s) did not register as expected", n)),
+    }
+}
+
+/// Measure write and read speed by copying a test pattern to and from memory
+///
+/// Reads are timed with both page (`M`) and line (`m`) dumps when the
+/// connection supports both. The original contents of the region are
+/// restored afterwards.
+pub fn bench(
+    comm: &mut dyn M65Communicator,
+    address: &str,
+    size: usize,
+) -> Result<(), anyhow::Error> {
+    let address = parse_address(address)?;
+    if size == 0 || address as usize + size > ADDRESS_SPACE_SIZE {
         return Err(anyhow::Error::msg(
-            "poking outside the 16-bit address space is currently unsupported",
+            "benchmark region must be non-empty and within the 28-bit address space",
         ));
     }
-    matrix65::serial::write_memory(port, parsed_address, &bytes)?;
+    // pseudo-random bytes so that dropped or shifted bytes are noticed
+    let mut state: u32 = 0x2545_f491;
+    let pattern: Vec<u8> = (0..size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+    let original = comm.read_memory(address, size)?;
+    let rate = |duration: std::time::Duration| size as f64 / 1024.0 / duration.as_secs_f64();
+
+    let start = std::time::Instant::now();
+    comm.write_memory_far(address, &pattern)?;
+    let write_time = start.elapsed();
+    // page and line dumps side by side, where the connection can choose
+    let mut reads = Vec::new();
+    for (name, page_dump) in [("Read (M pages)", true), ("Read (m lines)", false)] {
+        if comm.set_page_dump(page_dump).is_ok() {
+            let start = std::time::Instant::now();
+            let found = comm.read_memory(address, size)?;
+            reads.push((name, start.elapsed(), found));
+        }
+    }
+    // back to the default of page dumps when available
+    let _ = comm.set_page_dump(true);
+    if reads.is_empty() {
+        let start = std::time::Instant::now();
+        let found = comm.read_memory(address, size)?;
+        reads.push(("Read", start.elapsed(), found));
+    }
+    comm.write_memory_far(address, &original)?;
+
+    println!("Benchmark of {} bytes at 0x{:07x}", size, address);
+    println!("{:<16}{:8.1} KB/s", "Write", rate(write_time));
+    for (name, read_time, _) in &reads {
+        println!("{:<16}{:8.1} KB/s", name, rate(*read_time));
+    }
+    let errors = reads
+        .iter()
+        .map(|(_, _, found)| pattern.iter().zip(found).filter(|(a, b)| a != b).count())
+        .max()
+        .unwrap_or(0);
+    match errors {
+        0 => {
+            println!("All bytes read back correctly");
+            Ok(())
+        }
+        n => Err(anyhow::anyhow!(
+            "{} of {} bytes differ after reading back",
+            n,
+            size
+        )),
+    }
+}
+
+/// Print available serial ports
+pub fn ports(json: bool) -> Result<(), anyhow::Error> {
+    let ports = serial::available_ports()?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&ports)?);
+    } else if ports.is_empty() {
+        println!("No serial ports found");
+    } else {
+        ports.iter().for_each(|port| println!("{}", port));
+        if let Ok(port) = serial::select_mega65_port(&ports) {
+            println!(
+                "\nMEGA65 detected on {} (used when --port is omitted)",
+                port
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Print machine model, core version, and mode
+pub fn info(comm: &mut dyn M65Communicator, json: bool) -> Result<(), anyhow::Error> {
+    let info = comm.model_info()?;
+    match json {
+        true => println!("{}", serde_json::to_string_pretty(&info)?),
+        false => println!("{}", info),
+    }
+    Ok(())
+}
+
+/// Create a CBM disk image and add PRG files, named after the file stems
+pub fn mkdisk(image: &str, files: &[String], force: bool) -> Result<(), anyhow::Error> {
+    io::prepare_disk_write(image, force)?;
+    let name = disk_filename(image);
+    let mut disk = io::cbm_create(image, &name)?;
+    for file in files {
+        let (load_address, bytes) = io::load_with_load_address(file)?;
+        let name = disk_filename(file);
+        io::cbm_write_file(disk.as_mut(), &name, load_address.value(), &bytes)?;
+        println!("Added {}.prg ({} bytes)", name, bytes.len() + 2);
+    }
     Ok(())
 }
 
-pub fn filehost(port: &mut Box<dyn SerialPort>) -> Result<(), anyhow::Error> {
+/// Print BASIC style directory of a CBM disk image or T64 tape archive
+///
+/// Disk images end with the number of free blocks; tape archives have none.
+pub fn dir(image: &str) -> Result<(), anyhow::Error> {
+    let (entries, blocks_free) = match io::DiskFormat::from_filename(image) {
+        Some(_) => {
+            let disk = io::cbm_open(image)?;
+            (
+                io::cbm_disk_directory(disk.as_ref())?,
+                Some(disk.blocks_free()?),
+            )
+        }
+        None => (io::cbm_directory(image)?, None),
+    };
+    for entry in &entries {
+        println!("{}", entry);
+    }
+    if let Some(blocks) = blocks_free {
+        println!("{} BLOCKS FREE.", blocks);
+    }
+    Ok(())
+}
+
+/// Maximum length of CBM disk and file names
+const CBM_NAME_LENGTH: usize = 16;
+
+/// File stem of `path`, shortened to a valid CBM name
+fn disk_filename(path: &str) -> String {
+    let stem = std::path::Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    stem.chars().take(CBM_NAME_LENGTH).collect()
+}
+
+/// Number of palette entries used by text mode
+const TEXT_COLORS: usize = 16;
+
+/// Save the current text screen as a PNG image
+///
+/// The screen, colour RAM, character set, and palette locations are
+/// taken from the VIC-IV registers, so both 40 and 80 column modes work.
+pub fn screenshot(comm: &mut dyn M65Communicator, outfile: &str) -> Result<(), anyhow::Error> {
+    let mode = VideoMode::read(comm)?;
+    if mode.bitmap {
+        return Err(anyhow::Error::msg(
+            "screenshots of bitmap modes are not supported",
+        ));
+    }
+    let (columns, rows) = (mode.columns as usize, mode.rows as usize);
+    if columns == 0 || rows == 0 {
+        return Err(anyhow::anyhow!("invalid text size {}x{}", columns, rows));
+    }
+    let line_step = mode.line_step as usize;
+    let color_ram =
+        comm.read_color_ram(mode.color_offset as usize, (rows - 1) * line_step + columns)?;
+    let mut screen = Vec::with_capacity(columns * rows);
+    let mut colors = Vec::with_capacity(columns * rows);
+    for row in 0..rows {
+        let offset = row * line_step;
+        screen.append(&mut comm.read_memory(mode.screen_address + offset as u32, columns)?);
+        colors.extend_from_slice(&color_ram[offset..offset + columns]);
+    }
+    let charset = comm.read_memory(mode.charset_address, 256 * io::CHAR_SIZE)?;
+    let palette = video::read_palette(comm, TEXT_COLORS)?;
+    let background = comm.peek(video::BACKGROUND_COLOR)?;
+    let pixels = io::text_to_rgba(&screen, &colors, &charset, columns, &palette, background);
+    let (width, height) = (columns * io::CHAR_SIZE, rows * io::CHAR_SIZE);
+    image::RgbaImage::from_raw(width as u32, height as u32, pixels)
+        .ok_or_else(|| anyhow::Error::msg("screen size does not match image"))?
+        .save_with_format(outfile, image::ImageFormat::Png)?;
+    println!("Saved {}x{} screenshot to {}", width, height, outfile);
+    Ok(())
+}
+
+/// Print CPU registers
+pub fn regs(comm: &mut dyn M65Communicator, json: bool) -> Result<(), anyhow::Error> {
+    let registers = comm.read_registers()?;
+    match json {
+        true => println!("{}", serde_json::to_string_pretty(&registers)?),
+        false => println!("{}", registers),
+    }
+    Ok(())
+}
+
+/// Number of keys in the KERNAL keyboard buffer (NDX) in C64 and C65 mode
+const KEY_COUNT_C64: u32 = 0xc6;
+const KEY_COUNT_C65: u32 = 0xd0;
+
+/// Give up waiting for the editor to accept a typed line after this long
+const LINE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Delay between checks of the keyboard buffer
+const LINE_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(20);
+
+/// Type BASIC program from a text file, one line at a time
+///
+/// After each line, this waits for the editor to empty the keyboard
+/// buffer so that long programs do not overrun it, and then pauses
+/// for another `line_delay` milliseconds.
+pub fn basic(
+    comm: &mut dyn M65Communicator,
+    file: &str,
+    auto_number: bool,
+    line_delay: u64,
+) -> Result<(), anyhow::Error> {
+    let source = String::from_utf8(io::load_bytes(file)?)?;
+    let lines = io::basic_lines(&source, auto_number);
+    let key_count = match comm.is_c65_mode()? {
+        true => KEY_COUNT_C65,
+        false => KEY_COUNT_C64,
+    };
+    for line in lines {
+        comm.type_text(&format!("{}\r", line))?;
+        let start = std::time::Instant::now();
+        while comm.peek(key_count)? != 0 {
+            if start.elapsed() > LINE_TIMEOUT {
+                return Err(anyhow::anyhow!("line not accepted by the editor: {}", line));
+            }
+            std::thread::sleep(LINE_POLL_INTERVAL);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(line_delay));
+    }
+    Ok(())
+}
+
+/// Single-step `count` instructions, showing registers and the next instruction
+pub fn step(comm: &mut dyn M65Communicator, count: usize) -> Result<(), anyhow::Error> {
+    for _ in 0..count {
+        let registers = comm.step()?;
+        println!("{}", registers);
+        let mut bytes = comm.read_memory(registers.pc as u32, 3)?;
+        bytes.truncate(io::instruction_length(bytes[0]));
+        print_disassembly(&bytes, registers.pc as u32)?;
+    }
+    Ok(())
+}
+
+/// Delay between register reads while waiting for a breakpoint
+const BREAKPOINT_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// Give up waiting for a breakpoint after this long
+const BREAKPOINT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Resume the CPU and wait until it halts at `breakpoint`, then print registers
+///
+/// Gives up after `BREAKPOINT_TIMEOUT`, leaving the CPU running.
+pub fn resume(comm: &mut dyn M65Communicator, breakpoint: u32) -> Result<(), anyhow::Error> {
+    comm.start_cpu()?;
+    let deadline = std::time::Instant::now() + BREAKPOINT_TIMEOUT;
+    while std::time::Instant::now() < deadline {
+        let registers = comm.read_registers()?;
+        if registers.pc as u32 == breakpoint & 0xffff {
+            println!("{}", registers);
+            return Ok(());
+        }
+        std::thread::sleep(BREAKPOINT_POLL_INTERVAL);
+    }
+    Err(anyhow::anyhow!(
+        "breakpoint at 0x{:04x} not reached within {} seconds; the CPU is still running",
+        breakpoint,
+        BREAKPOINT_TIMEOUT.as_secs()
+    ))
+}
+
+/// A changed file is reloaded once it has been unchanged this long,
+/// so that several quick writes, e.g. by an assembler, reload it once
+const FILE_SETTLE_TIME: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Modification time and size, to tell whether a file has changed
+type FileStamp = Option<(std::time::SystemTime, u64)>;
+
+fn file_stamp(path: &str) -> FileStamp {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Debounces changes to a watched file
+struct FileChanges {
+    /// Stamp of the file as last loaded
+    loaded: FileStamp,
+    /// New stamp and when it was first seen
+    pending: Option<(FileStamp, std::time::Instant)>,
+}
+
+impl FileChanges {
+    fn new(loaded: FileStamp) -> FileChanges {
+        FileChanges {
+            loaded,
+            pending: None,
+        }
+    }
+
+    /// Whether the file should be reloaded, given its current stamp
+    ///
+    /// A missing file is never reloaded, e.g. while being rebuilt.
+    fn check(&mut self, stamp: FileStamp, now: std::time::Instant) -> bool {
+        if stamp == self.loaded || stamp.is_none() {
+            self.pending = None;
+            return false;
+        }
+        match self.pending {
+            Some((pending, since)) if pending == stamp => {
+                if now.duration_since(since) < FILE_SETTLE_TIME {
+                    return false;
+                }
+                self.loaded = stamp;
+                self.pending = None;
+                true
+            }
+            _ => {
+                self.pending = Some((stamp, now));
+                false
+            }
+        }
+    }
+}
+
+/// Call `reload` whenever `file` changes, until interrupted with Ctrl-C
+///
+/// The directory of the file is watched rather than the file itself, as
+/// editors and assemblers often write a new file and rename it over the
+/// old one. Failed reloads are reported and watching continues, so that
+/// a broken build does not end the session.
+pub fn watch_file(
+    file: &str,
+    mut reload: impl FnMut() -> Result<(), anyhow::Error>,
+) -> Result<(), anyhow::Error> {
+    use notify::{RecursiveMode, Watcher};
+    use std::sync::mpsc::{self, RecvTimeoutError};
+    if file.starts_with("http") {
+        return Err(anyhow::Error::msg("only local files can be watched"));
+    }
+    let directory = match std::path::Path::new(file).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => std::path::Path::new("."),
+    };
+    let (sender, events) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    watcher.watch(directory, RecursiveMode::NonRecursive)?;
+    let mut changes = FileChanges::new(file_stamp(file));
+    eprintln!("Watching {} for changes; press Ctrl-C to stop", file);
+    loop {
+        // wait for events, or for a pending change to settle
+        let event = match changes.pending {
+            Some(_) => events.recv_timeout(FILE_SETTLE_TIME),
+            None => events.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match event {
+            Ok(Ok(_)) | Err(RecvTimeoutError::Timeout) => {}
+            Ok(Err(err)) => eprintln!("Warning: {}", err),
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(anyhow::Error::msg("file watcher stopped"))
+            }
+        }
+        if changes.check(file_stamp(file), std::time::Instant::now()) {
+            eprintln!("{} changed; reloading", file);
+            if let Err(err) = reload() {
+                eprintln!("Error: {}", err);
+            }
+        }
+    }
+}
+
+pub fn filehost(comm: &mut dyn M65Communicator) -> Result<(), anyhow::Error> {
     let mut entries: Vec<_> = filehost::get_file_list()?
         .iter()
         .cloned()
         .filter(|item| {
             item.filename.to_lowercase().ends_with(".prg")
                 | item.filename.to_lowercase().ends_with(".d81")
+                | item.filename.to_lowercase().ends_with(".t64")
         })
         .collect();
     entries.sort_by_key(|i| i.title.clone());
-    textui::terminal::start_tui(port, &entries)?;
+    let keys = crate::config::Config::load()?.keys;
+    textui::terminal::start_tui(comm, &entries, &keys)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix65::test_support::MockCommunicator;
+
+    #[test]
+    fn bench_restores_memory() {
+        let mut mock = MockCommunicator::new();
+        mock.load(0x40000, &[1, 2, 3, 4]);
+        bench(&mut mock, "0x40000", 4).unwrap();
+        assert_eq!(mock.writes.len(), 2);
+        assert_eq!(mock.read_memory(0x40000, 4).unwrap(), [1, 2, 3, 4]);
+        assert!(bench(&mut mock, "0x40000", 0).is_err());
+    }
+
+    #[test]
+    fn screenshot_reads_colour_ram_in_c64_mode() {
+        let mut mock = MockCommunicator::new();
+        let mut registers = [0u8; video::VIC_REGISTER_COUNT];
+        registers[0x58] = 40; // bytes per row
+        registers[0x5e] = 40; // columns
+        registers[0x61] = 0x08; // screen at $0800
+        registers[0x69] = 0x10; // charset at $1000
+        registers[0x7b] = 25; // rows
+        mock.load(video::VIC_REGISTERS, &registers);
+        mock.load(0x0800, &[1]);
+        mock.load(0x1008, &[0x80]);
+        mock.load(matrix65::COLOR_RAM, &[2]);
+        mock.load(0xffd3102, &[0x0f]); // red component of colour 2
+        let file = tempfile::Builder::new().suffix(".png").tempfile().unwrap();
+        screenshot(&mut mock, file.path().to_str().unwrap()).unwrap();
+        let image = image::open(file.path()).unwrap().to_rgba8();
+        assert_eq!(image.dimensions(), (320, 200));
+        assert_eq!(image.get_pixel(0, 0).0, [0xf0, 0, 0, 255]);
+        assert_eq!(image.get_pixel(1, 0).0, [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn basic_waits_for_keyboard_buffer() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "PRINT 1\nPRINT 2").unwrap();
+        let path = file.path().to_str().unwrap();
+        let mut mock = MockCommunicator::new();
+        mock.c65_mode = true;
+        basic(&mut mock, path, true, 0).unwrap();
+        assert_eq!(mock.typed, ["10 print 1\r", "20 print 2\r"]);
+        assert!(mock.reads.contains(&(KEY_COUNT_C65, 1)));
+        // keys that are never consumed stop the transfer
+        let mut mock = MockCommunicator::new();
+        mock.load(KEY_COUNT_C64, &[3]);
+        assert!(basic(&mut mock, path, true, 0).is_err());
+        assert_eq!(mock.typed.len(), 1);
+    }
+
+    #[test]
+    fn file_changes_are_debounced() {
+        let start = std::time::Instant::now();
+        let later = |ms| start + std::time::Duration::from_millis(ms);
+        let stamp = |len| Some((std::time::SystemTime::UNIX_EPOCH, len));
+        let mut changes = FileChanges::new(stamp(1));
+        assert!(!changes.check(stamp(1), later(0)));
+        // rapid writes restart the wait
+        assert!(!changes.check(stamp(2), later(0)));
+        assert!(!changes.check(stamp(3), later(300)));
+        assert!(!changes.check(stamp(3), later(600)));
+        assert!(changes.check(stamp(3), later(800)));
+        assert!(!changes.check(stamp(3), later(2000)));
+        // a missing file is not loaded
+        assert!(!changes.check(None, later(3000)));
+        assert!(!changes.check(None, later(4000)));
+    }
+
+    #[test]
+    fn watched_file_reloads_after_atomic_replace() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("prog.prg");
+        std::fs::write(&path, [1]).unwrap();
+        let file = path.to_str().unwrap().to_string();
+        let (sender, reloads) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            watch_file(&file, || Ok(sender.send(())?)).unwrap();
+        });
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        let replacement = directory.path().join("prog.tmp");
+        std::fs::write(&replacement, [1, 2]).unwrap();
+        std::fs::rename(&replacement, &path).unwrap();
+        assert!(reloads
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .is_ok());
+    }
+
+    #[test]
+    fn sid_uploads_tune_and_driver() {
+        let mut bytes = vec![0u8; 0x76];
+        bytes[..4].copy_from_slice(b"PSID");
+        bytes[0x07] = 0x76;
+        bytes[0x08..0x0e].copy_from_slice(&[0x10, 0x00, 0x10, 0x00, 0x10, 0x03]);
+        bytes[0x0f] = 1;
+        bytes.extend_from_slice(&[0x60; 6]);
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), &bytes).unwrap();
+        let path = file.path().to_str().unwrap();
+
+        let mut mock = MockCommunicator::new();
+        mock.c65_mode = true;
+        sid(&mut mock, path, None).unwrap();
+        assert_eq!(mock.typed, ["go64\ry\r"]);
+        assert_eq!(mock.writes[0], (0x1000, vec![0x60; 6]));
+        assert_eq!(mock.jumps, [0x033c]);
+        // the KERNAL vector is set to the interrupt handler at the end of the driver
+        let driver = &mock.writes[1].1;
+        let irq = 0x033c + driver.len() - 11;
+        let vector = driver
+            .windows(3)
+            .position(|w| w[1..] == [0x14, 0x03])
+            .unwrap();
+        assert_eq!(driver[vector - 1], irq as u8);
+        assert_eq!(driver[vector + 4], (irq >> 8) as u8);
+        assert_eq!(
+            driver[irq - 0x033c..irq - 0x033c + 5],
+            [0xa9, 0x01, 0x8d, 0x19, 0xd0]
+        );
+        assert!(sid(&mut mock, path, Some(2)).is_err());
+    }
+
+    #[test]
+    fn crt_write_copies_rom_to_ram() {
+        let mut bytes = b"C64 CARTRIDGE   ".to_vec();
+        bytes.extend_from_slice(&[0, 0, 0, 0x40, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        bytes.extend_from_slice(&[0; 32]);
+        for (address, fill) in [(0x80, 0x09), (0xa0, 0xbb)] {
+            bytes.extend_from_slice(b"CHIP");
+            bytes.extend_from_slice(&[0, 0, 0x20, 0x10, 0, 0, 0, 0, address, 0, 0x20, 0]);
+            bytes.extend_from_slice(&[fill; 0x2000]);
+        }
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), &bytes).unwrap();
+        let path = file.path().to_str().unwrap();
+
+        let mut mock = MockCommunicator::new();
+        mock.c65_mode = true;
+        crt_write(&mut mock, path, true).unwrap();
+        assert_eq!(mock.typed, ["go64\ry\r"]);
+        assert_eq!(mock.read_memory(0x8000, 2).unwrap(), [0x09, 0x09]);
+        assert_eq!(mock.read_memory(0xbfff, 1).unwrap(), [0xbb]);
+
+        // bank switched cartridges are only inspected
+        bytes[0x17] = 32;
+        std::fs::write(file.path(), &bytes).unwrap();
+        assert!(crt_write(&mut mock, path, true).is_err());
+        assert_eq!(mock.writes.len(), 2);
+    }
+
+    #[test]
+    fn go_jumps_to_address() {
+        let mut mock = MockCommunicator::new();
+        mock.cpu_stopped = true;
+        go(&mut mock, "$c000").unwrap();
+        assert_eq!(mock.jumps, [0xc000]);
+        assert!(!mock.cpu_stopped);
+        assert!(go(&mut mock, "nowhere").is_err());
+    }
+
+    #[test]
+    fn poke_writes_value_at_named_address() {
+        let mut mock = MockCommunicator::new();
+        poke(
+            None,
+            Some(0x01),
+            None,
+            "screen".to_string(),
+            false,
+            &mut mock,
+        )
+        .unwrap();
+        assert_eq!(mock.writes, [(0x0800, vec![0x01])]);
+    }
+
+    #[test]
+    fn poke_reaches_28_bit_addresses() {
+        let mut mock = MockCommunicator::new();
+        poke(
+            None,
+            Some(0x01),
+            None,
+            "colour".to_string(),
+            false,
+            &mut mock,
+        )
+        .unwrap();
+        assert_eq!(mock.writes, [(0xff80000, vec![0x01])]);
+        assert!(poke(
+            None,
+            Some(0x01),
+            None,
+            "0x10000000".to_string(),
+            false,
+            &mut mock
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn poke_writes_hex_bytes() {
+        let mut mock = MockCommunicator::new();
+        let hex = Some("a9 00 8d 20 d0".to_string());
+        poke(None, None, hex, "0xc000".to_string(), false, &mut mock).unwrap();
+        assert_eq!(mock.writes, [(0xc000, vec![0xa9, 0x00, 0x8d, 0x20, 0xd0])]);
+    }
+
+    #[test]
+    fn mkdisk_image_can_be_listed() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let prg = tmp_dir.path().join("hello.prg");
+        std::fs::write(&prg, [0x01, 0x08, 0xa9, 0x01, 0x60]).unwrap();
+        let files = [prg.to_str().unwrap().to_string()];
+        for extension in ["d64", "d71", "d81"] {
+            let image = tmp_dir.path().join(format!("games.{}", extension));
+            let image = image.to_str().unwrap();
+            mkdisk(image, &files, false).unwrap();
+            dir(image).unwrap();
+            let entries = io::cbm_directory(image).unwrap();
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].name, "HELLO");
+            assert!(entries[0].is_prg());
+            let disk = io::cbm_open(image).unwrap();
+            let (load_address, bytes) = io::cbm_load_file(disk.as_ref(), 0).unwrap();
+            assert_eq!(load_address, LoadAddress::Commodore64);
+            assert_eq!(bytes, [0xa9, 0x01, 0x60]);
+        }
+    }
+
+    #[test]
+    fn upload_writes_without_running() {
+        let mut file = tempfile::Builder::new().suffix(".prg").tempfile().unwrap();
+        file.write_all(&[0x00, 0xc0, 0xa9, 0x01]).unwrap();
+        let name = file.path().to_str().unwrap();
+        let mut mock = MockCommunicator::new();
+        upload(&mut mock, name, None, false, false).unwrap();
+        upload(&mut mock, name, Some("0x40000"), false, false).unwrap();
+        upload(&mut mock, name, Some("0x50000"), true, false).unwrap();
+        assert_eq!(
+            mock.writes,
+            [
+                (0xc000, vec![0xa9, 0x01]),
+                (0x40000, vec![0xa9, 0x01]),
+                (0x50000, vec![0x00, 0xc0, 0xa9, 0x01])
+            ]
+        );
+        assert!(mock.typed.is_empty());
+        assert!(upload(&mut mock, name, None, true, false).is_err());
+    }
+
+    #[test]
+    fn upload_writes_sys_stub() {
+        let mut file = tempfile::Builder::new().suffix(".prg").tempfile().unwrap();
+        file.write_all(&[0x00, 0xc0, 0xa9, 0x01]).unwrap();
+        let name = file.path().to_str().unwrap();
+        let mut mock = MockCommunicator::new();
+        upload(&mut mock, name, None, false, true).unwrap();
+        assert_eq!(mock.writes[1], (0x0801, io::sys_stub(0x0801, 0xc000)));
+        assert!(upload(&mut mock, name, Some("0x0805"), false, true).is_err());
+        assert!(upload(&mut mock, name, Some("0x40000"), false, true).is_err());
+        assert_eq!(mock.writes.len(), 2);
+    }
+
+    #[test]
+    fn large_prg_loads_into_banked_ram() {
+        let size = 0x12000;
+        let mut prg = vec![0x01, 0x08];
+        prg.resize(size + 2, 0xea);
+        let mut file = tempfile::Builder::new().suffix(".prg").tempfile().unwrap();
+        file.write_all(&prg).unwrap();
+        let name = file.path().to_str().unwrap();
+        let written = |mock: &MockCommunicator| -> usize {
+            mock.writes.iter().map(|(_, bytes)| bytes.len()).sum()
+        };
+
+        let mut mock = MockCommunicator::new();
+        prg_at(&mut mock, name, "0x40000", false, false, false).unwrap();
+        assert_eq!(mock.writes[0].0, 0x40000);
+        assert_eq!(written(&mock), size);
+        assert_eq!(
+            mock.read_memory(0x40000 + size as u32 - 1, 1).unwrap(),
+            [0xea]
+        );
+
+        let mut mock = MockCommunicator::new();
+        upload(&mut mock, name, Some("0x40000"), false, false).unwrap();
+        assert_eq!(written(&mock), size);
+
+        // too large for its own 16-bit load address
+        let mut mock = MockCommunicator::new();
+        assert!(prg_at(&mut mock, name, "0x0801", false, false, false).is_err());
+        assert!(mock.writes.is_empty());
+    }
+
+    #[test]
+    fn peek_saves_requested_bytes() {
+        let mut mock = MockCommunicator::new();
+        mock.load(0xc000, &[1, 2, 3, 4]);
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let name = file.path().to_str().unwrap().to_string();
+        peek(
+            &mut mock,
+            "$c001".to_string(),
+            2,
+            Some(name.clone()),
+            false,
+            false,
+        )
+        .unwrap();
+        assert_eq!(mock.reads, [(0xc001, 2)]);
+        assert_eq!(std::fs::read(name).unwrap(), [2, 3]);
+    }
+}
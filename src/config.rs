@@ -0,0 +1,121 @@
+// copyright 2022 mikael lund aka wombat
+//
+// licensed under the apache license, version 2.0 (the "license");
+// you may not use this file except in compliance with the license.
+// you may obtain a copy of the license at
+//
+//     http://www.apache.org/licenses/license-2.0
+//
+// unless required by applicable law or agreed to in writing, software
+// distributed under the license is distributed on an "as is" basis,
+// without warranties or conditions of any kind, either express or implied.
+// see the license for the specific language governing permissions and
+// limitations under the license.
+
+//! Configuration file with defaults and named connection profiles, e.g.
+//!
+//! ~~~ toml
+//! port = "/dev/cu.usbserial-AQ027F6E"
+//! keydelay = 30
+//! history = "~/matrix65-history.txt"
+//!
+//! [profiles.mymega]
+//! port = "/dev/cu.usbserial-AQ027F6E"
+//! baud = 2000000
+//! flow_control = "software"
+//! ~~~
+
+use anyhow::Result;
+use directories::ProjectDirs;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Connection settings; unset values fall back to the command line defaults
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub port: Option<String>,
+    pub baud: Option<u32>,
+    pub flow_control: Option<String>,
+    pub monitor: Option<String>,
+    pub no_halt: Option<bool>,
+    pub keydelay: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Default serial device
+    pub port: Option<String>,
+    /// Default serial speed in bits/s
+    pub baud: Option<u32>,
+    /// Default delay between typed keys in milliseconds
+    pub keydelay: Option<u64>,
+    /// Named connection presets
+    pub profiles: BTreeMap<String, Profile>,
+    /// FileHost browser key bindings, e.g. `quit = "x"`
+    pub keys: BTreeMap<String, String>,
+    /// REPL history file, or `false` to not keep history
+    pub history: Option<History>,
+}
+
+/// REPL history setting: a file path, or `true`/`false` for the default file or none
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum History {
+    Enabled(bool),
+    File(PathBuf),
+}
+
+impl Config {
+    /// Location of the config file, e.g. `~/.config/matrix65/config.toml` on Linux
+    pub fn path() -> Option<PathBuf> {
+        ProjectDirs::from("", "", "matrix65").map(|dirs| dirs.config_dir().join("config.toml"))
+    }
+
+    /// Load config file; an absent file gives an empty configuration
+    pub fn load() -> Result<Config> {
+        match Config::path() {
+            Some(path) if path.exists() => {
+                let text = std::fs::read_to_string(&path)?;
+                toml::from_str(&text).map_err(|err| {
+                    anyhow::anyhow!("invalid config file {}: {}", path.display(), err)
+                })
+            }
+            _ => Ok(Config::default()),
+        }
+    }
+
+    /// REPL history file, e.g. `~/.local/share/matrix65/history.txt` on Linux
+    ///
+    /// `None` if history is disabled. A leading `~` is expanded to the home directory.
+    pub fn history_path(&self) -> Option<PathBuf> {
+        match &self.history {
+            Some(History::Enabled(false)) => None,
+            Some(History::File(path)) => match path.strip_prefix("~") {
+                Ok(relative) => {
+                    directories::BaseDirs::new().map(|dirs| dirs.home_dir().join(relative))
+                }
+                Err(_) => Some(path.clone()),
+            },
+            _ => ProjectDirs::from("", "", "matrix65")
+                .map(|dirs| dirs.data_dir().join("history.txt")),
+        }
+    }
+
+    /// Find profile by name
+    pub fn profile(&self, name: &str) -> Result<&Profile> {
+        self.profiles.get(name).ok_or_else(|| {
+            let names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+            anyhow::anyhow!(
+                "unknown profile '{}'; available profiles: {}",
+                name,
+                match names.is_empty() {
+                    true => "none".to_string(),
+                    false => names.join(", "),
+                }
+            )
+        })
+    }
+}
@@ -0,0 +1,135 @@
+// copyright 2022 mikael lund aka wombat
+//
+// licensed under the apache license, version 2.0 (the "license");
+// you may not use this file except in compliance with the license.
+// you may obtain a copy of the license at
+//
+//     http://www.apache.org/licenses/license-2.0
+//
+// unless required by applicable law or agreed to in writing, software
+// distributed under the license is distributed on an "as is" basis,
+// without warranties or conditions of any kind, either express or implied.
+// see the license for the specific language governing permissions and
+// limitations under the license.
+
+//! End to end diagnosis of the connection to the MEGA65
+
+use anyhow::Result;
+use matrix65::serial::{self, DEFAULT_BAUD_RATE};
+use matrix65::{M65Communicator, Matrix65Error};
+
+/// Baud rates tried if the configured one does not work
+const COMMON_BAUD_RATES: [u32; 3] = [DEFAULT_BAUD_RATE, 4000000, 115200];
+
+/// Scratch memory for the round-trip test (C64 cassette buffer); restored afterwards
+const SCRATCH_ADDRESS: u16 = 0x0340;
+
+/// Bit patterns written during the round-trip test
+const SCRATCH_PATTERN: [u8; 4] = [0x55, 0xaa, 0x00, 0xff];
+
+/// Opens a connection to `port` at a baud rate
+pub type Connect<'a> = dyn FnMut(&str, u32) -> Result<Box<dyn M65Communicator>> + 'a;
+
+/// Print outcome of a step, with a suggested fix if it failed
+fn report<T, E: std::fmt::Display>(step: &str, result: &Result<T, E>, fix: &str) {
+    match result {
+        Ok(_) => println!("[ok] {}", step),
+        Err(err) => {
+            println!("[failed] {}: {}", step, err);
+            println!("         fix: {}", fix);
+        }
+    }
+}
+
+/// Write a test pattern to scratch memory, read it back, and restore the original bytes
+fn round_trip(comm: &mut dyn M65Communicator) -> Result<()> {
+    let length = SCRATCH_PATTERN.len();
+    let original = comm.read_memory(SCRATCH_ADDRESS as u32, length)?;
+    comm.write_memory(SCRATCH_ADDRESS, &SCRATCH_PATTERN)?;
+    let readback = comm.read_memory(SCRATCH_ADDRESS as u32, length)?;
+    comm.write_memory(SCRATCH_ADDRESS, &original)?;
+    match readback == SCRATCH_PATTERN {
+        true => Ok(()),
+        false => Err(anyhow::anyhow!(
+            "wrote {:02x?}, read {:02x?}",
+            SCRATCH_PATTERN,
+            readback
+        )),
+    }
+}
+
+/// Check each step from finding the serial port to writing memory
+///
+/// Stops at the first step that later steps depend on.
+pub fn doctor(port: Option<&str>, baud: u32, connect: &mut Connect) -> Result<()> {
+    let ports = serial::available_ports().and_then(|ports| match ports.is_empty() {
+        true => Err(Matrix65Error::Serial("no serial ports found".to_string())),
+        false => Ok(ports),
+    });
+    report(
+        "List serial ports",
+        &ports,
+        "connect the MEGA65 by USB, switch it on, and install the FTDI driver if needed",
+    );
+    if let Ok(ports) = &ports {
+        ports.iter().for_each(|port| println!("         {}", port));
+    }
+
+    let port = match port {
+        Some(port) => Ok(port.to_string()),
+        None => serial::detect_port(),
+    };
+    report(
+        "Select port",
+        &port,
+        "pass one of the ports above with --port, or use --profile",
+    );
+    let port = port?;
+    let port = port.as_str();
+
+    let xemu = port.starts_with("xemu:");
+    let opened = connect(port, baud);
+    report(
+        &format!("Open {}", port),
+        &opened,
+        match xemu {
+            true => "start Xemu with e.g. -uartmon :4510",
+            false => "check permissions and that no other program uses the port",
+        },
+    );
+    let mut comm = opened?;
+
+    // Serial ports open at any speed, so check that the monitor answers
+    let mut info = comm.model_info();
+    report(
+        &format!("Probe monitor at {} baud", baud),
+        &info,
+        "check the USB cable and that the MEGA65 is switched on",
+    );
+    if info.is_err() && !xemu {
+        for candidate in COMMON_BAUD_RATES.iter().filter(|rate| **rate != baud) {
+            drop(comm);
+            comm = connect(port, *candidate)?;
+            info = comm.model_info();
+            if info.is_ok() {
+                println!(
+                    "         fix: the monitor replies at {0} baud; use --baud {0}",
+                    candidate
+                );
+                break;
+            }
+        }
+    }
+    let info = info?;
+    println!("         {}", info.to_string().replace('\n', "\n         "));
+
+    let written = round_trip(comm.as_mut());
+    report(
+        &format!("Write and read back memory at 0x{:04x}", SCRATCH_ADDRESS),
+        &written,
+        "try a lower baud rate or --flow-control software",
+    );
+    written?;
+    println!("\nAll checks passed");
+    Ok(())
+}
@@ -12,8 +12,16 @@
 // see the license for the specific language governing permissions and
 // limitations under the license.
 
+use crate::config::{Config, Profile};
+use anyhow::Result;
 use clap::{Parser, Subcommand};
-use matrix65::serial::DEFAULT_BAUD_RATE;
+use crossterm::tty::IsTty;
+use matrix65::serial::{DEFAULT_BAUD_RATE, DEFAULT_READ_RETRIES, DEFAULT_READ_TIMEOUT};
+
+/// Environment variables with defaults for `--port`, `--baud`, and `--keydelay`
+const ENV_PORT: &str = "MATRIX65_PORT";
+const ENV_BAUD: &str = "MATRIX65_BAUD";
+const ENV_KEYDELAY: &str = "MATRIX65_KEYDELAY";
 
 /// Matrix Mode Serial Communicator for MEGA65
 #[derive(Debug, Subcommand)]
@@ -21,15 +29,58 @@ pub enum Commands {
     /// Transfer and run PRG from file or archive
     #[clap(arg_required_else_help = true)]
     Prg {
-        /// File/URL to load or scan (.prg|.d64|.d81)
-        #[clap(value_parser)]
-        file: String,
+        /// File/URL to load or scan (.prg|.d64|.d71|.d81)
+        #[clap(value_parser, required_unless_present = "last")]
+        file: Option<String>,
+        /// Load the file or URL of the previous `prg` command again
+        #[clap(long, action, conflicts_with = "file")]
+        last: bool,
         /// Reset before loading
         #[clap(long, action)]
         reset: bool,
-        /// Run after loading
+        /// Run after loading; uses RUN for BASIC and SYS for machine code
         #[clap(long, short = 'r', action)]
         run: bool,
+        /// Start with SYS at this address after loading, e.g. $c000
+        #[clap(long, conflicts_with = "run")]
+        sys: Option<String>,
+        /// Read back and compare memory after loading; slow for large files
+        #[clap(long, action)]
+        verify: bool,
+        /// Load here instead of the load address, e.g. 0x40000 for banked RAM above 64 KB
+        #[clap(long, short = '@')]
+        address: Option<String>,
+        /// Keep running and reset and run again whenever the file changes
+        #[clap(long, short = 'w', action)]
+        watch: bool,
+    },
+
+    /// Transfer PRG to memory without running it
+    #[clap(arg_required_else_help = true)]
+    Upload {
+        /// File/URL to load or scan (.prg|.t64|.d64|.d71|.d81)
+        #[clap(value_parser)]
+        file: String,
+        /// Write here instead of the load address, e.g. $c000 or 0x40000
+        #[clap(long, short = '@', required_if_eq("raw", "true"))]
+        address: Option<String>,
+        /// File has no load address header, e.g. a sprite dump; write all bytes to --address
+        #[clap(long, action)]
+        raw: bool,
+        /// Also write `10 SYS <address>` to $0801 so that RUN starts the code in C64 mode
+        #[clap(long, action)]
+        stub: bool,
+    },
+
+    /// Jump to machine code at an address and run it
+    ///
+    /// Sets the program counter with the monitor. This is for machine
+    /// code, e.g. placed with `upload`; start BASIC programs with `prg --run`.
+    #[clap(arg_required_else_help = true)]
+    Go {
+        /// Start address, e.g. $c000 or 0x40000
+        #[clap(long, short = '@')]
+        address: String,
     },
 
     /// Send key presses
@@ -38,6 +89,23 @@ pub enum Commands {
         /// Text to type - use \r for return
         #[clap(value_parser)]
         text: String,
+        /// Delay between key presses in milliseconds
+        #[clap(long, value_name = "MS")]
+        keydelay: Option<u64>,
+    },
+
+    /// Type a BASIC program from a text file, line by line
+    #[clap(arg_required_else_help = true)]
+    Basic {
+        /// Text file with BASIC lines
+        #[clap(value_parser)]
+        file: String,
+        /// Number lines that have no line number
+        #[clap(long, action)]
+        auto_number: bool,
+        /// Extra pause in milliseconds after each line has been accepted
+        #[clap(long, value_name = "MS", default_value_t = 100)]
+        line_delay: u64,
     },
 
     /// Reset MEGA65
@@ -45,46 +113,202 @@ pub enum Commands {
         /// Reset into C64 mode
         #[clap(long, action)]
         c64: bool,
+        /// Warm reset with RUN/STOP + RESTORE; keeps memory and the current mode
+        #[clap(long, action, conflicts_with = "c64")]
+        warm: bool,
     },
 
     /// Peek into memory
     #[clap(arg_required_else_help = true)]
     Peek {
-        /// Address to peek into, e.g. 4096 (dec) or 0x1000 (hex)
+        /// Address to peek into, e.g. 4096 (dec), 0x1000 or $1000 (hex), or a region like "screen"
         #[clap(long, short = '@')]
         address: String,
         /// Number of bytes to retrieve
         #[clap(long = "num", short = 'n', default_value_t = 1)]
         length: usize,
-        /// Output to binary file instead of hexdump
+        /// Output to binary file instead of hexdump, or to text file with --dasm
         #[clap(long, short = 'o')]
         outfile: Option<String>,
         /// Disassemble instead of hexdump (currently only 6502)
-        #[clap(long = "dasm", short = 'd', action, conflicts_with = "outfile")]
+        #[clap(long = "dasm", short = 'd', action)]
         disassemble: bool,
+        /// Last address to read; overrides the number of bytes
+        #[clap(long)]
+        end: Option<String>,
+        /// Disassemble following jumps, subroutines, and branches from the address
+        #[clap(long, action, conflicts_with = "outfile")]
+        follow: bool,
+        /// Maximum number of instructions when following code
+        #[clap(long, default_value_t = 256, requires = "follow")]
+        max_instructions: usize,
+        /// Read and show the memory repeatedly until q or Ctrl-C is pressed
+        #[clap(long, short = 'w', action, conflicts_with_all = ["outfile", "follow"])]
+        watch: bool,
+        /// Time between reads when watching, in milliseconds
+        #[clap(long, value_name = "MS", default_value_t = 100, requires = "watch")]
+        interval: u64,
     },
 
     /// Poke into memory with value or file
     #[clap(arg_required_else_help = true)]
     Poke {
-        /// Destination address, e.g. 4096 (dec) or 0x1000 (hex)
+        /// Destination address, e.g. 4096 (dec), 0x1000 or $1000 (hex), or a region like "screen"
         #[clap(long, short = '@')]
         address: String,
-        /// Write bytes from file
+        /// Write all bytes from file; a PRG load address header is not stripped
         #[clap(long, short = 'f')]
         file: Option<String>,
         /// Byte value to place into memory
         #[clap(value_parser, conflicts_with = "file")]
         value: Option<u8>,
+        /// Hex bytes to write, separated by spaces or commas, e.g. "a9 00 8d 20 d0"
+        #[clap(long, conflicts_with_all = ["file", "value"])]
+        bytes: Option<String>,
+        /// Ask before writing to zero page, the stack, or the I/O area
+        #[clap(long, action)]
+        warn_critical: bool,
+        /// Write to critical regions without asking
+        #[clap(long, action, requires = "warn_critical")]
+        force: bool,
     },
 
+    /// Type every mapped key and check that the MEGA65 received it
+    ///
+    /// This types into whatever has focus on the MEGA65, e.g. the BASIC
+    /// editor, so only run it when that is harmless.
+    Kbtest {
+        /// Do not ask for confirmation before typing
+        #[clap(long, short = 'y', action)]
+        yes: bool,
+        /// Milliseconds to wait for each key to register
+        #[clap(long, default_value_t = 100)]
+        delay: u64,
+    },
+
+    /// Measure transfer speed by writing and reading back a test pattern
+    ///
+    /// Reading is timed with both page and line dumps if the connection
+    /// supports both. The memory region is restored afterwards.
+    Bench {
+        /// Number of bytes to transfer
+        #[clap(long, default_value_t = 16384)]
+        size: usize,
+        /// Scratch memory region to use
+        #[clap(long, short = '@', default_value = "0x40000")]
+        address: String,
+    },
+
+    /// List serial ports with USB details
+    #[clap()]
+    Ports {},
+
+    /// Diagnose the connection step by step and suggest fixes
+    #[clap()]
+    Doctor {},
+
+    /// Show machine model, core version, and current mode
+    #[clap()]
+    Info {},
+
+    /// Show CPU registers
+    #[clap()]
+    Regs {},
+
+    /// Save the text screen as a PNG image
+    #[clap(arg_required_else_help = true)]
+    Screenshot {
+        /// Output PNG file
+        #[clap(long, short = 'o')]
+        outfile: String,
+    },
+
+    /// Play SID tune (.sid file or url) in C64 mode
+    #[clap(arg_required_else_help = true)]
+    Sid {
+        /// PSID or RSID file or url
+        file: String,
+        /// Song number, starting from 1; defaults to the tune's start song
+        #[clap(long, short = 's')]
+        song: Option<u16>,
+    },
+
+    /// Show the header and memory chips of a C64 cartridge image (.crt)
+    ///
+    /// With --write, the ROM of a normal 8K or 16K cartridge is copied
+    /// to RAM at $8000 and $A000 in C64 mode. This is not a cartridge
+    /// port, so only cartridges that do not rely on banking can work.
+    #[clap(arg_required_else_help = true)]
+    CrtInfo {
+        /// CRT file or url
+        file: String,
+        /// Copy the cartridge ROM to RAM on the MEGA65
+        #[clap(long, short = 'w', action)]
+        write: bool,
+        /// Do not ask for confirmation before writing
+        #[clap(long, short = 'y', action, requires = "write")]
+        yes: bool,
+    },
+
+    /// Show the current VIC-IV video mode
+    #[clap()]
+    Video {},
+
     /// FileHost browser
     #[clap()]
     Filehost {},
 
+    /// List FileHost entries
+    #[clap()]
+    List {
+        /// Only show entries with this text in the title or author (case insensitive)
+        #[clap(long, short = 'f')]
+        filter: Option<String>,
+        /// Sort by title, newest, rating, or downloads
+        #[clap(long, short = 's', default_value = "title")]
+        sort: String,
+        /// Show at most this many entries
+        #[clap(long, short = 'n')]
+        limit: Option<usize>,
+        /// Skip this many entries before listing
+        #[clap(long, default_value_t = 0)]
+        offset: usize,
+    },
+
+    /// Create a CBM disk image with PRG files
+    #[clap(arg_required_else_help = true)]
+    Mkdisk {
+        /// Disk image to create (.d64, .d71, or .d81)
+        #[clap(long, short = 'i')]
+        image: String,
+        /// PRG file to add; repeat for more files
+        #[clap(long = "add", short = 'a', required = true)]
+        files: Vec<String>,
+        /// Overwrite an existing image without asking or making a backup
+        #[clap(long, action)]
+        force: bool,
+    },
+
+    /// Show the directory of a CBM disk image or T64 tape archive
+    #[clap(arg_required_else_help = true)]
+    Dir {
+        /// Disk image or tape archive (.d64, .d71, .d81, .t64), file or url
+        image: String,
+    },
+
     /// Interactive shell environment
     #[clap()]
-    Cmd {},
+    Cmd {
+        /// Run commands separated by ';' and exit, e.g. "reset; go64"
+        #[clap(long, short = 'e', conflicts_with = "file")]
+        exec: Option<String>,
+        /// Run commands from script file, one per line, and exit
+        #[clap(long, short = 'f')]
+        file: Option<String>,
+        /// Continue with remaining commands if one fails
+        #[clap(long, action)]
+        keep_going: bool,
+    },
 }
 
 #[derive(Parser)]
@@ -93,15 +317,115 @@ pub struct Args {
     #[clap(subcommand)]
     pub command: Commands,
 
-    /// Serial device name, e.g. /dev/cu.usbserial-AQ027F6E
+    /// Serial device name, e.g. /dev/cu.usbserial-AQ027F6E, or xemu:localhost:4510 for Xemu
     #[clap(short = 'p', long)]
-    pub port: String,
+    pub port: Option<String>,
+
+    /// Serial communication speed in bits/s [default: 2000000]
+    #[clap(short = 'b', long)]
+    pub baud: Option<u32>,
+
+    /// Delay between typed keys in milliseconds [default: 20]
+    #[clap(long, value_name = "MS")]
+    pub keydelay: Option<u64>,
+
+    /// Flow control when writing to the MEGA65: none, software, or hardware [default: none]
+    #[clap(long)]
+    pub flow_control: Option<String>,
+
+    /// Leave the CPU running when reading memory, if supported by the monitor
+    #[clap(long, action)]
+    pub no_halt: bool,
+
+    /// Re-read corrupted memory dumps this many times before failing
+    #[clap(long, default_value_t = DEFAULT_READ_RETRIES)]
+    pub read_retries: usize,
+
+    /// Serial read timeout in milliseconds; increase if replies are cut short
+    #[clap(long, value_name = "MS", default_value_t = DEFAULT_READ_TIMEOUT.as_millis() as u64)]
+    pub read_timeout: u64,
+
+    /// Monitor memory dump header: 'mainline', 'generic', or a pattern like ':{addr}:' [default: mainline]
+    #[clap(long)]
+    pub monitor: Option<String>,
+
+    /// Connection preset from the config file; command line options and environment take precedence
+    #[clap(long)]
+    pub profile: Option<String>,
 
-    /// Serial communication speed in bits/s
-    #[clap(short = 'b', long, default_value_t = DEFAULT_BAUD_RATE)]
-    pub baud: u32,
+    /// Print results as JSON, where supported (info, ports)
+    #[clap(long, action)]
+    pub json: bool,
+
+    /// Always download files instead of using the download cache
+    #[clap(long, action)]
+    pub no_cache: bool,
+
+    /// Disable coloured output; also off when not a terminal or NO_COLOR is set
+    #[clap(long, action)]
+    pub no_color: bool,
 
     /// Verbose output. See more with e.g. RUST_LOG=Trace
     #[clap(long, short = 'v', action)]
     pub verbose: bool,
+
+    /// Print time spent in each serial protocol step when done
+    #[clap(long, action)]
+    pub trace_timing: bool,
+
+    /// Print serial monitor commands to stderr instead of sending them; replies time out
+    #[clap(long, action)]
+    pub dry_run: bool,
+}
+
+impl Args {
+    /// Fill in settings not given on the command line from environment variables
+    pub fn apply_env(&mut self) -> Result<()> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let number = |name: &str| -> Result<Option<u64>> {
+            var(name)
+                .map(|value| {
+                    value
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("invalid {}: '{}'", name, value))
+                })
+                .transpose()
+        };
+        self.port = self.port.take().or_else(|| var(ENV_PORT));
+        if self.baud.is_none() {
+            self.baud = number(ENV_BAUD)?.map(u32::try_from).transpose()?;
+        }
+        self.keydelay = self.keydelay.or(number(ENV_KEYDELAY)?);
+        Ok(())
+    }
+
+    /// Fill in settings not given on the command line from a profile
+    pub fn apply_profile(&mut self, profile: &Profile) {
+        self.port = self.port.take().or_else(|| profile.port.clone());
+        self.baud = self.baud.or(profile.baud);
+        self.keydelay = self.keydelay.or(profile.keydelay);
+        self.flow_control = self
+            .flow_control
+            .take()
+            .or_else(|| profile.flow_control.clone());
+        self.monitor = self.monitor.take().or_else(|| profile.monitor.clone());
+        self.no_halt |= profile.no_halt.unwrap_or(false);
+    }
+
+    /// Fill in settings still unset from the top level of the config file
+    pub fn apply_config(&mut self, config: &Config) {
+        self.port = self.port.take().or_else(|| config.port.clone());
+        self.baud = self.baud.or(config.baud);
+        self.keydelay = self.keydelay.or(config.keydelay);
+    }
+
+    /// Whether to colour output written to stdout
+    pub fn color(&self) -> bool {
+        !self.no_color && std::env::var_os("NO_COLOR").is_none() && std::io::stdout().is_tty()
+    }
+
+    /// Serial communication speed, falling back to the default
+    pub fn baud(&self) -> u32 {
+        self.baud.unwrap_or(DEFAULT_BAUD_RATE)
+    }
 }
@@ -0,0 +1,150 @@
+// copyright 2022 mikael lund aka wombat
+//
+// licensed under the apache license, version 2.0 (the "license");
+// you may not use this file except in compliance with the license.
+// you may obtain a copy of the license at
+//
+//     http://www.apache.org/licenses/license-2.0
+//
+// unless required by applicable law or agreed to in writing, software
+// distributed under the license is distributed on an "as is" basis,
+// without warranties or conditions of any kind, either express or implied.
+// see the license for the specific language governing permissions and
+// limitations under the license.
+
+//! On-disk cache of downloaded files, e.g. from the FileHost
+//!
+//! Files are stored under the OS cache directory, keyed by URL, and
+//! revalidated with the server using the ETag and Last-Modified headers.
+
+use crate::{Matrix65Error, Result};
+use directories::ProjectDirs;
+use log::{debug, warn};
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether downloads go through the cache
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Enable or disable the cache, e.g. with `--no-cache`
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether downloads go through the cache
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Server validators stored next to each cached file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// Cache directory, e.g. `~/.cache/matrix65/downloads` on Linux
+pub fn cache_dir() -> Option<PathBuf> {
+    ProjectDirs::from("", "", "matrix65").map(|dirs| dirs.cache_dir().join("downloads"))
+}
+
+/// File name used for a URL in the cache
+///
+/// All characters but letters, digits, `.`, and `-` are replaced by `_`.
+///
+/// Example:
+/// ~~~
+/// use matrix65::cache::cache_key;
+/// assert_eq!(
+///     cache_key("https://files.mega65.org/files/game.d81"),
+///     "https___files.mega65.org_files_game.d81"
+/// );
+/// ~~~
+pub fn cache_key(url: &str) -> String {
+    url.chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect()
+}
+
+/// Path to an up-to-date copy of `url` in the cache
+///
+/// A cached file is revalidated with the server and only downloaded
+/// again if it has changed. If the server cannot be reached, a
+/// previously cached copy is used.
+pub fn fetch(url: &str) -> Result<PathBuf> {
+    let dir =
+        cache_dir().ok_or_else(|| Matrix65Error::Invalid("no cache directory".to_string()))?;
+    std::fs::create_dir_all(&dir)?;
+    let key = cache_key(url);
+    let path = dir.join(&key);
+    let validators_path = dir.join(format!("{}.validators.json", key));
+    let cached = path.exists();
+    let validators: Validators = match cached {
+        true => std::fs::read_to_string(&validators_path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default(),
+        false => Validators::default(),
+    };
+
+    let mut request = reqwest::blocking::Client::new().get(url);
+    if cached {
+        if let Some(etag) = &validators.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    let response = match request.send() {
+        Ok(response) => response,
+        Err(err) if cached => {
+            warn!(
+                "Using cached {} as the server cannot be reached: {}",
+                url, err
+            );
+            return Ok(path);
+        }
+        Err(err) => return Err(crate::io::http_error(url, err)),
+    };
+    if cached && response.status() == StatusCode::NOT_MODIFIED {
+        debug!("Using cached {}", url);
+        return Ok(path);
+    }
+    let response = crate::io::check_response(url, response)?;
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let validators = Validators {
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+    };
+    let bytes = response
+        .bytes()
+        .map_err(|err| crate::io::http_error(url, err))?;
+    debug!("Caching {} bytes from {}", bytes.len(), url);
+    write_atomic(&path, &bytes)?;
+    std::fs::write(&validators_path, serde_json::to_string(&validators)?)?;
+    Ok(path)
+}
+
+/// Write via a temporary file so that an interrupted download leaves no partial file
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".part");
+    std::fs::write(&partial, bytes)?;
+    std::fs::rename(&partial, path)?;
+    Ok(())
+}
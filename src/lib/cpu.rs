@@ -0,0 +1,129 @@
+// copyright 2022 mikael lund aka wombat
+//
+// licensed under the apache license, version 2.0 (the "license");
+// you may not use this file except in compliance with the license.
+// you may obtain a copy of the license at
+//
+//     http://www.apache.org/licenses/license-2.0
+//
+// unless required by applicable law or agreed to in writing, software
+// distributed under the license is distributed on an "as is" basis,
+// without warranties or conditions of any kind, either express or implied.
+// see the license for the specific language governing permissions and
+// limitations under the license.
+
+//! CPU state as reported by the monitor
+
+use crate::{Matrix65Error, Result};
+use serde::Serialize;
+use std::fmt;
+
+/// Names of the status flags from bit 7 to bit 0
+const FLAG_NAMES: &str = "NVEBDIZC";
+
+/// 45GS02 registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Registers {
+    /// Program counter
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub z: u8,
+    /// Base page
+    pub b: u8,
+    /// Stack pointer
+    pub sp: u16,
+    /// Status flags, if reported
+    pub flags: Option<u8>,
+}
+
+impl Registers {
+    /// Parse the reply to the monitor's `r` command
+    ///
+    /// The reply has a header line starting with `PC`, followed by a line
+    /// with the values. The status flags are read from the `P` column.
+    ///
+    /// Example:
+    /// ~~~
+    /// use matrix65::cpu::Registers;
+    /// let reply = "r\r\n\
+    ///     PC   A  X  Y  Z  B  SP   MAPH MAPL LAST-OP     P  P-FLAGS\r\n\
+    ///     ,0799 01 02 03 04 00 01F6 0000 0000 A5 00 00    23 ..E...ZC\r\n.";
+    /// let registers = Registers::parse(reply).unwrap();
+    /// assert_eq!(registers.pc, 0x0799);
+    /// assert_eq!((registers.a, registers.x, registers.y, registers.z), (1, 2, 3, 4));
+    /// assert_eq!(registers.sp, 0x01f6);
+    /// assert_eq!(registers.flags, Some(0x23));
+    /// ~~~
+    pub fn parse(reply: &str) -> Result<Registers> {
+        let mut lines = reply
+            .lines()
+            .skip_while(|line| !line.trim().starts_with("PC"));
+        let (header, values) = match (lines.next(), lines.next()) {
+            (Some(header), Some(values)) => (header, values.trim_start_matches(',')),
+            _ => {
+                return Err(Matrix65Error::Parse(
+                    "no registers in monitor reply".to_string(),
+                ))
+            }
+        };
+        let tokens: Vec<&str> = values.split_whitespace().collect();
+        if tokens.len() < 7 {
+            return Err(Matrix65Error::Parse(format!(
+                "too few register values in '{}'",
+                values
+            )));
+        }
+        let byte = |i: usize| u8::from_str_radix(tokens[i], 16);
+        let word = |i: usize| u16::from_str_radix(tokens[i], 16);
+        let invalid = |_| Matrix65Error::Parse(format!("invalid register values in '{}'", values));
+        Ok(Registers {
+            pc: word(0).map_err(invalid)?,
+            a: byte(1).map_err(invalid)?,
+            x: byte(2).map_err(invalid)?,
+            y: byte(3).map_err(invalid)?,
+            z: byte(4).map_err(invalid)?,
+            b: byte(5).map_err(invalid)?,
+            sp: word(6).map_err(invalid)?,
+            flags: Self::column(header, values, "P").and_then(|p| u8::from_str_radix(p, 16).ok()),
+        })
+    }
+
+    /// Value in the line below the header `name`, aligned by column
+    fn column<'a>(header: &str, values: &'a str, name: &str) -> Option<&'a str> {
+        let header = header.trim_start_matches(',');
+        let start = header.match_indices(name).map(|(i, _)| i).find(|i| {
+            let before = header[..*i].chars().last();
+            let after = header[i + name.len()..].chars().next();
+            !matches!(before, Some(c) if !c.is_whitespace())
+                && !matches!(after, Some(c) if !c.is_whitespace())
+        })?;
+        let token_start = values[..start.min(values.len())]
+            .rfind(char::is_whitespace)
+            .map_or(0, |i| i + 1);
+        values[token_start..].split_whitespace().next()
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "PC={:04x} A={:02x} X={:02x} Y={:02x} Z={:02x} B={:02x} SP={:04x}",
+            self.pc, self.a, self.x, self.y, self.z, self.b, self.sp
+        )?;
+        if let Some(flags) = self.flags {
+            let names: String = FLAG_NAMES
+                .chars()
+                .enumerate()
+                .map(|(bit, name)| match flags & (0x80 >> bit) {
+                    0 => '.',
+                    _ => name,
+                })
+                .collect();
+            write!(f, " P={:02x} {}", flags, names)?;
+        }
+        Ok(())
+    }
+}
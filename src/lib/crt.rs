@@ -0,0 +1,263 @@
+// copyright 2022 mikael lund aka wombat
+//
+// licensed under the apache license, version 2.0 (the "license");
+// you may not use this file except in compliance with the license.
+// you may obtain a copy of the license at
+//
+//     http://www.apache.org/licenses/license-2.0
+//
+// unless required by applicable law or agreed to in writing, software
+// distributed under the license is distributed on an "as is" basis,
+// without warranties or conditions of any kind, either express or implied.
+// see the license for the specific language governing permissions and
+// limitations under the license.
+
+//! Inspect C64 cartridge images (.crt)
+//!
+//! Cartridges cannot be attached over serial, but the ROM of simple
+//! 8K and 16K cartridges can be copied to the same addresses in RAM.
+
+use crate::{M65Communicator, Matrix65Error, Result};
+use std::fmt;
+
+/// Signature at the start of every CRT file
+const SIGNATURE: &[u8] = b"C64 CARTRIDGE   ";
+
+/// Minimum size of the file header; some files state a smaller size
+const HEADER_SIZE: usize = 0x40;
+
+/// Size of the header of each CHIP packet
+const CHIP_HEADER_SIZE: usize = 0x10;
+
+/// Names of the most common hardware types, by number
+const HARDWARE_TYPES: [&str; 33] = [
+    "Normal cartridge",
+    "Action Replay",
+    "KCS Power Cartridge",
+    "Final Cartridge III",
+    "Simons' BASIC",
+    "Ocean type 1",
+    "Expert Cartridge",
+    "Fun Play, Power Play",
+    "Super Games",
+    "Atomic Power",
+    "Epyx Fastload",
+    "Westermann Learning",
+    "Rex Utility",
+    "Final Cartridge I",
+    "Magic Formel",
+    "C64 Game System, System 3",
+    "Warp Speed",
+    "Dinamic",
+    "Zaxxon, Super Zaxxon",
+    "Magic Desk, Domark, HES Australia",
+    "Super Snapshot V5",
+    "Comal-80",
+    "Structured BASIC",
+    "Ross",
+    "Dela EP64",
+    "Dela EP7x8",
+    "Dela EP256",
+    "Rex EP256",
+    "Mikro Assembler",
+    "Final Cartridge Plus",
+    "Action Replay 4",
+    "Stardos",
+    "EasyFlash",
+];
+
+/// ROM, RAM, or flash memory in a cartridge, from a CHIP packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chip {
+    /// 0 = ROM, 1 = RAM, 2 = flash, 3 = EEPROM
+    pub chip_type: u16,
+    pub bank: u16,
+    pub load_address: u16,
+    pub data: Vec<u8>,
+}
+
+impl Chip {
+    /// Name of the chip type
+    pub fn type_name(&self) -> &'static str {
+        match self.chip_type {
+            0 => "ROM",
+            1 => "RAM",
+            2 => "Flash",
+            3 => "EEPROM",
+            _ => "unknown",
+        }
+    }
+}
+
+/// Cartridge image with header information and memory chips
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cartridge {
+    pub name: String,
+    /// Major and minor version of the file format
+    pub version: (u8, u8),
+    /// Banking hardware; 0 is a normal cartridge without bank switching
+    pub hardware_type: u16,
+    /// State of the EXROM line; 0 is active
+    pub exrom: u8,
+    /// State of the GAME line; 0 is active
+    pub game: u8,
+    pub chips: Vec<Chip>,
+}
+
+impl Cartridge {
+    /// Parse CRT file contents
+    ///
+    /// Example:
+    /// ~~~
+    /// use matrix65::crt::Cartridge;
+    /// let mut bytes = b"C64 CARTRIDGE   ".to_vec();
+    /// bytes.extend_from_slice(&[0, 0, 0, 0x40, 1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    /// bytes.extend_from_slice(&[b'X'; 32]);
+    /// bytes.extend_from_slice(b"CHIP");
+    /// bytes.extend_from_slice(&[0, 0, 0x20, 0x10, 0, 0, 0, 0, 0x80, 0x00, 0x20, 0x00]);
+    /// bytes.extend_from_slice(&[0xaa; 0x2000]);
+    /// let cart = Cartridge::parse(&bytes).unwrap();
+    /// assert_eq!(cart.hardware_name(), "Normal cartridge");
+    /// assert_eq!(cart.memory_mode(), "8K");
+    /// assert_eq!(cart.chips.len(), 1);
+    /// assert_eq!((cart.chips[0].load_address, cart.chips[0].data.len()), (0x8000, 0x2000));
+    /// assert!(cart.is_simple());
+    /// assert!(Cartridge::parse(&bytes[..0x50]).is_err());
+    /// ~~~
+    pub fn parse(bytes: &[u8]) -> Result<Cartridge> {
+        let parse_error = |message: &str| Matrix65Error::Parse(message.to_string());
+        if bytes.len() < HEADER_SIZE || !bytes.starts_with(SIGNATURE) {
+            return Err(parse_error("not a CRT cartridge image"));
+        }
+        let word = |offset: usize| u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+        let long = |offset: usize| {
+            u32::from_be_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ]) as usize
+        };
+        let name = &bytes[0x20..0x40];
+        let name_length = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+        let mut chips = Vec::new();
+        let mut offset = long(0x10).max(HEADER_SIZE);
+        while offset < bytes.len() {
+            let header = bytes
+                .get(offset..offset + CHIP_HEADER_SIZE)
+                .filter(|header| header.starts_with(b"CHIP"))
+                .ok_or_else(|| parse_error("invalid CHIP packet in CRT image"))?;
+            let packet_length = long(offset + 4);
+            let size = word(offset + 0x0e) as usize;
+            let data = bytes
+                .get(offset + CHIP_HEADER_SIZE..offset + CHIP_HEADER_SIZE + size)
+                .ok_or_else(|| parse_error("CHIP data beyond end of CRT image"))?;
+            chips.push(Chip {
+                chip_type: u16::from_be_bytes([header[8], header[9]]),
+                bank: u16::from_be_bytes([header[10], header[11]]),
+                load_address: u16::from_be_bytes([header[12], header[13]]),
+                data: data.to_vec(),
+            });
+            offset += packet_length.max(CHIP_HEADER_SIZE + size);
+        }
+        Ok(Cartridge {
+            name: String::from_utf8_lossy(&name[..name_length])
+                .trim()
+                .to_string(),
+            version: (bytes[0x14], bytes[0x15]),
+            hardware_type: word(0x16),
+            exrom: bytes[0x18],
+            game: bytes[0x19],
+            chips,
+        })
+    }
+
+    /// Name of the cartridge hardware type
+    pub fn hardware_name(&self) -> &'static str {
+        HARDWARE_TYPES
+            .get(self.hardware_type as usize)
+            .copied()
+            .unwrap_or("unknown")
+    }
+
+    /// Memory configuration selected by the EXROM and GAME lines
+    pub fn memory_mode(&self) -> &'static str {
+        match (self.exrom, self.game) {
+            (0, 0) => "16K",
+            (0, _) => "8K",
+            (_, 0) => "Ultimax",
+            _ => "off",
+        }
+    }
+
+    /// Whether the ROM can simply be copied to RAM, i.e. a normal
+    /// 8K or 16K cartridge with ROM only at $8000 and $A000
+    pub fn is_simple(&self) -> bool {
+        self.hardware_type == 0
+            && matches!(self.memory_mode(), "8K" | "16K")
+            && !self.chips.is_empty()
+            && self.chips.iter().all(|chip| {
+                chip.chip_type == 0
+                    && chip.bank == 0
+                    && matches!(chip.load_address, 0x8000 | 0xa000)
+                    && chip.load_address as usize + chip.data.len() <= 0xc000
+            })
+    }
+
+    /// Address of the cold start routine, from the vector at $8000
+    pub fn cold_start(&self) -> Option<u16> {
+        let rom = self.chips.iter().find(|chip| chip.load_address == 0x8000)?;
+        Some(u16::from_le_bytes([*rom.data.first()?, *rom.data.get(1)?]))
+    }
+
+    /// Go to C64 mode and copy the ROM of a simple cartridge to RAM
+    ///
+    /// Fails for cartridges with bank switching or other hardware;
+    /// see `is_simple()`.
+    pub fn write(&self, comm: &mut dyn M65Communicator) -> Result<()> {
+        if !self.is_simple() {
+            return Err(Matrix65Error::Invalid(format!(
+                "only normal 8K and 16K cartridges can be copied to RAM, not {} ({})",
+                self.hardware_name(),
+                self.memory_mode()
+            )));
+        }
+        comm.go64()?;
+        for chip in &self.chips {
+            comm.write_memory(chip.load_address, &chip.data)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Cartridge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Name:      {}", self.name)?;
+        writeln!(f, "Version:   {}.{}", self.version.0, self.version.1)?;
+        writeln!(
+            f,
+            "Hardware:  {} ({})",
+            self.hardware_type,
+            self.hardware_name()
+        )?;
+        writeln!(
+            f,
+            "Lines:     EXROM {}, GAME {} ({})",
+            self.exrom,
+            self.game,
+            self.memory_mode()
+        )?;
+        write!(f, "Chips:     {}", self.chips.len())?;
+        for chip in &self.chips {
+            write!(
+                f,
+                "\n  {:<6} bank {:<3} at 0x{:04x}, {} bytes",
+                chip.type_name(),
+                chip.bank,
+                chip.load_address,
+                chip.data.len()
+            )?;
+        }
+        Ok(())
+    }
+}
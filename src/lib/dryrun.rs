@@ -0,0 +1,196 @@
+// copyright 2022 mikael lund aka wombat
+//
+// licensed under the apache license, version 2.0 (the "license");
+// you may not use this file except in compliance with the license.
+// you may obtain a copy of the license at
+//
+//     http://www.apache.org/licenses/license-2.0
+//
+// unless required by applicable law or agreed to in writing, software
+// distributed under the license is distributed on an "as is" basis,
+// without warranties or conditions of any kind, either express or implied.
+// see the license for the specific language governing permissions and
+// limitations under the license.
+
+//! Serial port stand-in that logs monitor commands instead of sending them
+//!
+//! Use with `M65Serial::new()` to see what a command would do to the
+//! MEGA65. Nothing is ever received, so commands that depend on a
+//! reply, e.g. reading memory or detecting the C64 mode, time out after
+//! their request has been logged.
+
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+use std::io::{self, ErrorKind, Read, Write};
+use std::time::Duration;
+
+/// Writes longer than this are summarised unless they are text
+const MAX_LOGGED_BINARY: usize = 16;
+
+/// Serial port that prints everything written to stderr
+#[derive(Debug, Clone)]
+pub struct DryRunPort {
+    name: String,
+    baud_rate: u32,
+    timeout: Duration,
+    flow_control: FlowControl,
+}
+
+impl DryRunPort {
+    pub fn new(name: &str, baud_rate: u32) -> DryRunPort {
+        DryRunPort {
+            name: name.to_string(),
+            baud_rate,
+            timeout: crate::serial::DEFAULT_READ_TIMEOUT,
+            flow_control: FlowControl::None,
+        }
+    }
+}
+
+/// Printable form of bytes written to the port
+///
+/// Monitor commands are shown with escaped control characters, while
+/// larger binary payloads are only counted.
+///
+/// Example:
+/// ~~~
+/// use matrix65::dryrun::describe;
+/// assert_eq!(describe(b"sffd3615 41 7f\r"), "sffd3615 41 7f\\r");
+/// assert_eq!(describe(&[0xa9, 0x00]), "\\xa9\\x00");
+/// assert_eq!(describe(&[0u8; 256]), "<256 bytes of binary data>");
+/// ~~~
+pub fn describe(bytes: &[u8]) -> String {
+    let is_text = bytes
+        .iter()
+        .all(|c| c.is_ascii_graphic() || matches!(c, b' ' | b'\r' | b'\n'));
+    match is_text || bytes.len() <= MAX_LOGGED_BINARY {
+        true => bytes.escape_ascii().to_string(),
+        false => format!("<{} bytes of binary data>", bytes.len()),
+    }
+}
+
+impl Write for DryRunPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        eprintln!("[dry-run {}] {}", self.name, describe(buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for DryRunPort {
+    /// Never receives anything; waits for the timeout like a silent port
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        std::thread::sleep(self.timeout);
+        Err(io::Error::new(
+            ErrorKind::TimedOut,
+            "dry run receives nothing",
+        ))
+    }
+}
+
+impl SerialPort for DryRunPort {
+    fn name(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.baud_rate)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(self.flow_control)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.baud_rate = baud_rate;
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _data_bits: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> serialport::Result<()> {
+        self.flow_control = flow_control;
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _parity: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _stop_bits: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, _buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
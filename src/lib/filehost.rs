@@ -14,27 +14,57 @@
 
 //! Routines for accessing the MEGA65 FileHost <https://files.mega65.org>
 
-use anyhow::Result;
-use serde::{Deserialize, Serialize};
+use crate::Matrix65Error;
+use crate::Result;
+use serde::{Deserialize, Deserializer, Serialize};
 
 /// Record for an entry on the MEGA65 FileHost website
+///
+/// Missing fields are left empty, and numbers or `null` are accepted
+/// in place of text, so that changes to the FileHost data do not make
+/// the whole list unreadable.
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
 pub struct Record {
+    #[serde(deserialize_with = "lenient_string")]
     pub fileid: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub title: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub category: String,
-    #[serde(rename = "type")]
+    #[serde(rename = "type", deserialize_with = "lenient_string")]
     pub kind: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub os: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub rating: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub downloads: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub published: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub sortdate: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub versionid: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub filename: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub size: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub location: String,
+    #[serde(deserialize_with = "lenient_string")]
     pub author: String,
+    /// Location of a screenshot, if the entry has one
+    pub screenshot: Option<String>,
+}
+
+/// Deserialize text, also accepting numbers and booleans; `null` gives an empty string
+fn lenient_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::String(text) => text,
+        other => other.to_string(),
+    })
 }
 
 impl Record {
@@ -46,11 +76,67 @@ impl Record {
     pub fn columns(&self) -> Vec<&str> {
         vec![&self.title, &self.kind, &self.author]
     }
+
+    /// Full URL of the screenshot, if any
+    ///
+    /// Example:
+    /// ~~~
+    /// let mut record = matrix65::filehost::Record::default();
+    /// assert_eq!(record.screenshot_url(), None);
+    /// record.screenshot = Some("files/plasma.png".to_string());
+    /// assert_eq!(
+    ///     record.screenshot_url().unwrap(),
+    ///     "https://files.mega65.org/files/plasma.png"
+    /// );
+    /// ~~~
+    pub fn screenshot_url(&self) -> Option<String> {
+        match self.screenshot.as_deref().map(str::trim) {
+            None | Some("") => None,
+            Some(location) if location.starts_with("http") => Some(location.to_string()),
+            Some(location) => Some(format!("https://files.mega65.org/{}", location)),
+        }
+    }
+}
+
+/// Download and decode a screenshot image
+pub fn get_screenshot(url: &str) -> Result<image::RgbImage> {
+    let bytes = crate::io::load_bytes_url(url)?;
+    Ok(image::load_from_memory(&bytes)?.to_rgb8())
 }
 
 /// Get list of records from the filehost
 pub fn get_file_list() -> Result<Vec<Record>> {
     let url = "https://files.mega65.org/php/readfilespublic.php";
-    let body = reqwest::blocking::get(url)?.text()?;
-    Ok(serde_json::from_str(&body)?)
+    let response = reqwest::blocking::get(url).map_err(|err| crate::io::http_error(url, err))?;
+    if !response.status().is_success() {
+        let reason = format!(
+            "FileHost returned {}; the service may be down",
+            response.status()
+        );
+        return Err(crate::io::http_error(url, reason));
+    }
+    parse_file_list(&response.text()?)
+}
+
+/// Parse the JSON list of FileHost records
+///
+/// HTML error pages and other unexpected replies give a clear error
+/// rather than a JSON syntax error.
+///
+/// Example:
+/// ~~~
+/// use matrix65::filehost::parse_file_list;
+/// let records = parse_file_list(r#"[{"title": "Plasma", "rating": 4, "os": null}]"#).unwrap();
+/// assert_eq!(records[0].title, "Plasma");
+/// assert_eq!(records[0].rating, "4");
+/// assert_eq!(records[0].os, "");
+/// assert!(parse_file_list("<html>502 Bad Gateway</html>").is_err());
+/// ~~~
+pub fn parse_file_list(body: &str) -> Result<Vec<Record>> {
+    const UNEXPECTED: &str = "FileHost returned an unexpected response; the service may be down";
+    if !body.trim_start().starts_with('[') {
+        return Err(Matrix65Error::Parse(UNEXPECTED.to_string()));
+    }
+    serde_json::from_str(body)
+        .map_err(|err| Matrix65Error::Parse(format!("{} ({})", UNEXPECTED, err)))
 }
@@ -0,0 +1,142 @@
+// copyright 2022 mikael lund aka wombat
+//
+// licensed under the apache license, version 2.0 (the "license");
+// you may not use this file except in compliance with the license.
+// you may obtain a copy of the license at
+//
+//     http://www.apache.org/licenses/license-2.0
+//
+// unless required by applicable law or agreed to in writing, software
+// distributed under the license is distributed on an "as is" basis,
+// without warranties or conditions of any kind, either express or implied.
+// see the license for the specific language governing permissions and
+// limitations under the license.
+
+//! Identification of the connected machine and its FPGA core
+
+use crate::M65Communicator;
+use crate::Result;
+use serde::Serialize;
+use std::fmt;
+
+/// Hardware model register
+const MODEL_ID: u32 = 0xffd3629;
+
+/// First of four bytes with the git commit of the FPGA core, little endian
+const CORE_COMMIT: u32 = 0xffd362c;
+
+/// Name of the hardware model from its id
+///
+/// Example:
+/// ~~~
+/// use matrix65::info::model_name;
+/// assert_eq!(model_name(0x03), "MEGA65 R3");
+/// assert_eq!(model_name(0x99), "unknown");
+/// ~~~
+pub const fn model_name(model_id: u8) -> &'static str {
+    match model_id {
+        0x01 => "MEGA65 R1",
+        0x02 => "MEGA65 R2",
+        0x03 => "MEGA65 R3",
+        0x04 => "MEGA65 R4",
+        0x05 => "MEGA65 R5",
+        0x21 => "MEGAphone R1",
+        0x40 => "Nexys4",
+        0x41 => "Nexys4 DDR",
+        0x42 => "Nexys4 DDR with widget board",
+        0xfd => "QMTECH Wukong",
+        0xfe => "Simulation",
+        _ => "unknown",
+    }
+}
+
+/// Find core and hypervisor versions in the monitor's help text
+///
+/// Returns the text after `build` and `HYPPO` on lines that start
+/// with them, if present.
+///
+/// Example:
+/// ~~~
+/// use matrix65::info::parse_versions;
+/// let help = "MEGA65 Serial Monitor\r\nbuild GIT: development,20220822.00,ab12cd3\r\n";
+/// let (core, hypervisor) = parse_versions(help);
+/// assert_eq!(core.as_deref(), Some("GIT: development,20220822.00,ab12cd3"));
+/// assert_eq!(hypervisor, None);
+/// ~~~
+pub fn parse_versions(help: &str) -> (Option<String>, Option<String>) {
+    let find = |prefix: &str| {
+        help.lines()
+            .map(str::trim)
+            .find(|line| line.to_lowercase().starts_with(prefix))
+            .map(|line| line[prefix.len()..].trim().to_string())
+    };
+    (find("build"), find("hyppo"))
+}
+
+/// Machine model, core version, and current mode
+///
+/// Field names are stable as they are used for JSON output.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MachineInfo {
+    /// Raw hardware model id
+    pub model_id: u8,
+    /// Human readable model, see `model_name()`
+    pub model: String,
+    /// Git commit of the FPGA core as eight hex digits
+    pub core_commit: String,
+    /// Core version reported by the monitor, if available
+    pub core_version: Option<String>,
+    /// Hypervisor (HYPPO) version reported by the monitor, if available
+    pub hypervisor_version: Option<String>,
+    /// Current mode, "c64" or "c65"
+    pub mode: String,
+}
+
+impl MachineInfo {
+    /// Read machine information from the MEGA65
+    ///
+    /// Versions are only filled in if the connection supports
+    /// `M65Communicator::monitor_help()`.
+    pub fn read<C: M65Communicator + ?Sized>(comm: &mut C) -> Result<MachineInfo> {
+        let (core_version, hypervisor_version) = match comm.monitor_help() {
+            Ok(help) => parse_versions(&help),
+            Err(_) => (None, None),
+        };
+        let model_id = comm.peek(MODEL_ID)?;
+        let commit = comm.read_memory(CORE_COMMIT, 4)?;
+        let mode = match comm.is_c65_mode()? {
+            true => "c65",
+            false => "c64",
+        };
+        Ok(MachineInfo {
+            model_id,
+            model: model_name(model_id).to_string(),
+            core_commit: format!(
+                "{:08x}",
+                u32::from_le_bytes([commit[0], commit[1], commit[2], commit[3]])
+            ),
+            core_version,
+            hypervisor_version,
+            mode: mode.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for MachineInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Model:        {} (0x{:02x})", self.model, self.model_id)?;
+        writeln!(f, "Core commit:  {}", self.core_commit)?;
+        let unknown = String::from("unknown");
+        writeln!(
+            f,
+            "Core version: {}",
+            self.core_version.as_ref().unwrap_or(&unknown)
+        )?;
+        writeln!(
+            f,
+            "Hypervisor:   {}",
+            self.hypervisor_version.as_ref().unwrap_or(&unknown)
+        )?;
+        write!(f, "Mode:         {}", self.mode.to_uppercase())
+    }
+}
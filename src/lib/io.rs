@@ -14,20 +14,59 @@
 
 //! Routines for file; url; and terminal I/O
 
-use anyhow::Result;
 use cbm::disk;
 use cbm::disk::file::FileOps;
+use cbm::Petscii;
+use crossterm::tty::IsTty;
 use disasm6502;
-use log::debug;
+use log::{debug, warn};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs::File;
 use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 use tempfile::Builder;
 
-use crate::LoadAddress;
+use crate::{cache, LoadAddress, Matrix65Error, Result};
 
 /// Fill byte vector from url with compatible error
-fn load_bytes_url(url: &str) -> Result<Vec<u8>> {
-    Ok(reqwest::blocking::get(url)?.bytes()?.to_vec())
+///
+/// Goes through the download cache unless it is disabled.
+pub fn load_bytes_url(url: &str) -> Result<Vec<u8>> {
+    if cache::is_enabled() {
+        return Ok(std::fs::read(cache::fetch(url)?)?);
+    }
+    let bytes = get_url(url)?.bytes().map_err(|err| http_error(url, err))?;
+    Ok(bytes.to_vec())
+}
+
+/// Download error naming the URL
+pub(crate) fn http_error(url: &str, reason: impl fmt::Display) -> Matrix65Error {
+    Matrix65Error::Http {
+        url: url.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+/// Send GET request, with errors that name the URL
+pub(crate) fn get_url(url: &str) -> Result<reqwest::blocking::Response> {
+    let response = reqwest::blocking::get(url).map_err(|err| http_error(url, err))?;
+    check_response(url, response)
+}
+
+/// Fail unless the server replied with success, naming the URL and HTTP status
+pub(crate) fn check_response(
+    url: &str,
+    response: reqwest::blocking::Response,
+) -> Result<reqwest::blocking::Response> {
+    match response.status() {
+        status if status.is_success() => Ok(response),
+        reqwest::StatusCode::NOT_FOUND if url.contains("files.mega65.org") => {
+            Err(http_error(url, "no longer on the FileHost (404 Not Found)"))
+        }
+        status => Err(http_error(url, format!("HTTP {}", status))),
+    }
 }
 
 /// Load file or url into byte vector
@@ -38,29 +77,294 @@ pub fn load_bytes(filename: &str) -> Result<Vec<u8>> {
     } else {
         File::open(filename)?.read_to_end(&mut bytes)?;
     }
-    assert!(bytes.len() < u16::MAX as usize);
     Ok(bytes)
 }
 
-/// Load PRG from prg and CBM disk files
+/// CBM disk image formats, identified by file extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskFormat {
+    /// 1541 single sided 5.25" disk
+    D64,
+    /// 1571 double sided 5.25" disk
+    D71,
+    /// 1581 3.5" disk, also used by the MEGA65 internal drive
+    D81,
+    /// 8250 double sided 5.25" disk
+    D82,
+    /// 1541 disk as raw GCR tracks, used to preserve copy protection
+    G64,
+    /// 1541 disk as raw nibbles read by nibtools
+    NIB,
+}
+
+impl DiskFormat {
+    /// Detect format from the file extension (case insensitive)
+    ///
+    /// Example:
+    /// ~~~
+    /// use matrix65::io::DiskFormat;
+    /// assert_eq!(DiskFormat::from_filename("games.D71"), Some(DiskFormat::D71));
+    /// assert_eq!(DiskFormat::from_filename("https://host/disk.d81"), Some(DiskFormat::D81));
+    /// assert_eq!(DiskFormat::from_filename("program.prg"), None);
+    /// assert!(!DiskFormat::D82.is_supported());
+    /// assert_eq!(DiskFormat::from_filename("elite.g64"), Some(DiskFormat::G64));
+    /// assert!(!DiskFormat::G64.is_supported());
+    /// let err = matrix65::io::load_prg("elite.g64").unwrap_err();
+    /// assert!(err.to_string().contains("raw GCR tracks"));
+    /// ~~~
+    pub fn from_filename(filename: &str) -> Option<DiskFormat> {
+        let extension = std::path::Path::new(filename).extension()?;
+        match extension.to_ascii_lowercase().to_str()? {
+            "d64" => Some(DiskFormat::D64),
+            "d71" => Some(DiskFormat::D71),
+            "d81" => Some(DiskFormat::D81),
+            "d82" => Some(DiskFormat::D82),
+            "g64" => Some(DiskFormat::G64),
+            "nib" => Some(DiskFormat::NIB),
+            _ => None,
+        }
+    }
+
+    /// Whether the cbm crate can read and write this geometry
+    pub const fn is_supported(&self) -> bool {
+        !matches!(self, DiskFormat::D82 | DiskFormat::G64 | DiskFormat::NIB)
+    }
+
+    /// Image sizes in bytes without and with error info bytes appended
+    ///
+    /// The canonical geometry comes first; D64 images may also have
+    /// 40 instead of 35 tracks. Empty for unsupported formats.
+    const fn geometries(&self) -> &'static [(usize, usize)] {
+        match self {
+            DiskFormat::D64 => &[(174848, 175531), (196608, 197376)],
+            DiskFormat::D71 => &[(349696, 351062)],
+            DiskFormat::D81 => &[(819200, 822400)],
+            DiskFormat::D82 | DiskFormat::G64 | DiskFormat::NIB => &[],
+        }
+    }
+
+    /// Check the byte length of an image against the known geometries
+    ///
+    /// Example:
+    /// ~~~
+    /// use matrix65::io::{DiskFormat, SizeCheck};
+    /// assert_eq!(DiskFormat::D81.check_size(819200), SizeCheck::Valid);
+    /// assert_eq!(DiskFormat::D64.check_size(175531), SizeCheck::ErrorInfo { size: 174848 });
+    /// assert_eq!(DiskFormat::D64.check_size(174850), SizeCheck::Unexpected { canonical: 174848 });
+    /// assert_eq!(DiskFormat::G64.check_size(1234), SizeCheck::Valid);
+    /// ~~~
+    pub fn check_size(&self, size: usize) -> SizeCheck {
+        let geometries = self.geometries();
+        if let Some((plain, _)) = geometries.iter().find(|(_, errors)| *errors == size) {
+            return SizeCheck::ErrorInfo { size: *plain };
+        }
+        match geometries.first() {
+            Some((canonical, _)) if !geometries.iter().any(|(plain, _)| *plain == size) => {
+                SizeCheck::Unexpected {
+                    canonical: *canonical,
+                }
+            }
+            _ => SizeCheck::Valid,
+        }
+    }
+
+    /// Valid sizes for messages, e.g. "819200 (822400 with error info)"
+    fn expected_sizes(&self) -> String {
+        self.geometries()
+            .iter()
+            .map(|(plain, errors)| format!("{} ({} with error info)", plain, errors))
+            .collect::<Vec<_>>()
+            .join(" or ")
+    }
+
+    /// Corresponding cbm crate disk type, or an explanation if unsupported
+    fn disk_type(&self) -> Result<disk::DiskType> {
+        match self {
+            DiskFormat::D64 => Ok(disk::DiskType::D64),
+            DiskFormat::D71 => Ok(disk::DiskType::D71),
+            DiskFormat::D81 => Ok(disk::DiskType::D81),
+            DiskFormat::D82 => Err(Matrix65Error::CbmDisk(format!(
+                "{} disk images are recognised but not supported by the cbm crate",
+                self
+            ))),
+            DiskFormat::G64 | DiskFormat::NIB => Err(Matrix65Error::CbmDisk(format!(
+                "{} images store raw GCR tracks that the cbm crate cannot read; \
+                 convert to .d64 first, e.g. with nibconv from nibtools, \
+                 although copy protected titles may not survive the conversion",
+                self
+            ))),
+        }
+    }
+}
+
+/// Outcome of `DiskFormat::check_size()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeCheck {
+    /// Matches a known geometry, or the format has no fixed size
+    Valid,
+    /// Error info bytes are appended to an image of `size` bytes
+    ErrorInfo { size: usize },
+    /// Off-spec image; `canonical` is the usual size of the format
+    Unexpected { canonical: usize },
+}
+
+impl fmt::Display for DiskFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, drive) = match self {
+            DiskFormat::D64 => ("D64", "1541"),
+            DiskFormat::D71 => ("D71", "1571"),
+            DiskFormat::D81 => ("D81", "1581"),
+            DiskFormat::D82 => ("D82", "8250"),
+            DiskFormat::G64 => ("G64", "1541"),
+            DiskFormat::NIB => ("NIB", "1541"),
+        };
+        write!(f, "{} ({})", name, drive)
+    }
+}
+
+/// Load PRG from prg, CBM disk, and T64 tape files
 ///
-/// If an archive (.d64|.d81) is detected, the user is presented with a selection
-/// of found PRG files. Returns intended load address and raw bytes.
+/// If a disk image (.d64|.d71|.d81|.d82|.g64|.nib) or tape archive (.t64) is detected,
+/// the user is presented with a selection of found PRG files. Returns intended
+/// load address and raw bytes.
 pub fn load_prg(file: &str) -> Result<(LoadAddress, Vec<u8>)> {
+    if let Some(format) = DiskFormat::from_filename(file) {
+        format.disk_type()?;
+        return cbm_select_and_load(file);
+    }
     match std::path::Path::new(&file).extension() {
         None => load_with_load_address(file),
         Some(os_str) => match os_str.to_ascii_lowercase().to_str() {
             Some("prg") => load_with_load_address(file),
-            Some("d81") | Some("d64") => cbm_select_and_load(file),
-            _ => Err(anyhow::Error::msg("invalid file extension")),
+            Some("t64") => t64_select_and_load(file),
+            _ => Err(Matrix65Error::Invalid(format!(
+                "unknown file extension of {}; expected .prg, .t64, .d64, .d71, .d81, .d82, .g64, or .nib",
+                file
+            ))),
         },
     }
 }
 
+/// Size of the T64 header and of each directory entry
+const T64_HEADER_SIZE: usize = 0x40;
+const T64_ENTRY_SIZE: usize = 0x20;
+
+/// File stored in a T64 tape archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TapeFile {
+    pub name: String,
+    pub load_address: u16,
+    /// Contents without the load address
+    pub bytes: Vec<u8>,
+}
+
+/// Parse the directory of a T64 tape archive and extract all files
+///
+/// Many T64 files have an incorrect end address in the directory, so the
+/// length of each file is capped by the start of the next file and the
+/// archive size.
+///
+/// Example:
+/// ~~~
+/// let mut image = vec![0u8; 0x60];
+/// image[..3].copy_from_slice(b"C64");
+/// image[0x22] = 1; // directory entries
+/// image[0x24] = 1; // used entries
+/// image[0x40] = 1; // normal tape file
+/// image[0x41] = 0x82; // PRG
+/// image[0x42..0x46].copy_from_slice(&[0x01, 0x08, 0x04, 0x08]); // $0801-$0804
+/// image[0x48] = 0x60; // offset to data
+/// image[0x50..0x60].copy_from_slice(b"HELLO           ");
+/// image.extend_from_slice(&[1, 2, 3]);
+/// let files = matrix65::io::t64_files(&image).unwrap();
+/// assert_eq!(files.len(), 1);
+/// assert_eq!(files[0].name, "HELLO");
+/// assert_eq!(files[0].load_address, 0x0801);
+/// assert_eq!(files[0].bytes, [1, 2, 3]);
+/// ~~~
+pub fn t64_files(image: &[u8]) -> Result<Vec<TapeFile>> {
+    if image.len() < T64_HEADER_SIZE || !image.starts_with(b"C64") {
+        return Err(Matrix65Error::CbmDisk("not a T64 tape archive".to_string()));
+    }
+    let word = |offset: usize| u16::from_le_bytes([image[offset], image[offset + 1]]) as usize;
+    let entries = match word(0x22) {
+        0 => word(0x24),
+        n => n,
+    };
+    let directory: Vec<&[u8]> = image[T64_HEADER_SIZE..]
+        .chunks_exact(T64_ENTRY_SIZE)
+        .take(entries)
+        .filter(|entry| entry[0] == 1)
+        .collect();
+    let offset_of = |entry: &[u8]| u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]);
+    let mut offsets: Vec<usize> = directory.iter().map(|e| offset_of(e) as usize).collect();
+    offsets.push(image.len());
+    offsets.sort_unstable();
+    directory
+        .iter()
+        .map(|entry| {
+            let start = offset_of(entry) as usize;
+            let load_address = u16::from_le_bytes([entry[2], entry[3]]);
+            let end_address = u16::from_le_bytes([entry[4], entry[5]]);
+            let next = offsets
+                .iter()
+                .copied()
+                .find(|o| *o > start)
+                .unwrap_or(start);
+            let length = (end_address.wrapping_sub(load_address) as usize).min(next - start);
+            let bytes = image.get(start..start + length).ok_or_else(|| {
+                Matrix65Error::CbmDisk("T64 file data beyond end of archive".to_string())
+            })?;
+            // names are padded with spaces or shifted spaces
+            let name = &entry[0x10..0x20];
+            let name_length = name
+                .iter()
+                .rposition(|c| !matches!(c, b' ' | 0xa0 | 0))
+                .map_or(0, |i| i + 1);
+            let name = String::from_utf8_lossy(&name[..name_length]).to_string();
+            Ok(TapeFile {
+                name,
+                load_address,
+                bytes: bytes.to_vec(),
+            })
+        })
+        .collect()
+}
+
+/// Read all files from a T64 tape archive file or url
+pub fn t64_open(filename: &str) -> Result<Vec<TapeFile>> {
+    debug!("Opening T64 archive {}", filename);
+    let image = match filename.starts_with("http") {
+        true => load_bytes_url(filename)?,
+        false => std::fs::read(filename)?,
+    };
+    t64_files(&image)
+}
+
+/// User select file from T64 tape archive file or url
+fn t64_select_and_load(filename: &str) -> Result<(LoadAddress, Vec<u8>)> {
+    let files = t64_open(filename)?;
+    for (counter, file) in files.iter().enumerate() {
+        println!("[{}] {}.prg", counter, file.name);
+    }
+    print!("Select: ");
+    io::stdout().flush()?;
+    let mut selection = String::new();
+    io::stdin().read_line(&mut selection)?;
+    let index = selection.trim_end().parse::<usize>()?;
+    let file = files
+        .into_iter()
+        .nth(index)
+        .ok_or_else(|| Matrix65Error::Invalid("invalid selection".to_string()))?;
+    Ok((LoadAddress::new(file.load_address), file.bytes))
+}
+
 /// Purge and return load address from vector of bytes
 ///
 /// The two first bytes form the 16-bit load address, little endian.
 /// Returns found address and removes the first two bytes from the byte vector.
+/// The size is not checked here, as the program may be written elsewhere;
+/// see `M65Communicator::handle_prg_from_bytes()`.
 ///
 /// Example:
 /// ~~~
@@ -69,6 +373,11 @@ pub fn load_prg(file: &str) -> Result<(LoadAddress, Vec<u8>)> {
 /// assert_eq!(load_address.value(), 0x0801);
 /// assert_eq!(bytes.len(), 1);
 /// assert_eq!(bytes[0], 0xff);
+///
+/// let mut large = vec![0x01, 0x08];
+/// large.resize(0x20002, 0);
+/// assert!(matrix65::io::purge_load_address(&mut large).is_ok());
+/// assert!(matrix65::io::purge_load_address(&mut vec![0x01]).is_err());
 /// ~~~
 pub fn purge_load_address(bytes: &mut Vec<u8>) -> Result<LoadAddress> {
     let address = LoadAddress::from_bytes(bytes)?;
@@ -76,27 +385,185 @@ pub fn purge_load_address(bytes: &mut Vec<u8>) -> Result<LoadAddress> {
     Ok(address)
 }
 
+/// Detect if bytes loaded at `load_address` form a BASIC program
+///
+/// The load address must be the start of BASIC in C64 or C65 mode
+/// ($0801/$2001) and the bytes must hold a chain of BASIC lines, each
+/// linking to the next and ending with a zero byte, terminated by a zero
+/// link. Otherwise the program is assumed to be machine code that should
+/// be started with `SYS`. Programs with a BASIC stub like `10 SYS2061`
+/// count as BASIC.
+///
+/// Examples:
+/// ~~~
+/// use matrix65::io::is_basic_program;
+/// let stub = [0x0b, 0x08, 0x0a, 0x00, 0x9e, 0x32, 0x30, 0x36, 0x31, 0x00, 0x00, 0x00];
+/// assert!(is_basic_program(0x0801, &stub));
+/// assert!(!is_basic_program(0xc000, &stub));
+/// assert!(!is_basic_program(0x0801, &[0xa9, 0x00, 0x8d, 0x20, 0xd0, 0x60]));
+/// ~~~
+pub fn is_basic_program(load_address: u16, bytes: &[u8]) -> bool {
+    if !matches!(load_address, 0x0801 | 0x2001) {
+        return false;
+    }
+    let mut offset = 0;
+    let mut lines = 0;
+    while offset + 2 <= bytes.len() {
+        let link = u16::from_le_bytes([bytes[offset], bytes[offset + 1]]) as usize;
+        if link == 0 {
+            return lines > 0;
+        }
+        // link, line number, at least one token, and the terminating zero
+        let next = match link.checked_sub(load_address as usize) {
+            Some(next) if next >= offset + 5 && next <= bytes.len() => next,
+            _ => return false,
+        };
+        if bytes[next - 1] != 0 {
+            return false;
+        }
+        offset = next;
+        lines += 1;
+    }
+    false
+}
+
+/// BASIC token for SYS
+const TOKEN_SYS: u8 = 0x9e;
+
+/// Tokenized BASIC program `10 SYS<entry>` to place at `basic_start`
+///
+/// Lets machine code be started with RUN, e.g. from $0801 in C64 mode.
+/// The program has no load address header.
+///
+/// Examples:
+/// ~~~
+/// use matrix65::io::{is_basic_program, sys_stub};
+/// let stub = sys_stub(0x0801, 2061);
+/// assert_eq!(stub, [0x0b, 0x08, 0x0a, 0x00, 0x9e, 0x32, 0x30, 0x36, 0x31, 0x00, 0x00, 0x00]);
+/// assert!(is_basic_program(0x0801, &stub));
+/// assert_eq!(sys_stub(0x2001, 0xc000)[..2], [0x0c, 0x20]);
+/// ~~~
+pub fn sys_stub(basic_start: u16, entry: u16) -> Vec<u8> {
+    let line_number: u16 = 10;
+    let mut line = line_number.to_le_bytes().to_vec();
+    line.push(TOKEN_SYS);
+    line.extend(entry.to_string().bytes());
+    line.push(0);
+    let next_line = basic_start + 2 + line.len() as u16;
+    let mut stub = next_line.to_le_bytes().to_vec();
+    stub.extend(line);
+    // a zero link ends the program
+    stub.extend([0, 0]);
+    stub
+}
+
 /// Open a CBM disk image from file or url
+///
+/// Errors from the cbm crate are reported together with the
+/// expected format so that unreadable images can be diagnosed.
 pub fn cbm_open(diskimage: &str) -> Result<Box<dyn cbm::disk::Disk>> {
+    if let Some(format) = DiskFormat::from_filename(diskimage) {
+        format.disk_type()?;
+    }
+    let path = match diskimage.starts_with("http") {
+        true if cache::is_enabled() => cache::fetch(diskimage)?,
+        true => return cbm_open_bytes(diskimage, &load_bytes_url(diskimage)?),
+        false => PathBuf::from(diskimage),
+    };
+    open_disk_file(diskimage, path)
+}
+
+/// Open a CBM disk image already loaded into memory, e.g. downloaded in the background
+///
+/// `diskimage` is the original file name or url, which gives the format.
+pub fn cbm_open_bytes(diskimage: &str, bytes: &[u8]) -> Result<Box<dyn cbm::disk::Disk>> {
+    if let Some(format) = DiskFormat::from_filename(diskimage) {
+        format.disk_type()?;
+    }
+    let tmp_dir = Builder::new().tempdir()?;
+    let path = tmp_dir.path().join("temp-image");
+    std::fs::write(&path, bytes)?;
+    open_disk_file(diskimage, path)
+}
+
+/// Open the disk image at `path`, checking its size against the format of `diskimage`
+fn open_disk_file(diskimage: &str, mut path: PathBuf) -> Result<Box<dyn cbm::disk::Disk>> {
     debug!("Opening CBM disk {}", diskimage);
-    if diskimage.starts_with("http") {
-        let bytes = load_bytes_url(diskimage)?;
-        let tmp_dir = Builder::new().tempdir()?;
-        let path = tmp_dir.path().join("temp-image");
-        let filename = path.to_str().unwrap_or("");
-        save_binary(filename, &bytes)?;
-        Ok(disk::open(filename, false)?)
-    } else {
-        Ok(disk::open(diskimage, false)?)
+    let format = DiskFormat::from_filename(diskimage);
+    let describe = |err: std::io::Error| {
+        let message = match format {
+            Some(format) => format!("cannot open {} as {} image: {}", diskimage, format, err),
+            None => format!("cannot open disk image {}: {}", diskimage, err),
+        };
+        Matrix65Error::CbmDisk(message)
+    };
+    // holds resized copies of the image
+    let tmp_dir = Builder::new().tempdir()?;
+    // size to retry with if the image is off-spec
+    let mut canonical = None;
+    if let (Some(format), Ok(metadata)) = (format, std::fs::metadata(&path)) {
+        let size = metadata.len() as usize;
+        match format.check_size(size) {
+            SizeCheck::Valid => {}
+            SizeCheck::ErrorInfo { size: plain } => {
+                warn!(
+                    "{} image has {} error info bytes appended; stripping them",
+                    format,
+                    size - plain
+                );
+                path = resize_disk_image(&path, plain, tmp_dir.path())?;
+            }
+            SizeCheck::Unexpected {
+                canonical: expected,
+            } => {
+                warn!(
+                    "{} image is {} bytes but should be {}",
+                    format,
+                    size,
+                    format.expected_sizes()
+                );
+                canonical = Some(expected);
+            }
+        }
+    }
+    let err = match disk::open(&path, false) {
+        Ok(disk) => return Ok(disk),
+        Err(err) => err,
+    };
+    // many dumps in the wild are a few bytes off; report the original error if resizing fails
+    if let Some(size) = canonical {
+        warn!(
+            "Retrying with the image padded or truncated to {} bytes",
+            size
+        );
+        let resized = resize_disk_image(&path, size, tmp_dir.path());
+        if let Ok(disk) = resized.and_then(|path| Ok(disk::open(path, false)?)) {
+            return Ok(disk);
+        }
     }
+    Err(describe(err))
+}
+
+/// Copy of a disk image padded with zeros or truncated to `size` bytes
+///
+/// The copy is placed in `dir`.
+fn resize_disk_image(path: &Path, size: usize, dir: &Path) -> Result<PathBuf> {
+    let mut bytes = std::fs::read(path)?;
+    bytes.resize(size, 0);
+    let resized = dir.join("resized-image");
+    std::fs::write(&resized, &bytes)?;
+    Ok(resized)
 }
 
 /// Load n'th file from CBM disk image and return load address and bytes
+///
+/// The index refers to the position in directory order, i.e. as
+/// returned by `Disk::directory()`, including non-PRG files.
 pub fn cbm_load_file(disk: &dyn cbm::disk::Disk, index: usize) -> Result<(LoadAddress, Vec<u8>)> {
     let dir = disk.directory()?;
     let entry = dir
         .get(index)
-        .ok_or_else(|| anyhow::Error::msg("invalid selection"))?;
+        .ok_or_else(|| Matrix65Error::Invalid("invalid selection".to_string()))?;
     let mut bytes = Vec::<u8>::new();
     disk.open_file(&entry.filename)?
         .reader()?
@@ -105,6 +572,137 @@ pub fn cbm_load_file(disk: &dyn cbm::disk::Disk, index: usize) -> Result<(LoadAd
     Ok((load_address, bytes))
 }
 
+/// Create an empty CBM disk image; the type is given by the file extension
+///
+/// The image is overwritten if it already exists, so call
+/// `prepare_disk_write()` first.
+pub fn cbm_create(diskimage: &str, name: &str) -> Result<Box<dyn cbm::disk::Disk>> {
+    let format = DiskFormat::from_filename(diskimage).ok_or_else(|| {
+        Matrix65Error::CbmDisk("disk image must end with .d64, .d71, or .d81".to_string())
+    })?;
+    let disk_type = format.disk_type()?;
+    debug!("Creating CBM disk {}", diskimage);
+    // the cbm crate only creates new files when asked not to overwrite, so start from an empty one
+    File::create(diskimage)?;
+    let mut disk: Box<dyn cbm::disk::Disk> = match disk_type {
+        disk::DiskType::D64 => Box::new(disk::D64::create(
+            diskimage,
+            disk::D64::geometry(false),
+            false,
+        )?),
+        disk::DiskType::D71 => Box::new(disk::D71::create(
+            diskimage,
+            disk::D71::geometry(false),
+            false,
+        )?),
+        disk::DiskType::D81 => Box::new(disk::D81::create(
+            diskimage,
+            disk::D81::geometry(false),
+            false,
+        )?),
+    };
+    // writes the header with disk name and id, and an empty directory and BAM
+    let name = Petscii::from(name.to_uppercase().as_str());
+    disk.write_format(&name, &disk::Id::from("65"))?;
+    Ok(disk)
+}
+
+/// Write bytes as a new PRG file to a CBM disk image, prepending the load address
+pub fn cbm_write_file(
+    disk: &mut dyn cbm::disk::Disk,
+    name: &str,
+    load_address: u16,
+    bytes: &[u8],
+) -> Result<()> {
+    debug!("Writing {} bytes to {}.prg on CBM disk", bytes.len(), name);
+    let filename = Petscii::from(name.to_uppercase().as_str());
+    let file = disk.create_file(
+        &filename,
+        cbm::disk::directory::FileType::PRG,
+        cbm::disk::file::Scheme::Linear,
+    )?;
+    let mut writer = file.writer()?;
+    writer.write_all(&load_address.to_le_bytes())?;
+    writer.write_all(bytes)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Data bytes per disk block; the first two bytes link to the next block
+const CBM_BLOCK_DATA_SIZE: u64 = 254;
+
+/// File in the directory of a CBM disk image or tape archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    /// File type, e.g. "PRG" or "SEQ"
+    pub file_type: String,
+    /// Size in disk blocks
+    pub blocks: u64,
+}
+
+impl DirEntry {
+    /// Whether this is a program that can be loaded and run
+    pub fn is_prg(&self) -> bool {
+        self.file_type.trim().eq_ignore_ascii_case("prg")
+    }
+}
+
+/// Tape files are given the number of blocks they would take on disk
+impl From<&TapeFile> for DirEntry {
+    fn from(file: &TapeFile) -> DirEntry {
+        DirEntry {
+            name: file.name.clone(),
+            file_type: "PRG".to_string(),
+            blocks: (file.bytes.len() as u64 + 2).div_ceil(CBM_BLOCK_DATA_SIZE),
+        }
+    }
+}
+
+/// Line in a BASIC style directory listing, i.e. blocks, quoted name, and type
+///
+/// Example:
+/// ~~~
+/// use matrix65::io::{DirEntry, TapeFile};
+/// let file = TapeFile { name: "HELLO".to_string(), load_address: 0x0801, bytes: vec![0; 300] };
+/// let entry = DirEntry::from(&file);
+/// assert!(entry.is_prg());
+/// assert_eq!(entry.blocks, 2);
+/// assert_eq!(entry.to_string(), "2    \"HELLO\"            PRG");
+/// ~~~
+impl fmt::Display for DirEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let quoted = format!("\"{}\"", self.name);
+        write!(f, "{:<5}{:<19}{}", self.blocks, quoted, self.file_type)
+    }
+}
+
+/// Directory of an open CBM disk image, in directory order
+///
+/// Indices into the returned list can be passed to `cbm_load_file()`.
+pub fn cbm_disk_directory(disk: &dyn cbm::disk::Disk) -> Result<Vec<DirEntry>> {
+    Ok(disk
+        .directory()?
+        .iter()
+        .map(|entry| DirEntry {
+            name: entry.filename.to_string(),
+            file_type: entry.file_attributes.file_type.to_string(),
+            blocks: entry.file_size as u64,
+        })
+        .collect())
+}
+
+/// Directory of a CBM disk image or T64 tape archive file or url
+///
+/// Nothing is printed or loaded, so the listing can be shown in any
+/// way, e.g. by the `dir` command or the TUI.
+pub fn cbm_directory(diskimage: &str) -> Result<Vec<DirEntry>> {
+    if diskimage.to_lowercase().ends_with(".t64") {
+        return Ok(t64_open(diskimage)?.iter().map(DirEntry::from).collect());
+    }
+    cbm_disk_directory(cbm_open(diskimage)?.as_ref())
+}
+
 /// User select PRG file from CBM image file or url
 ///
 /// Looks for PRG files on the CBM disk image and
@@ -113,28 +711,23 @@ pub fn cbm_load_file(disk: &dyn cbm::disk::Disk, index: usize) -> Result<(LoadAd
 /// address together with raw bytes.
 fn cbm_select_and_load(diskimage: &str) -> Result<(LoadAddress, Vec<u8>)> {
     let disk = cbm_open(diskimage)?;
-    let dir = disk.directory()?;
-    let prg_files = &mut dir
-        .iter()
-        .filter(|entry| entry.file_attributes.file_type == cbm::disk::directory::FileType::PRG);
-    for (counter, file) in prg_files.clone().enumerate() {
-        println!("[{}] {}.prg", counter, file.filename.to_string());
+    let prg_files: Vec<(usize, DirEntry)> = cbm_disk_directory(disk.as_ref())?
+        .into_iter()
+        .enumerate()
+        .filter(|(_, entry)| entry.is_prg())
+        .collect();
+    for (counter, (_, entry)) in prg_files.iter().enumerate() {
+        println!("[{}] {}.prg", counter, entry.name);
     }
     print!("Select: ");
     io::stdout().flush()?;
     let mut selection = String::new();
     io::stdin().read_line(&mut selection)?;
     let index = selection.trim_end().parse::<usize>()?;
-
-    let entry = prg_files
-        .nth(index)
-        .ok_or_else(|| anyhow::Error::msg("invalid selection"))?;
-    let mut bytes = Vec::<u8>::new();
-    disk.open_file(&entry.filename)?
-        .reader()?
-        .read_to_end(&mut bytes)?;
-    let load_address = purge_load_address(&mut bytes)?;
-    Ok((load_address, bytes))
+    let (dir_index, _) = prg_files
+        .get(index)
+        .ok_or_else(|| Matrix65Error::Invalid("invalid selection".to_string()))?;
+    cbm_load_file(disk.as_ref(), *dir_index)
 }
 
 /// Load a prg file or url into a byte vector and detect load address
@@ -156,20 +749,466 @@ pub fn save_binary(filename: &str, bytes: &[u8]) -> Result<(), std::io::Error> {
     File::create(filename)?.write_all(bytes)
 }
 
-/// Print bytes to screen
-pub fn hexdump(bytes: &[u8], bytes_per_line: usize) {
-    let to_hex = |i: u8| format!("0x{:02x}", i);
-    bytes.chunks(bytes_per_line).for_each(|line| {
+/// Ask a yes/no question on the terminal; defaults to no
+pub fn confirm(question: &str) -> Result<bool> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Copy file to `<filename>.bak` and return the name of the backup
+pub fn backup_file(filename: &str) -> Result<String> {
+    let backup = format!("{}.bak", filename);
+    std::fs::copy(filename, &backup)?;
+    debug!("Backed up {} to {}", filename, backup);
+    Ok(backup)
+}
+
+/// Safety checks before modifying an existing CBM disk image
+///
+/// Unless `force` is set, the user is asked for confirmation when
+/// running interactively and a `.bak` copy of the image is made.
+/// Disk image writers should call this before touching the file.
+pub fn prepare_disk_write(diskimage: &str, force: bool) -> Result<()> {
+    if force || !std::path::Path::new(diskimage).exists() {
+        return Ok(());
+    }
+    if io::stdin().is_tty() && !confirm(&format!("Modify disk image {}?", diskimage))? {
+        return Err(Matrix65Error::Invalid(
+            "disk image left unchanged".to_string(),
+        ));
+    }
+    backup_file(diskimage)?;
+    Ok(())
+}
+
+/// Write hexdump of bytes with addresses on the left and characters on the right
+///
+/// `address` is the address of the first byte. Printable characters are
+/// shown in the right gutter and all other bytes as dots.
+///
+/// Example:
+/// ~~~
+/// let mut out = Vec::new();
+/// matrix65::io::hexdump(&[0x48, 0x49, 0x00], 0xc000, 4, &mut out).unwrap();
+/// assert_eq!(
+///     String::from_utf8(out).unwrap(),
+///     "000c000: 0x48 0x49 0x00      |HI. |\n"
+/// );
+/// ~~~
+pub fn hexdump(
+    bytes: &[u8],
+    address: u32,
+    bytes_per_line: usize,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    for (i, line) in bytes.chunks(bytes_per_line).enumerate() {
+        write!(out, "{:07x}: ", address as usize + i * bytes_per_line)?;
         for byte in line {
-            print!("{} ", to_hex(*byte));
+            write!(out, "0x{:02x} ", byte)?;
         }
-        println!();
-    });
+        write_gutter(line, bytes_per_line, out)?;
+    }
+    Ok(())
+}
+
+/// Pad a short hexdump line and write the character gutter
+fn write_gutter(line: &[u8], bytes_per_line: usize, out: &mut dyn Write) -> io::Result<()> {
+    let padding = "     ".repeat(bytes_per_line - line.len());
+    let text: String = line.iter().map(|byte| printable_char(*byte)).collect();
+    let text_padding = " ".repeat(bytes_per_line - line.len());
+    writeln!(out, "{}|{}{}|", padding, text, text_padding)
+}
+
+/// Character shown for a byte in the hexdump gutter
+///
+/// Bytes in the printable range common to PETSCII and ASCII are shown
+/// as is; everything else as a dot.
+const fn printable_char(byte: u8) -> char {
+    match byte {
+        0x20..=0x7e => byte as char,
+        _ => '.',
+    }
+}
+
+/// Convert PETSCII in the lower/upper case character set to ASCII
+///
+/// Unshifted letters become lower case and shifted letters upper case.
+/// Return becomes a newline, control codes are dropped, and graphics
+/// characters without an ASCII counterpart become `?`.
+///
+/// Example:
+/// ~~~
+/// use matrix65::io::petscii_to_ascii;
+/// assert_eq!(petscii_to_ascii(&[0xc8, 0x45, 0x4c, 0x4c, 0x4f, 0x21, 0x0d]), "Hello!\n");
+/// assert_eq!(petscii_to_ascii(&[0x93, 0x5f, 0xa0, 0x61]), "_ A");
+/// ~~~
+pub fn petscii_to_ascii(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .filter_map(|byte| match byte {
+            0x0d => Some('\n'),
+            0x20..=0x40 | 0x5b | 0x5d => Some(*byte as char),
+            0x41..=0x5a => Some(byte.to_ascii_lowercase() as char),
+            0x61..=0x7a => Some((byte - 0x20) as char),
+            0xc1..=0xda => Some((byte - 0x80) as char),
+            0x5e => Some('^'),
+            0x5f | 0xa4 => Some('_'),
+            0xa0 => Some(' '),
+            0x00..=0x1f | 0x80..=0x9f => None,
+            _ => Some('?'),
+        })
+        .collect()
 }
-/// Print disassembled bytes
-pub fn disassemble(bytes: &[u8], start_address: u32) {
-    let instructions = disasm6502::from_addr_array(bytes, start_address as u16).unwrap();
-    for i in instructions {
-        println!("{}", i);
+
+/// Convert ASCII to PETSCII in the lower/upper case character set
+///
+/// The inverse of `petscii_to_ascii()`: lower case letters become unshifted
+/// and upper case letters shifted letters, newlines become return, and
+/// characters without a PETSCII counterpart become `?`.
+///
+/// Example:
+/// ~~~
+/// use matrix65::io::{ascii_to_petscii, petscii_to_ascii};
+/// assert_eq!(ascii_to_petscii("Hi!\n"), [0xc8, 0x49, 0x21, 0x0d]);
+/// assert_eq!(petscii_to_ascii(&ascii_to_petscii("Load \"*\",8")), "Load \"*\",8");
+/// assert_eq!(ascii_to_petscii("{~}"), [0x3f, 0x3f, 0x3f]);
+/// ~~~
+pub fn ascii_to_petscii(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| match c {
+            '\n' | '\r' => 0x0d,
+            'a'..='z' => c.to_ascii_uppercase() as u8,
+            'A'..='Z' => c as u8 + 0x80,
+            ' '..='@' | '[' | ']' | '^' => c as u8,
+            '_' => 0xa4,
+            _ => b'?',
+        })
+        .collect()
+}
+
+/// Line number step used when numbering BASIC lines automatically
+const BASIC_LINE_STEP: u32 = 10;
+
+/// Prepare BASIC source text for typing, one entry per program line
+///
+/// Blank lines are skipped. Letters outside string literals are made
+/// lower case, which is typed unshifted and hence read as BASIC keywords.
+/// With `auto_number`, lines without a line number get one that follows
+/// the previous line in steps of ten.
+///
+/// Example:
+/// ~~~
+/// use matrix65::io::basic_lines;
+/// let source = "PRINT \"Hi\"\n\n100 GOTO 10\nEND\n";
+/// assert_eq!(
+///     basic_lines(source, true),
+///     ["10 print \"Hi\"", "100 goto 10", "110 end"]
+/// );
+/// assert_eq!(basic_lines(source, false)[0], "print \"Hi\"");
+/// ~~~
+pub fn basic_lines(source: &str, auto_number: bool) -> Vec<String> {
+    let mut previous = 0;
+    source
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let digits: String = line.chars().take_while(char::is_ascii_digit).collect();
+            let line = match digits.parse::<u32>() {
+                Ok(number) => {
+                    previous = number;
+                    line.to_string()
+                }
+                Err(_) if auto_number => {
+                    previous += BASIC_LINE_STEP;
+                    format!("{} {}", previous, line)
+                }
+                Err(_) => line.to_string(),
+            };
+            let mut in_string = false;
+            line.chars()
+                .map(|c| {
+                    if c == '"' {
+                        in_string = !in_string;
+                    }
+                    match in_string {
+                        true => c,
+                        false => c.to_ascii_lowercase(),
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Kind of byte as seen in a PETSCII hexdump
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteClass {
+    Zero,
+    /// PETSCII control codes ($01-$1f and $80-$9f)
+    Control,
+    /// Printable PETSCII in the lower half ($20-$7f)
+    Printable,
+    /// Printable graphics characters in the upper half ($a0-$ff)
+    Graphics,
+}
+
+impl ByteClass {
+    /// Classify byte according to PETSCII
+    ///
+    /// Examples:
+    /// ~~~
+    /// use matrix65::io::ByteClass;
+    /// assert_eq!(ByteClass::from(0x00), ByteClass::Zero);
+    /// assert_eq!(ByteClass::from(0x0d), ByteClass::Control);
+    /// assert_eq!(ByteClass::from(0x41), ByteClass::Printable);
+    /// assert_eq!(ByteClass::from(0x93), ByteClass::Control);
+    /// assert_eq!(ByteClass::from(0xa0), ByteClass::Graphics);
+    /// ~~~
+    pub const fn from(byte: u8) -> ByteClass {
+        match byte {
+            0x00 => ByteClass::Zero,
+            0x01..=0x1f | 0x80..=0x9f => ByteClass::Control,
+            0x20..=0x7f => ByteClass::Printable,
+            0xa0..=0xff => ByteClass::Graphics,
+        }
+    }
+}
+
+/// Write hexdump like `hexdump()` with ANSI colours according to `ByteClass`
+///
+/// Zero bytes are dimmed so that patterns in the remaining data stand out.
+pub fn hexdump_colored(
+    bytes: &[u8],
+    address: u32,
+    bytes_per_line: usize,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    use crossterm::style::{Color, Stylize};
+    for (i, line) in bytes.chunks(bytes_per_line).enumerate() {
+        write!(out, "{:07x}: ", address as usize + i * bytes_per_line)?;
+        for byte in line {
+            let hex = format!("0x{:02x}", byte);
+            match ByteClass::from(*byte) {
+                ByteClass::Zero => write!(out, "{} ", hex.dim())?,
+                ByteClass::Control => write!(out, "{} ", hex.with(Color::Yellow))?,
+                ByteClass::Printable => write!(out, "{} ", hex.with(Color::Green))?,
+                ByteClass::Graphics => write!(out, "{} ", hex.with(Color::Cyan))?,
+            }
+        }
+        write_gutter(line, bytes_per_line, out)?;
+    }
+    Ok(())
+}
+
+/// Single disassembled instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisasmLine {
+    /// Address of the instruction
+    pub address: u16,
+    /// Opcode and operand bytes
+    pub bytes: Vec<u8>,
+    /// Instruction as formatted by the disassembler
+    pub text: String,
+}
+
+impl fmt::Display for DisasmLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+/// Disassemble bytes starting at `start_address` (currently only 6502)
+pub fn disassemble(bytes: &[u8], start_address: u32) -> Result<Vec<DisasmLine>> {
+    let instructions = disasm6502::from_addr_array(bytes, start_address as u16)
+        .map_err(|err| Matrix65Error::Parse(format!("cannot disassemble: {}", err)))?;
+    Ok(instructions
+        .iter()
+        .map(|instruction| {
+            let offset = instruction.address.wrapping_sub(start_address as u16) as usize;
+            let end = match bytes.get(offset) {
+                Some(opcode) => (offset + instruction_length(*opcode)).min(bytes.len()),
+                None => offset,
+            };
+            DisasmLine {
+                address: instruction.address,
+                bytes: bytes.get(offset..end).unwrap_or_default().to_vec(),
+                text: instruction.to_string(),
+            }
+        })
+        .collect())
+}
+
+/// Pixels per character in each direction
+pub const CHAR_SIZE: usize = 8;
+
+/// Render text mode screen codes and colour RAM to RGBA pixels
+///
+/// Each screen code picks an 8x8 glyph from `charset`, drawn in the colour
+/// from the lower nibble of the matching colour RAM byte on the `background`
+/// colour. Multicolour and extended background modes are not decoded.
+/// The image is `columns * 8` pixels wide.
+///
+/// Example:
+/// ~~~
+/// use matrix65::io::text_to_rgba;
+/// let mut charset = [0u8; 2048];
+/// charset[8] = 0x80; // glyph 1 has its top left pixel set
+/// let palette = [[0, 0, 0], [255, 255, 255]];
+/// let pixels = text_to_rgba(&[1, 0], &[1, 1], &charset, 2, &palette, 0);
+/// assert_eq!(pixels.len(), 16 * 8 * 4);
+/// assert_eq!(pixels[..8], [255, 255, 255, 255, 0, 0, 0, 255]);
+/// ~~~
+pub fn text_to_rgba(
+    screen: &[u8],
+    colors: &[u8],
+    charset: &[u8],
+    columns: usize,
+    palette: &[[u8; 3]],
+    background: u8,
+) -> Vec<u8> {
+    let width = columns * CHAR_SIZE;
+    let rows = screen.len() / columns;
+    let rgba = |index: u8| {
+        let [red, green, blue] = palette[index as usize % palette.len()];
+        [red, green, blue, 255]
+    };
+    let mut pixels = vec![0u8; width * rows * CHAR_SIZE * 4];
+    for (i, code) in screen.iter().enumerate() {
+        let foreground = rgba(colors.get(i).unwrap_or(&0) & 0x0f);
+        let (column, row) = (i % columns, i / columns);
+        for y in 0..CHAR_SIZE {
+            let bits = charset.get(*code as usize * CHAR_SIZE + y).unwrap_or(&0);
+            for x in 0..CHAR_SIZE {
+                let color = match bits & (0x80 >> x) {
+                    0 => rgba(background),
+                    _ => foreground,
+                };
+                let offset = ((row * CHAR_SIZE + y) * width + column * CHAR_SIZE + x) * 4;
+                pixels[offset..offset + 4].copy_from_slice(&color);
+            }
+        }
+    }
+    pixels
+}
+
+/// Number of bytes fetched at a time when following code
+const FOLLOW_READ_SIZE: usize = 16;
+
+/// Length in bytes of a 6502 instruction, including illegal opcodes
+///
+/// Examples:
+/// ~~~
+/// use matrix65::io::instruction_length;
+/// assert_eq!(instruction_length(0xea), 1); // NOP
+/// assert_eq!(instruction_length(0xa9), 2); // LDA #imm
+/// assert_eq!(instruction_length(0xd0), 2); // BNE
+/// assert_eq!(instruction_length(0x20), 3); // JSR
+/// assert_eq!(instruction_length(0x8d), 3); // STA abs
+/// assert_eq!(instruction_length(0xb9), 3); // LDA abs,y
+/// ~~~
+pub const fn instruction_length(opcode: u8) -> usize {
+    match (opcode & 0x1f, opcode) {
+        (_, 0x20) => 3,
+        (0x00 | 0x02, opcode) if opcode >= 0x80 => 2,
+        (0x00 | 0x02 | 0x08 | 0x0a | 0x12 | 0x18 | 0x1a, _) => 1,
+        (0x0c..=0x0f | 0x19 | 0x1b..=0x1f, _) => 3,
+        _ => 2,
+    }
+}
+
+/// Follow 6502 code flow from `start`, returning each visited instruction
+///
+/// Unconditional jumps are followed, subroutines (JSR) are entered
+/// and then returned from, and both outcomes of conditional branches
+/// are visited. A path ends at RTS, RTI, BRK, indirect JMP, a
+/// previously visited instruction, or outside the optional `bounds`.
+/// At most `max_instructions` instructions are returned, each as
+/// address and raw bytes in the order visited. Memory is fetched
+/// as needed via `read(address, length)`.
+///
+/// Example:
+/// ~~~
+/// use matrix65::io::follow_code;
+/// // $c000: LDX #$00; $c002: DEX; BNE $c002; JSR $c00a; RTS; $c00a: RTS
+/// let memory = [0xa2, 0x00, 0xca, 0xd0, 0xfd, 0x20, 0x0a, 0xc0, 0x60, 0x00, 0x60];
+/// let mut read = |address: u16, length: usize| {
+///     let start = (address - 0xc000) as usize;
+///     Ok(memory[start..(start + length).min(memory.len())].to_vec())
+/// };
+/// let listing = follow_code(0xc000, 100, None, &mut read).unwrap();
+/// let addresses: Vec<u16> = listing.iter().map(|(address, _)| *address).collect();
+/// assert_eq!(addresses, [0xc000, 0xc002, 0xc003, 0xc005, 0xc00a, 0xc008]);
+/// assert_eq!(listing[3].1, [0x20, 0x0a, 0xc0]);
+/// ~~~
+pub fn follow_code(
+    start: u16,
+    max_instructions: usize,
+    bounds: Option<(u16, u16)>,
+    read: &mut dyn FnMut(u16, usize) -> Result<Vec<u8>>,
+) -> Result<Vec<(u16, Vec<u8>)>> {
+    let mut blocks: HashMap<u16, Vec<u8>> = HashMap::new();
+    let mut fetch = |address: u16, length: usize| -> Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(length);
+        for address in (0..length).map(|i| address.wrapping_add(i as u16)) {
+            let block_start = address - address % FOLLOW_READ_SIZE as u16;
+            let block = match blocks.entry(block_start) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(read(block_start, FOLLOW_READ_SIZE)?),
+            };
+            let byte = block
+                .get((address - block_start) as usize)
+                .copied()
+                .ok_or_else(|| {
+                    Matrix65Error::Invalid(format!("cannot read memory at 0x{:04x}", address))
+                })?;
+            bytes.push(byte);
+        }
+        Ok(bytes)
+    };
+    let in_bounds = |address: u16| match bounds {
+        Some((low, high)) => (low..=high).contains(&address),
+        None => true,
+    };
+
+    let mut listing = Vec::new();
+    let mut visited = HashSet::new();
+    let mut pending = vec![start];
+    while let Some(mut address) = pending.pop() {
+        while listing.len() < max_instructions && in_bounds(address) && visited.insert(address) {
+            let opcode = fetch(address, 1)?[0];
+            let bytes = fetch(address, instruction_length(opcode))?;
+            let next = address.wrapping_add(bytes.len() as u16);
+            let target = match bytes.len() {
+                3 => u16::from_le_bytes([bytes[1], bytes[2]]),
+                2 => next.wrapping_add(bytes[1] as i8 as u16),
+                _ => next,
+            };
+            listing.push((address, bytes));
+            match opcode {
+                // JMP absolute
+                0x4c => address = target,
+                // JSR; continue after return once the subroutine is done
+                0x20 => {
+                    pending.push(next);
+                    address = target;
+                }
+                // conditional branches; visit target once fall-through is done
+                0x10 | 0x30 | 0x50 | 0x70 | 0x90 | 0xb0 | 0xd0 | 0xf0 => {
+                    pending.push(target);
+                    address = next;
+                }
+                // BRK, RTI, RTS, JMP indirect, and JAM
+                0x00 | 0x40 | 0x60 | 0x6c => break,
+                opcode if opcode & 0x1f == 0x12 || (opcode & 0x1f == 0x02 && opcode < 0x80) => {
+                    break
+                }
+                _ => address = next,
+            }
+        }
     }
+    Ok(listing)
 }
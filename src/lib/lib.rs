@@ -19,13 +19,35 @@
 //! It is the basis for the CLI tool `matrix65` which is included in
 //! this crate.
 
+pub mod cache;
+pub mod cpu;
+pub mod crt;
+pub mod dryrun;
 pub mod filehost;
+pub mod info;
 pub mod io;
+pub mod monitor;
 pub mod serial;
+pub mod sid;
+#[cfg(feature = "testing")]
+pub mod test_support;
+pub mod video;
+pub mod xemu;
 
-use anyhow::Result;
 use std::convert::From;
 use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Number of bytes per write when reporting progress
+const WRITE_CHUNK_SIZE: usize = 4096;
+
+/// Number of bytes per read when reporting progress (one page dump)
+const READ_CHUNK_SIZE: usize = 256;
 
 /// Load address for Commodore PRG files
 #[allow(dead_code)]
@@ -40,6 +62,11 @@ pub enum LoadAddress {
     /// Shared for Commodore 65 and MEGA65
     Commodore65,
     Custom(u16),
+    /// 28-bit address above the first 64 KB, e.g. in banked RAM
+    ///
+    /// PRG headers only hold 16-bit addresses, so this is never read
+    /// from a file; see `LoadAddress::at()`.
+    Banked(u32),
 }
 
 #[allow(dead_code)]
@@ -67,6 +94,22 @@ impl LoadAddress {
         }
     }
 
+    /// Construct LoadAddress from a 28-bit address, using `Banked` above 0xffff
+    ///
+    /// Examples:
+    /// ~~~
+    /// use matrix65::LoadAddress;
+    /// assert_eq!(LoadAddress::at(0x0801), LoadAddress::Commodore64);
+    /// assert_eq!(LoadAddress::at(0x40000), LoadAddress::Banked(0x40000));
+    /// assert_eq!(LoadAddress::at(0x40000).address(), 0x40000);
+    /// ~~~
+    pub const fn at(address: u32) -> LoadAddress {
+        match address {
+            0..=0xffff => LoadAddress::new(address as u16),
+            _ => LoadAddress::Banked(address),
+        }
+    }
+
     /// Extract load address from first two bytes, little endian.
     ///
     /// Examples:
@@ -78,10 +121,13 @@ impl LoadAddress {
     /// assert_eq!(load_address.value(), 0x0801);
     /// ~~~
     pub fn from_bytes(bytes: &[u8]) -> Result<LoadAddress> {
-        let address = u16::from_le_bytes(bytes[0..2].try_into()?);
+        let address = bytes
+            .get(0..2)
+            .ok_or_else(|| Matrix65Error::Parse("too few bytes for a load address".to_string()))?;
+        let address = u16::from_le_bytes(address.try_into()?);
         Ok(Self::new(address))
     }
-    /// Returns the 16-bit load address
+    /// Returns the 16-bit load address; for `Banked`, the offset within its 64 KB bank
     ///
     /// Examples:
     /// ~~~
@@ -101,6 +147,15 @@ impl LoadAddress {
             LoadAddress::Commodore128 => 0x1c01,
             LoadAddress::Commodore65 => 0x2001,
             LoadAddress::Custom(address) => address,
+            LoadAddress::Banked(address) => address as u16,
+        }
+    }
+
+    /// Returns the full 28-bit load address
+    pub const fn address(&self) -> u32 {
+        match *self {
+            LoadAddress::Banked(address) => address,
+            _ => self.value() as u32,
         }
     }
 }
@@ -127,7 +182,7 @@ impl From<LoadAddress> for u16 {
 /// assert_eq!(LoadAddress::from(0x1c01), LoadAddress::Commodore128);
 /// assert_eq!(LoadAddress::from(0x2001), LoadAddress::Commodore65);
 /// assert_eq!(LoadAddress::from(0xc000), LoadAddress::Custom(0xc000));
-/// 
+///
 /// let address: LoadAddress = 0x0801.into();
 /// assert_eq!(address, LoadAddress::Commodore64);
 /// ~~~
@@ -139,6 +194,807 @@ impl From<u16> for LoadAddress {
 
 impl fmt::Display for LoadAddress {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "0x{:x}", self.value())
+        write!(f, "0x{:x}", self.address())
+    }
+}
+
+/// Parse load address from a machine name or a 16-bit address
+///
+/// Examples:
+/// ~~~
+/// use matrix65::LoadAddress;
+/// assert_eq!("c64".parse::<LoadAddress>().unwrap(), LoadAddress::Commodore64);
+/// assert_eq!("MEGA65".parse::<LoadAddress>().unwrap(), LoadAddress::Commodore65);
+/// assert_eq!("$0801".parse::<LoadAddress>().unwrap(), LoadAddress::Commodore64);
+/// assert_eq!("0xc000".parse::<LoadAddress>().unwrap(), LoadAddress::Custom(0xc000));
+/// assert!("0x12345".parse::<LoadAddress>().is_err());
+/// ~~~
+impl FromStr for LoadAddress {
+    type Err = Error;
+    fn from_str(text: &str) -> Result<Self> {
+        let load_address = match text.trim().to_lowercase().as_str() {
+            "pet" => LoadAddress::PET,
+            "c64" | "x16" => LoadAddress::Commodore64,
+            "c16" | "vic20" | "plus4" => LoadAddress::Commodore16,
+            "c128" => LoadAddress::Commodore128,
+            "c65" | "mega65" => LoadAddress::Commodore65,
+            _ => LoadAddress::new(u16::try_from(parse_address(text)?)?),
+        };
+        Ok(load_address)
+    }
+}
+
+/// Named memory regions accepted in place of numeric addresses
+pub const NAMED_ADDRESSES: [(&str, u32); 10] = [
+    ("zeropage", 0x0000),
+    ("screen", 0x0800),
+    ("basic", 0x2001),
+    ("vic", 0xd000),
+    ("sid", 0xd400),
+    ("cia1", 0xdc00),
+    ("cia2", 0xdd00),
+    ("kernal", 0xe000),
+    ("colour", 0xff80000),
+    ("color", 0xff80000),
+];
+
+/// Parse address given as hex (`0x` or `$`), binary (`0b`), decimal, or region name
+///
+/// Examples:
+/// ~~~
+/// use matrix65::parse_address;
+/// assert_eq!(parse_address("0xd020").unwrap(), 0xd020);
+/// assert_eq!(parse_address("$D020").unwrap(), 0xd020);
+/// assert_eq!(parse_address("53280").unwrap(), 0xd020);
+/// assert_eq!(parse_address("0b1000").unwrap(), 8);
+/// assert_eq!(parse_address("colour").unwrap(), 0xff80000);
+/// assert_eq!(parse_address("SID").unwrap(), 0xd400);
+/// assert!(parse_address("$").is_err());
+/// assert!(parse_address("nowhere").is_err());
+/// ~~~
+pub fn parse_address(text: &str) -> Result<u32> {
+    let text = text.trim();
+    if let Some((_, address)) = NAMED_ADDRESSES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(text))
+    {
+        return Ok(*address);
+    }
+    let address = match text.strip_prefix('$') {
+        Some(hex) => u32::from_str_radix(&hex.replace('_', ""), 16),
+        None => parse_int::parse::<u32>(&text.to_lowercase()),
+    };
+    address.map_err(|_| Matrix65Error::Parse(format!("invalid address '{}'", text)))
+}
+
+/// Parse hex bytes separated by spaces or commas, e.g. "a9 00 8d 20 d0"
+///
+/// Each group may hold several bytes and have a `0x` or `$` prefix.
+///
+/// Examples:
+/// ~~~
+/// use matrix65::parse_hex_bytes;
+/// assert_eq!(parse_hex_bytes("a9 00,8D 20 d0").unwrap(), [0xa9, 0x00, 0x8d, 0x20, 0xd0]);
+/// assert_eq!(parse_hex_bytes("$ea 0x6000").unwrap(), [0xea, 0x60, 0x00]);
+/// assert!(parse_hex_bytes("a9 0").is_err());
+/// assert!(parse_hex_bytes("zz").is_err());
+/// assert!(parse_hex_bytes(" ").is_err());
+/// ~~~
+pub fn parse_hex_bytes(text: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for group in text.split([' ', ',']).filter(|group| !group.is_empty()) {
+        let hex = group
+            .strip_prefix("0x")
+            .or_else(|| group.strip_prefix('$'))
+            .unwrap_or(group);
+        let mut decoded = hex::decode(hex).map_err(|_| {
+            Matrix65Error::Parse(format!(
+                "invalid hex byte(s) '{}'; use two hex digits per byte, e.g. \"a9 00\"",
+                group
+            ))
+        })?;
+        bytes.append(&mut decoded);
+    }
+    if bytes.is_empty() {
+        return Err(Matrix65Error::Parse("no bytes given".to_string()));
+    }
+    Ok(bytes)
+}
+
+/// Regions where stray writes easily crash a running program
+pub const CRITICAL_REGIONS: [(&str, u32, u32); 4] = [
+    ("zero page", 0x0000, 0x00ff),
+    ("stack", 0x0100, 0x01ff),
+    ("I/O", 0xd000, 0xdfff),
+    ("I/O", 0xffd0000, 0xffdffff),
+];
+
+/// Name of the first critical region overlapped by writing `length` bytes at `address`
+///
+/// Examples:
+/// ~~~
+/// use matrix65::critical_region;
+/// assert_eq!(critical_region(0x00fb, 1), Some("zero page"));
+/// assert_eq!(critical_region(0x01f0, 32), Some("stack"));
+/// assert_eq!(critical_region(0xcff0, 32), Some("I/O"));
+/// assert_eq!(critical_region(0x0200, 256), None);
+/// assert_eq!(critical_region(0x1000, 0), None);
+/// ~~~
+pub fn critical_region(address: u32, length: usize) -> Option<&'static str> {
+    let last = address.checked_add(length.checked_sub(1)? as u32)?;
+    CRITICAL_REGIONS
+        .iter()
+        .find(|(_, start, end)| address <= *end && last >= *start)
+        .map(|(name, _, _)| *name)
+}
+
+/// Errors reported by the MEGA65 and by the library
+///
+/// Library functions return `matrix65::Result`, so match on the variants,
+/// e.g. to handle an unsupported load address differently from a serial timeout:
+/// ~~~
+/// use matrix65::{parse_address, Matrix65Error};
+/// let err = parse_address("nowhere").unwrap_err();
+/// assert!(matches!(err, Matrix65Error::Parse(_)));
+/// assert_eq!(err.to_string(), "invalid address 'nowhere'");
+/// ~~~
+#[derive(Debug, thiserror::Error)]
+pub enum Matrix65Error {
+    /// The monitor rejected a command; contains the raw reply
+    #[error("monitor error: {0}")]
+    MonitorError(String),
+    /// The serial port or network connection cannot be opened, or the MEGA65 gave no valid reply in time
+    #[error("{0}")]
+    Serial(String),
+    /// Program for another Commodore machine, which cannot run on the MEGA65
+    #[error(
+        "{machine} programs (load address 0x{address:04x}) are not runnable on MEGA65; \
+         load without --run to inspect them"
+    )]
+    LoadAddressUnsupported {
+        address: u16,
+        /// Name of the machine(s) using this load address
+        machine: &'static str,
+    },
+    /// CBM disk image or T64 tape archive that cannot be opened or read
+    #[error("{0}")]
+    CbmDisk(String),
+    /// Download failed or the server replied with an error
+    #[error("cannot download {url}: {reason}")]
+    Http { url: String, reason: String },
+    /// Invalid address, value, or reply text
+    #[error("{0}")]
+    Parse(String),
+    /// Memory read back after writing differs from what was written
+    #[error(
+        "verification failed: {count} byte(s) differ, first at 0x{address:07x} \
+         (wrote 0x{expected:02x}, read 0x{found:02x})"
+    )]
+    VerifyMismatch {
+        /// First differing address
+        address: u32,
+        expected: u8,
+        found: u8,
+        /// Total number of differing bytes
+        count: usize,
+    },
+    /// Characters that have no key on the keyboard and were not typed
+    #[error("cannot type unsupported character(s): {}", format_keys(.0))]
+    UnsupportedKeys(Vec<char>),
+    /// The operation is not available over this connection
+    #[error("{0}")]
+    Unsupported(&'static str),
+    /// Request that cannot be carried out, e.g. an invalid selection or an address out of range
+    #[error("{0}")]
+    Invalid(String),
+    /// The operation was interrupted with a `CancelToken`
+    #[error("operation cancelled")]
+    Cancelled,
+    /// Reading or writing a file failed
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Error type of the library
+pub type Error = Matrix65Error;
+
+/// Result type of the library
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Comma separated, quoted list of characters
+fn format_keys(keys: &[char]) -> String {
+    let keys: Vec<String> = keys.iter().map(|key| format!("{:?}", key)).collect();
+    keys.join(", ")
+}
+
+impl From<serialport::Error> for Matrix65Error {
+    fn from(err: serialport::Error) -> Self {
+        Matrix65Error::Serial(err.to_string())
+    }
+}
+
+impl From<reqwest::Error> for Matrix65Error {
+    fn from(err: reqwest::Error) -> Self {
+        let url = err.url().map(|url| url.to_string()).unwrap_or_default();
+        Matrix65Error::Http {
+            url,
+            reason: err.to_string(),
+        }
+    }
+}
+
+impl From<hex::FromHexError> for Matrix65Error {
+    fn from(err: hex::FromHexError) -> Self {
+        Matrix65Error::Parse(err.to_string())
+    }
+}
+
+impl From<std::num::ParseIntError> for Matrix65Error {
+    fn from(err: std::num::ParseIntError) -> Self {
+        Matrix65Error::Parse(err.to_string())
+    }
+}
+
+impl From<std::num::TryFromIntError> for Matrix65Error {
+    fn from(err: std::num::TryFromIntError) -> Self {
+        Matrix65Error::Parse(err.to_string())
+    }
+}
+
+impl From<std::array::TryFromSliceError> for Matrix65Error {
+    fn from(err: std::array::TryFromSliceError) -> Self {
+        Matrix65Error::Parse(err.to_string())
+    }
+}
+
+impl From<std::net::AddrParseError> for Matrix65Error {
+    fn from(err: std::net::AddrParseError) -> Self {
+        Matrix65Error::Parse(err.to_string())
+    }
+}
+
+impl From<image::ImageError> for Matrix65Error {
+    fn from(err: image::ImageError) -> Self {
+        Matrix65Error::Parse(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Matrix65Error {
+    fn from(err: serde_json::Error) -> Self {
+        Matrix65Error::Parse(err.to_string())
+    }
+}
+
+/// Default delay between key presses when typing text
+pub const DEFAULT_KEY_DELAY: Duration = Duration::from_millis(20);
+
+/// MEGA65 colour RAM in the 28-bit address space (up to 32 KB)
+///
+/// Colour RAM is found here in every mode; programs see the first 1 KB
+/// through the I/O area at $D800 (physical $FFD3800), and C65 mode
+/// programs the rest through the MAP. Only the lower four bits are
+/// colours, as on the C64.
+pub const COLOR_RAM: u32 = 0xff80000;
+
+/// Size of the full colour RAM used in C65 mode
+const COLOR_RAM_SIZE_C65: usize = 0x8000;
+
+/// Size of the colour RAM seen through the I/O area in C64 mode
+const COLOR_RAM_SIZE_C64: usize = 0x400;
+
+/// Number of colour RAM bytes in use in C64 or C65 mode
+///
+/// Examples:
+/// ~~~
+/// use matrix65::color_ram_size;
+/// assert_eq!(color_ram_size(false), 1024);
+/// assert_eq!(color_ram_size(true), 32768);
+/// ~~~
+pub const fn color_ram_size(c65_mode: bool) -> usize {
+    match c65_mode {
+        true => COLOR_RAM_SIZE_C65,
+        false => COLOR_RAM_SIZE_C64,
+    }
+}
+
+/// Fail unless `length` bytes from `offset` are within the colour RAM in use
+fn check_color_ram_range(c65_mode: bool, offset: usize, length: usize) -> Result<()> {
+    let size = color_ram_size(c65_mode);
+    match offset + length > size {
+        true => Err(Matrix65Error::Invalid(format!(
+            "colour RAM access is limited to the first {} bytes in this mode",
+            size
+        ))),
+        false => Ok(()),
+    }
+}
+
+/// Shared flag to request cancellation of ongoing operations
+///
+/// Clones refer to the same flag, so one can be handed to a
+/// communicator and another kept by e.g. a user interface.
+///
+/// Example:
+/// ~~~
+/// use matrix65::CancelToken;
+/// let token = CancelToken::default();
+/// let handle = token.clone();
+/// handle.cancel();
+/// assert!(token.is_cancelled());
+/// token.reset();
+/// assert!(!handle.is_cancelled());
+/// ~~~
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Request cancellation
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Clear any previous cancellation request
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+
+    /// Check if cancellation has been requested
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Communication interface to the MEGA65
+///
+/// This should be implemented by different transfer protocols,
+/// e.g. serial and Xemu. Higher level operations like mode
+/// switching and PRG handling are provided as default methods.
+/// Communicators must be `Send` so that blocking operations can be
+/// moved to a worker thread.
+pub trait M65Communicator: Send {
+    /// Reset the MEGA65
+    fn reset(&mut self) -> Result<()>;
+
+    /// Warm reset by pressing RUN/STOP + RESTORE
+    ///
+    /// Unlike `reset`, which cold starts the machine and takes several
+    /// seconds, this triggers the KERNAL's NMI handler: a hung program is
+    /// interrupted and the screen and I/O chips are reinitialised, but RAM,
+    /// including a BASIC program, is left intact. It has no effect if the
+    /// program has disabled or redirected the NMI.
+    fn warm_reset(&mut self) -> Result<()> {
+        Err(Matrix65Error::Unsupported(
+            "warm reset is not supported by this connection",
+        ))
+    }
+
+    /// Try to empty the monitor of any pending output; returns the number of discarded bytes
+    fn flush(&mut self) -> Result<usize>;
+
+    /// Stop the MEGA65 CPU
+    fn stop_cpu(&mut self) -> Result<()>;
+
+    /// Start the MEGA65 CPU after being halted
+    fn start_cpu(&mut self) -> Result<()>;
+
+    /// Send array of key presses
+    fn type_text(&mut self, text: &str) -> Result<()>;
+
+    /// Stream memory from MEGA65 starting at given 28-bit address to `out`
+    ///
+    /// Bytes are written as they are decoded, so large regions can be
+    /// dumped without holding them in memory.
+    fn read_memory_to_writer(
+        &mut self,
+        address: u32,
+        length: usize,
+        out: &mut dyn Write,
+    ) -> Result<()>;
+
+    /// Load memory from MEGA65 starting at given 28-bit address
+    fn read_memory(&mut self, address: u32, length: usize) -> Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(length);
+        self.read_memory_to_writer(address, length, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Write bytes to MEGA65 anywhere in the 28-bit address space
+    ///
+    /// This reaches e.g. banked RAM, the I/O registers at $FFD3000, and
+    /// attic RAM.
+    fn write_memory_far(&mut self, address: u32, bytes: &[u8]) -> Result<()>;
+
+    /// Write bytes to MEGA65 at a 16-bit address
+    fn write_memory(&mut self, address: u16, bytes: &[u8]) -> Result<()> {
+        self.write_memory_far(address as u32, bytes)
+    }
+
+    /// Set the delay between key presses when typing, e.g. for slow keyboard scans
+    ///
+    /// The default implementation ignores the delay.
+    fn set_key_delay(&mut self, _delay: Duration) {}
+
+    /// Read memory with page dumps (`M`, 256 bytes per request) or line
+    /// dumps (`m`, 16 bytes per request), e.g. to compare their speed
+    ///
+    /// Fails if the chosen dump is not available. The default
+    /// implementation reports that this is unsupported.
+    fn set_page_dump(&mut self, _enabled: bool) -> Result<()> {
+        Err(Matrix65Error::Unsupported(
+            "choosing the memory dump command is not supported by this connection",
+        ))
+    }
+
+    /// Make ongoing and future operations fail early once `token` is cancelled
+    ///
+    /// The default implementation ignores the token.
+    fn set_cancel_token(&mut self, _token: CancelToken) {}
+
+    /// Write bytes to MEGA65 in chunks, reporting progress
+    ///
+    /// After each chunk, `progress` is called with the number of
+    /// bytes written so far and the total number of bytes.
+    fn write_memory_with_progress(
+        &mut self,
+        address: u32,
+        bytes: &[u8],
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<()> {
+        let mut written = 0;
+        for chunk in bytes.chunks(WRITE_CHUNK_SIZE) {
+            self.write_memory_far(address + written as u32, chunk)?;
+            written += chunk.len();
+            progress(written, bytes.len());
+        }
+        Ok(())
+    }
+
+    /// Help text of the monitor, which includes version information
+    ///
+    /// The default implementation reports that this is unsupported.
+    fn monitor_help(&mut self) -> Result<String> {
+        Err(Matrix65Error::Unsupported(
+            "monitor help is not supported by this connection",
+        ))
+    }
+
+    /// Read CPU registers with the monitor's `r` command
+    ///
+    /// The default implementation reports that this is unsupported.
+    fn read_registers(&mut self) -> Result<cpu::Registers> {
+        Err(Matrix65Error::Unsupported(
+            "reading registers is not supported by this connection",
+        ))
+    }
+
+    /// Execute a single instruction and return the new register state
+    ///
+    /// The CPU must be stopped with `stop_cpu()` first.
+    /// The default implementation reports that this is unsupported.
+    fn step(&mut self) -> Result<cpu::Registers> {
+        Err(Matrix65Error::Unsupported(
+            "single-stepping is not supported by this connection",
+        ))
+    }
+
+    /// Halt the CPU when it reaches `address`
+    ///
+    /// The monitor has a single hardware breakpoint, so this replaces any
+    /// previously set breakpoint. The default implementation reports that
+    /// this is unsupported.
+    fn set_breakpoint(&mut self, _address: u32) -> Result<()> {
+        Err(Matrix65Error::Unsupported(
+            "breakpoints are not supported by this connection",
+        ))
+    }
+
+    /// Remove the breakpoint set with `set_breakpoint()`
+    fn clear_breakpoint(&mut self) -> Result<()> {
+        Err(Matrix65Error::Unsupported(
+            "breakpoints are not supported by this connection",
+        ))
+    }
+
+    /// Set the program counter to `address` and resume the CPU
+    ///
+    /// This starts machine code, e.g. after `write_memory()`; BASIC
+    /// programs must be started with `RUN` instead. The default
+    /// implementation reports that this is unsupported.
+    fn jump(&mut self, _address: u32) -> Result<()> {
+        Err(Matrix65Error::Unsupported(
+            "jumping to an address is not supported by this connection",
+        ))
+    }
+
+    /// Identify hardware model, core and hypervisor versions, and mode
+    fn model_info(&mut self) -> Result<info::MachineInfo> {
+        info::MachineInfo::read(self)
+    }
+
+    /// Read bytes from MEGA65 in chunks, passing each chunk on as it arrives
+    ///
+    /// All chunks except the last are multiples of 16 bytes, so a line
+    /// based hexdump can be printed directly from `progress`. Returns all
+    /// bytes read.
+    fn read_memory_with_progress(
+        &mut self,
+        address: u32,
+        length: usize,
+        progress: &mut dyn FnMut(&[u8]),
+    ) -> Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(length);
+        while bytes.len() < length {
+            let chunk_size = READ_CHUNK_SIZE.min(length - bytes.len());
+            let chunk = self.read_memory(address + bytes.len() as u32, chunk_size)?;
+            progress(&chunk);
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(bytes)
+    }
+
+    /// Read single byte from MEGA65
+    fn peek(&mut self, address: u32) -> Result<u8> {
+        let bytes = self.read_memory(address, 1)?;
+        Ok(bytes[0])
+    }
+
+    /// Write single byte to MEGA65
+    fn poke(&mut self, destination: u16, value: u8) -> Result<()> {
+        self.write_memory(destination, &[value])
+    }
+
+    /// Write single byte anywhere in the 28-bit address space, e.g. to an I/O register
+    fn poke_far(&mut self, destination: u32, value: u8) -> Result<()> {
+        self.write_memory_far(destination, &[value])
+    }
+
+    /// Read colour RAM at `COLOR_RAM`, starting `offset` bytes into it
+    ///
+    /// Reads are limited to the part in use in the current mode, see
+    /// `color_ram_size()`, and values are masked to the low nibble.
+    ///
+    /// Example:
+    /// ~~~
+    /// use matrix65::test_support::MockCommunicator;
+    /// use matrix65::M65Communicator;
+    /// let mut mock = MockCommunicator::new();
+    /// mock.load(0xff80002, &[0xf1, 0x2e]);
+    /// mock.load(0xff80400, &[0x13, 0xa4]);
+    /// assert_eq!(mock.read_color_ram(2, 2).unwrap(), [0x01, 0x0e]);
+    /// assert_eq!(mock.reads.last(), Some(&(0xff80002, 2)));
+    /// assert!(mock.read_color_ram(0x400, 2).is_err());
+    /// mock.c65_mode = true;
+    /// assert_eq!(mock.read_color_ram(0x400, 2).unwrap(), [0x03, 0x04]);
+    /// assert_eq!(mock.reads.last(), Some(&(0xff80400, 2)));
+    /// ~~~
+    fn read_color_ram(&mut self, offset: usize, length: usize) -> Result<Vec<u8>> {
+        check_color_ram_range(self.is_c65_mode()?, offset, length)?;
+        let bytes = self.read_memory(COLOR_RAM + offset as u32, length)?;
+        Ok(bytes.iter().map(|byte| byte & 0x0f).collect())
+    }
+
+    /// Write colour RAM at `COLOR_RAM`, starting `offset` bytes into it
+    ///
+    /// Writes are limited as for `read_color_ram()`, covering 1 KB in
+    /// C64 mode and 32 KB in C65 mode. Values are masked to the low nibble.
+    ///
+    /// Example:
+    /// ~~~
+    /// use matrix65::test_support::MockCommunicator;
+    /// use matrix65::M65Communicator;
+    /// let mut mock = MockCommunicator::new();
+    /// mock.write_color_ram(0x10, &[0xf1, 0x2e]).unwrap();
+    /// assert_eq!(mock.writes.last(), Some(&(0xff80010, vec![0x01, 0x0e])));
+    /// assert!(mock.write_color_ram(0x3ff, &[1, 2]).is_err());
+    /// mock.c65_mode = true;
+    /// mock.write_color_ram(0x3ff, &[0x13, 0xa4]).unwrap();
+    /// assert_eq!(mock.writes.last(), Some(&(0xff803ff, vec![0x03, 0x04])));
+    /// ~~~
+    fn write_color_ram(&mut self, offset: usize, bytes: &[u8]) -> Result<()> {
+        check_color_ram_range(self.is_c65_mode()?, offset, bytes.len())?;
+        let nibbles: Vec<u8> = bytes.iter().map(|byte| byte & 0x0f).collect();
+        self.write_memory_far(COLOR_RAM + offset as u32, &nibbles)
+    }
+
+    /// Detect if in C65 mode
+    fn is_c65_mode(&mut self) -> Result<bool> {
+        let byte = self.peek(0xffd3030)?;
+        Ok(byte == 0x64)
+    }
+
+    /// If not already there, go to C64 mode via key presses
+    fn go64(&mut self) -> Result<()> {
+        log::debug!("Sending GO64");
+        if self.is_c65_mode()? {
+            self.type_text("go64\ry\r")?;
+            thread::sleep(Duration::from_secs(1));
+        }
+        Ok(())
+    }
+
+    /// If not already there, go to C65 mode via a reset
+    fn go65(&mut self) -> Result<()> {
+        if !self.is_c65_mode()? {
+            self.reset()?;
+        }
+        Ok(())
+    }
+
+    /// Read back memory and compare with `bytes`
+    ///
+    /// Fails with `Matrix65Error::VerifyMismatch` on any difference.
+    ///
+    /// Example:
+    /// ~~~
+    /// use matrix65::test_support::MockCommunicator;
+    /// use matrix65::{M65Communicator, Matrix65Error};
+    /// let mut mock = MockCommunicator::new();
+    /// mock.load(0x1000, &[1, 2, 3]);
+    /// assert!(mock.verify_memory(0x1000, &[1, 2, 3]).is_ok());
+    /// let err = mock.verify_memory(0x1000, &[1, 9, 9]).unwrap_err();
+    /// assert!(matches!(
+    ///     err,
+    ///     Matrix65Error::VerifyMismatch { address: 0x1001, expected: 9, found: 2, count: 2 }
+    /// ));
+    /// ~~~
+    fn verify_memory(&mut self, address: u32, bytes: &[u8]) -> Result<()> {
+        let found = self.read_memory(address, bytes.len())?;
+        let mut differences = bytes
+            .iter()
+            .zip(&found)
+            .enumerate()
+            .filter(|(_, (expected, found))| expected != found);
+        if let Some((offset, (expected, found))) = differences.next() {
+            return Err(Matrix65Error::VerifyMismatch {
+                address: address + offset as u32,
+                expected: *expected,
+                found: *found,
+                count: differences.count() + 1,
+            });
+        }
+        Ok(())
+    }
+
+    /// Start machine code at `address` by typing `SYS`
+    fn sys(&mut self, address: u16) -> Result<()> {
+        self.type_text(&format!("sys {}\r", address))
+    }
+
+    /// Transfer to MEGA65 and optionally run PRG
+    ///
+    /// C64/C65 modes are selected from the load address; other load
+    /// addresses are loaded in the current mode. Programs for other
+    /// Commodore machines (PET, C16, C128) can be loaded for inspection,
+    /// but not run. Banked programs are written with 28-bit addresses
+    /// and cannot be run directly, as the CPU only sees them once the
+    /// memory map is set up; start them with `jump()` afterwards. BASIC programs are started
+    /// with `RUN` and machine code with `SYS` at the load address, see
+    /// `io::is_basic_program()`. With `verify`, the written memory is read
+    /// back and compared before running, see `verify_memory()`.
+    fn handle_prg_from_bytes(
+        &mut self,
+        bytes: &[u8],
+        load_address: LoadAddress,
+        reset_before_run: bool,
+        run: bool,
+        verify: bool,
+    ) -> Result<()> {
+        self.handle_prg_from_bytes_with_progress(
+            bytes,
+            load_address,
+            reset_before_run,
+            run,
+            verify,
+            &mut |_, _| {},
+        )
+    }
+
+    /// Transfer to MEGA65 and optionally run PRG, reporting transfer progress
+    ///
+    /// See `write_memory_with_progress()` for the callback arguments.
+    /// Programs that do not fit are rejected before the MEGA65 is touched:
+    /// ~~~
+    /// use matrix65::test_support::MockCommunicator;
+    /// use matrix65::{LoadAddress, M65Communicator};
+    /// let mut mock = MockCommunicator::new();
+    /// let load_address = LoadAddress::at(0xfffff00);
+    /// let result = mock.handle_prg_from_bytes_with_progress(&[0; 512], load_address, true, false, false, &mut |_, _| {});
+    /// assert!(result.is_err());
+    /// // 16-bit load addresses must stay below 64 KB
+    /// let result = mock.handle_prg_from_bytes_with_progress(&[0; 512], LoadAddress::at(0xff00), true, false, false, &mut |_, _| {});
+    /// assert!(result.is_err());
+    /// assert_eq!(mock.resets, 0);
+    /// ~~~
+    fn handle_prg_from_bytes_with_progress(
+        &mut self,
+        bytes: &[u8],
+        load_address: LoadAddress,
+        reset_before_run: bool,
+        run: bool,
+        verify: bool,
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<()> {
+        let foreign = match load_address {
+            LoadAddress::Commodore65
+            | LoadAddress::Commodore64
+            | LoadAddress::Custom(_)
+            | LoadAddress::Banked(_) => None,
+            LoadAddress::Commodore128 => Some("C128"),
+            LoadAddress::Commodore16 => Some("C16, VIC-20, and Plus/4"),
+            LoadAddress::PET => Some("PET"),
+        };
+        if let (Some(machine), true) = (foreign, run) {
+            return Err(Matrix65Error::LoadAddressUnsupported {
+                address: load_address.value(),
+                machine,
+            });
+        }
+        if let (LoadAddress::Banked(address), true) = (&load_address, run) {
+            return Err(Matrix65Error::Invalid(format!(
+                "cannot run a program in banked memory at 0x{:07x}; load it without --run \
+                 and start it with go once mapped into the CPU address space",
+                address
+            )));
+        }
+        let address = load_address.address();
+        match load_address {
+            LoadAddress::Banked(_) if address as usize + bytes.len() > 1 << 28 => {
+                return Err(Matrix65Error::Invalid(format!(
+                    "program of {} bytes at 0x{:07x} extends beyond the 28-bit address space",
+                    bytes.len(),
+                    address
+                )))
+            }
+            LoadAddress::Banked(_) => {}
+            _ if address as usize + bytes.len() > 0x10000 => {
+                return Err(Matrix65Error::Invalid(format!(
+                    "program of {} bytes loaded at 0x{:04x} extends beyond 0xffff; \
+                     load larger programs into banked memory, e.g. at 0x40000",
+                    bytes.len(),
+                    address
+                )))
+            }
+            _ => {}
+        }
+        if reset_before_run {
+            self.reset()?;
+        }
+        match load_address {
+            LoadAddress::Commodore65 => self.go65()?,
+            LoadAddress::Commodore64 => self.go64()?,
+            // machine code or other machines; stays in the current mode
+            LoadAddress::Custom(_)
+            | LoadAddress::Banked(_)
+            | LoadAddress::Commodore128
+            | LoadAddress::Commodore16
+            | LoadAddress::PET => {}
+        }
+        self.write_memory_with_progress(address, bytes, progress)?;
+        if verify {
+            self.verify_memory(address, bytes)?;
+        }
+        if run {
+            let address = load_address.value();
+            match io::is_basic_program(address, bytes) {
+                true => self.type_text("run\r")?,
+                false => self.sys(address)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Transfers and optionally run PRG to MEGA65
+    ///
+    /// Here `file` can be a local file or a url. CBM disk images are allowed and
+    /// C64/C65 modes are detected from load address.
+    fn handle_prg(
+        &mut self,
+        file: &str,
+        reset_before_run: bool,
+        run: bool,
+        verify: bool,
+    ) -> Result<()> {
+        let (load_address, bytes) = io::load_prg(file)?;
+        self.handle_prg_from_bytes(&bytes, load_address, reset_before_run, run, verify)
     }
 }
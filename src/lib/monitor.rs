@@ -0,0 +1,216 @@
+// copyright 2022 mikael lund aka wombat
+//
+// licensed under the apache license, version 2.0 (the "license");
+// you may not use this file except in compliance with the license.
+// you may obtain a copy of the license at
+//
+//     http://www.apache.org/licenses/license-2.0
+//
+// unless required by applicable law or agreed to in writing, software
+// distributed under the license is distributed on an "as is" basis,
+// without warranties or conditions of any kind, either express or implied.
+// see the license for the specific language governing permissions and
+// limitations under the license.
+
+//! Text monitor protocol over a line based connection
+//!
+//! Xemu's remote monitor takes commands as text lines over TCP and
+//! replies as the MEGA65 does on the serial port. `TextMonitor`
+//! implements the commands on top of any `MonitorLink`.
+
+use crate::serial::{check_monitor_reply, check_typed, key_codes, parse_dump_line, NO_KEY};
+use crate::{CancelToken, Matrix65Error, Result, DEFAULT_KEY_DELAY};
+use log::debug;
+use std::io::{ErrorKind, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Give up waiting for a reply or memory dump after this long
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Bytes per page dump from the `M` command
+const PAGE_SIZE: usize = 256;
+
+/// Bytes per `s` command when writing memory; keeps command lines short
+const WRITE_CHUNK_SIZE: usize = 32;
+
+/// Largest chunk received at once
+const MAX_CHUNK_SIZE: usize = 1500;
+
+/// Connection carrying monitor commands and replies
+///
+/// Reads must time out, e.g. with `set_read_timeout()`, as a reply is
+/// considered complete once nothing more arrives.
+pub trait MonitorLink {
+    /// Send a single command; the line terminator is added by the link
+    fn send_line(&mut self, line: &str) -> Result<()>;
+
+    /// Receive available bytes; `None` if nothing arrived within the read timeout
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<Option<usize>>;
+}
+
+/// True if a read failed only because nothing arrived within the read timeout
+pub(crate) fn is_timeout(err: &std::io::Error) -> bool {
+    matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// Monitor commands sent as text over a `MonitorLink`
+pub struct TextMonitor<L: MonitorLink> {
+    link: L,
+    /// Aborts reads and writes once cancelled
+    cancel: Option<CancelToken>,
+    /// Delay between key presses when typing
+    key_delay: Duration,
+}
+
+impl<L: MonitorLink> TextMonitor<L> {
+    /// Monitor on `link`, which must have a read timeout
+    pub fn new(link: L) -> Self {
+        TextMonitor {
+            link,
+            cancel: None,
+            key_delay: DEFAULT_KEY_DELAY,
+        }
+    }
+
+    /// Set delay between key presses when typing
+    pub fn set_key_delay(&mut self, delay: Duration) {
+        self.key_delay = delay;
+    }
+
+    /// Abort reads and writes once `token` is cancelled
+    pub fn set_cancel_token(&mut self, token: CancelToken) {
+        self.cancel = Some(token);
+    }
+
+    /// Fail if the current operation has been cancelled
+    fn check_cancelled(&self) -> Result<()> {
+        match &self.cancel {
+            Some(token) if token.is_cancelled() => Err(Matrix65Error::Cancelled),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read and discard everything until the monitor goes quiet
+    pub fn flush(&mut self) -> Result<usize> {
+        let mut buffer = [0u8; MAX_CHUNK_SIZE];
+        let mut drained = 0;
+        while let Some(n) = self.link.receive(&mut buffer)? {
+            drained += n;
+        }
+        Ok(drained)
+    }
+
+    /// Send a monitor command and collect the reply until the monitor goes quiet
+    pub fn query(&mut self, command: &str) -> Result<Vec<u8>> {
+        self.check_cancelled()?;
+        self.flush()?;
+        self.link.send_line(command)?;
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        let mut reply = Vec::new();
+        let mut buffer = [0u8; MAX_CHUNK_SIZE];
+        loop {
+            self.check_cancelled()?;
+            match self.link.receive(&mut buffer)? {
+                Some(n) => reply.extend_from_slice(&buffer[..n]),
+                None if !reply.is_empty() => return Ok(reply),
+                None if Instant::now() > deadline => {
+                    return Err(Matrix65Error::Serial(
+                        "timeout waiting for monitor reply".to_string(),
+                    ))
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Send a monitor command and fail if the monitor rejects it
+    pub fn command(&mut self, command: &str) -> Result<()> {
+        let reply = self.query(command)?;
+        check_monitor_reply(&reply)
+    }
+
+    /// Request and parse a 256 byte memory dump starting at `address`
+    pub fn read_page(&mut self, address: u32) -> Result<Vec<u8>> {
+        self.check_cancelled()?;
+        self.link.send_line(&format!("M{:07x}", address))?;
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        let mut buffer = [0u8; MAX_CHUNK_SIZE];
+        let mut pending = Vec::new();
+        let mut page = Vec::with_capacity(PAGE_SIZE);
+        while page.len() < PAGE_SIZE {
+            self.check_cancelled()?;
+            if Instant::now() > deadline {
+                return Err(Matrix65Error::Serial(
+                    "timeout waiting for memory dump".to_string(),
+                ));
+            }
+            if let Some(n) = self.link.receive(&mut buffer)? {
+                pending.extend_from_slice(&buffer[..n]);
+            }
+            while let Some(end) = pending.iter().position(|c| *c == b'\n' || *c == b'\r') {
+                let line: Vec<u8> = pending.drain(..=end).collect();
+                if let Some((line_address, mut bytes)) = parse_dump_line(&line[..end]) {
+                    if line_address != address + page.len() as u32 {
+                        return Err(Matrix65Error::Serial(
+                            "unexpected address in memory dump".to_string(),
+                        ));
+                    }
+                    page.append(&mut bytes);
+                } else {
+                    check_monitor_reply(&line)?;
+                }
+            }
+        }
+        Ok(page)
+    }
+
+    /// Load memory a page at a time using the `M` command
+    pub fn read_memory_to_writer(
+        &mut self,
+        address: u32,
+        length: usize,
+        out: &mut dyn Write,
+    ) -> Result<()> {
+        debug!("Loading {} bytes from 0x{:x}", length, address);
+        self.flush()?;
+        let mut written = 0;
+        while written < length {
+            let page = self.read_page(address + written as u32)?;
+            let count = page.len().min(length - written);
+            out.write_all(&page[..count])?;
+            written += count;
+        }
+        Ok(())
+    }
+
+    /// Write memory in small chunks using the `s` command
+    pub fn write_memory_far(&mut self, address: u32, bytes: &[u8]) -> Result<()> {
+        debug!("Writing {} byte(s) to address 0x{:x}", bytes.len(), address);
+        for (i, chunk) in bytes.chunks(WRITE_CHUNK_SIZE).enumerate() {
+            let start = address as usize + i * WRITE_CHUNK_SIZE;
+            let values: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+            self.command(&format!("s{:x} {}", start, values.join(" ")))?;
+        }
+        Ok(())
+    }
+
+    /// Type text by poking keyboard matrix codes into the hardware registers
+    pub fn type_text(&mut self, text: &str) -> Result<()> {
+        debug!("Typing text");
+        let mut unsupported = Vec::new();
+        for key in text.replace("\\r", "\r").replace("\\n", "\r").chars() {
+            let (c1, c2) = match key_codes(key) {
+                NO_KEY => {
+                    unsupported.push(key);
+                    continue;
+                }
+                codes => codes,
+            };
+            self.command(&format!("sffd3615 {:02x} {:02x}", c1, c2))?;
+            thread::sleep(self.key_delay);
+        }
+        self.command("sffd3615 7f 7f 7f")?;
+        check_typed(unsupported)
+    }
+}
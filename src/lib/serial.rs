@@ -14,102 +14,600 @@
 
 //! Routines for serial communication with MEGA65
 
-use crate::LoadAddress;
-
-use super::io;
-use anyhow::Result;
+use crate::cpu::Registers;
+use crate::{CancelToken, M65Communicator, Matrix65Error, Result, DEFAULT_KEY_DELAY};
 use hex::FromHex;
-use log::debug;
-use serialport::SerialPort;
+use log::{debug, trace, warn};
+use serde::Serialize;
+use serialport::{SerialPort, SerialPortType};
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
-use std::io::{Read, Write};
+use std::time::{Duration, Instant};
 
 /// Delay after writing to serial port
 const DELAY_WRITE: Duration = Duration::from_millis(20);
-/// Delay between sending key presses
-const DELAY_KEYPRESS: Duration = DELAY_WRITE;
 /// Default serial speed in bits per second
 pub const DEFAULT_BAUD_RATE: u32 = 2000000;
+/// Default time to wait for incoming bytes before a read gives up
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_millis(10);
+/// Number of bytes in each line of a monitor memory dump
+const DUMP_LINE_LENGTH: usize = 16;
+/// Number of lines returned by the page dump (`M`) command
+const DUMP_PAGE_LINES: usize = 16;
+/// Maximum time to wait for a complete page dump
+const PAGE_DUMP_TIMEOUT: Duration = Duration::from_secs(1);
+/// Maximum time to wait for an expected number of reply bytes
+const REPLY_TIMEOUT: Duration = Duration::from_millis(500);
+/// Time to wait for the complete help text from the `h` command
+const HELP_TIMEOUT: Duration = Duration::from_millis(200);
+/// Time for the reset to take effect before polling for readiness
+const RESET_SETTLE: Duration = Duration::from_secs(1);
+/// Delay between readiness checks after a reset
+const RESET_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Give up waiting for the MEGA65 to become ready after a reset after this long
+const RESET_TIMEOUT: Duration = Duration::from_secs(10);
+/// C65 mode screen RAM and size where the BASIC prompt appears after boot
+const BOOT_SCREEN: u32 = 0x0800;
+const BOOT_SCREEN_SIZE: usize = 80 * 25;
+/// "READY." as screen codes
+const READY_PROMPT: [u8; 6] = [0x12, 0x05, 0x01, 0x04, 0x19, 0x2e];
+/// Time to hold down RUN/STOP + RESTORE for a warm reset
+pub const DELAY_WARM_RESET: Duration = Duration::from_millis(200);
+
+/// Keyboard matrix position of RUN/STOP
+pub const KEY_RUN_STOP: u8 = 0x3f;
+/// Keyboard matrix position of RESTORE
+pub const KEY_RESTORE: u8 = 0x52;
+/// Hardware keyboard queue with the ASCII value of the last key; write to dequeue
+pub const ASCII_KEY_QUEUE: u32 = 0xffd3610;
+
+/// Payload bytes sent per burst with software flow control
+const FLOW_BURST_SIZE: usize = 64;
+
+/// Pause between bursts with software flow control
+const FLOW_BURST_PAUSE: Duration = Duration::from_millis(2);
+
+/// Longest sleep before checking for cancellation
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Number of times a corrupted memory dump is re-read before giving up
+pub const DEFAULT_READ_RETRIES: usize = 3;
+
+/// Give up matching a dump header pattern after this many bytes
+const MAX_HEADER_LENGTH: usize = 256;
+/// Give up flushing if the monitor keeps sending for this long
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+/// Give up flushing after discarding this many bytes
+const FLUSH_MAX_BYTES: usize = 1 << 20;
+
+/// Echo and header sent by the monitor before the hex digits of a memory dump
+///
+/// The defaults match the mainline MEGA65 monitor. Cores with a
+/// different prompt or echo can instead use a pattern which is
+/// matched against the incoming bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DumpHeader {
+    /// Skip a fixed number of bytes after the first and the continuation requests
+    Fixed { first: usize, next: usize },
+    /// Skip until this literal has been received; `{addr}` matches any hex address
+    Pattern(String),
+}
+
+impl DumpHeader {
+    /// Fixed header lengths of the mainline MEGA65 monitor (default)
+    pub const MAINLINE: DumpHeader = DumpHeader::Fixed {
+        first: 27,
+        next: 18,
+    };
+
+    /// Find header by preset name
+    ///
+    /// - `mainline`: fixed header sizes of the mainline MEGA65 monitor
+    /// - `generic`: synchronise on the `:<address>:` prefix of each dump line
+    ///   which is slightly slower, but tolerates other prompts and echoes
+    pub fn preset(name: &str) -> Option<DumpHeader> {
+        match name {
+            "mainline" => Some(DumpHeader::MAINLINE),
+            "generic" => Some(DumpHeader::Pattern(":{addr}:".to_string())),
+            _ => None,
+        }
+    }
+}
+
+impl Default for DumpHeader {
+    fn default() -> Self {
+        DumpHeader::MAINLINE
+    }
+}
+
+/// Parse preset name or, if not a preset, a header pattern
+///
+/// Example:
+/// ~~~
+/// use matrix65::serial::DumpHeader;
+/// let header: DumpHeader = "mainline".parse().unwrap();
+/// assert_eq!(header, DumpHeader::MAINLINE);
+/// let header: DumpHeader = "> {addr} ".parse().unwrap();
+/// assert_eq!(header, DumpHeader::Pattern("> {addr} ".to_string()));
+/// ~~~
+impl FromStr for DumpHeader {
+    type Err = Matrix65Error;
+    fn from_str(text: &str) -> Result<Self> {
+        if text.is_empty() {
+            return Err(Matrix65Error::Parse(
+                "empty monitor header pattern".to_string(),
+            ));
+        }
+        Ok(DumpHeader::preset(text).unwrap_or_else(|| DumpHeader::Pattern(text.to_string())))
+    }
+}
+
+/// Check if the received bytes end with a header pattern
+///
+/// The placeholder `{addr}` in the pattern matches one or more hex digits.
+///
+/// Example:
+/// ~~~
+/// use matrix65::serial::ends_with_pattern;
+/// assert!(ends_with_pattern(b"m0000800\r\n:00000800:", ":{addr}:"));
+/// assert!(!ends_with_pattern(b"m0000800\r\n:0000", ":{addr}:"));
+/// assert!(ends_with_pattern(b"prompt> ", "> "));
+/// ~~~
+pub fn ends_with_pattern(received: &[u8], pattern: &str) -> bool {
+    match pattern.split_once("{addr}") {
+        None => received.ends_with(pattern.as_bytes()),
+        Some((prefix, suffix)) => match received.strip_suffix(suffix.as_bytes()) {
+            None => false,
+            Some(rest) => {
+                let digits = rest
+                    .iter()
+                    .rev()
+                    .take_while(|c| c.is_ascii_hexdigit())
+                    .count();
+                digits > 0 && rest[..rest.len() - digits].ends_with(prefix.as_bytes())
+            }
+        },
+    }
+}
+
+/// Flow control when sending data to the MEGA65
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlowControl {
+    /// Send data as fast as the port allows
+    #[default]
+    None,
+    /// Send payloads in small bursts with a short pause in between
+    ///
+    /// XON/XOFF is not used as the binary payload may contain those bytes.
+    Software,
+    /// Honor RTS/CTS handshaking, if supported by the adapter
+    Hardware,
+}
+
+/// Examples:
+/// ~~~
+/// use matrix65::serial::FlowControl;
+/// assert_eq!("none".parse::<FlowControl>().unwrap(), FlowControl::None);
+/// assert_eq!("software".parse::<FlowControl>().unwrap(), FlowControl::Software);
+/// assert_eq!("hardware".parse::<FlowControl>().unwrap(), FlowControl::Hardware);
+/// assert!("xon".parse::<FlowControl>().is_err());
+/// ~~~
+impl FromStr for FlowControl {
+    type Err = Matrix65Error;
+    fn from_str(text: &str) -> Result<Self> {
+        match text {
+            "none" => Ok(FlowControl::None),
+            "software" => Ok(FlowControl::Software),
+            "hardware" => Ok(FlowControl::Hardware),
+            _ => Err(Matrix65Error::Parse(format!(
+                "invalid flow control '{}'; use none, software, or hardware",
+                text
+            ))),
+        }
+    }
+}
+
+/// Optional monitor features, detected when first needed
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Capabilities {
+    /// Monitor can dump 256 bytes per request with the `M` command
+    pub page_dump: bool,
+    /// Monitor can dump memory without first halting the CPU
+    pub running_read: bool,
+}
+
+/// Accumulated time spent in each step of the serial protocol
+///
+/// Steps are `stop_cpu`, `start_cpu`, `write`, `read`, and `sleep`;
+/// CPU stop/start includes its own writing and sleeping.
+#[derive(Debug, Default)]
+pub struct Timing {
+    /// Step name, number of calls, and total duration
+    steps: Vec<(&'static str, usize, Duration)>,
+}
+
+impl Timing {
+    /// Add time spent in the given step
+    pub fn record(&mut self, step: &'static str, duration: Duration) {
+        match self.steps.iter_mut().find(|(name, _, _)| *name == step) {
+            Some((_, calls, total)) => {
+                *calls += 1;
+                *total += duration;
+            }
+            None => self.steps.push((step, 1, duration)),
+        }
+    }
+
+    /// Total time spent in all steps
+    pub fn total(&self) -> Duration {
+        self.steps.iter().map(|(_, _, duration)| *duration).sum()
+    }
+}
+
+impl fmt::Display for Timing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.total().as_secs_f64();
+        writeln!(
+            f,
+            "{:<10} {:>8} {:>12} {:>7}",
+            "step", "calls", "time (ms)", "share"
+        )?;
+        let mut steps = self.steps.clone();
+        steps.sort_by_key(|(_, _, duration)| std::cmp::Reverse(*duration));
+        for (step, calls, duration) in steps {
+            let seconds = duration.as_secs_f64();
+            let share = if total > 0.0 {
+                100.0 * seconds / total
+            } else {
+                0.0
+            };
+            writeln!(
+                f,
+                "{:<10} {:>8} {:>12.1} {:>6.1}%",
+                step,
+                calls,
+                1000.0 * seconds,
+                share
+            )?;
+        }
+        write!(f, "{:<10} {:>8} {:>12.1}", "total", "", 1000.0 * total)
+    }
+}
 
-/// Stop the MEGA65 CPU
-pub fn stop_cpu(port: &mut dyn Write) -> Result<()> {
-    port.write_all("t1\r".as_bytes())?;
-    port.flush()?;
-    thread::sleep(DELAY_WRITE);
-    Ok(())
+/// Serial communicator for the MEGA65 matrix mode monitor
+pub struct M65Serial {
+    port: Box<dyn SerialPort>,
+    /// Detected monitor features; `None` until probed
+    capabilities: Option<Capabilities>,
+    /// Time spent in each protocol step, if tracing is enabled
+    timing: Option<Arc<Mutex<Timing>>>,
+    /// Expected header of memory dumps
+    dump_header: DumpHeader,
+    /// Try to read memory without halting the CPU
+    no_halt: bool,
+    /// Read memory with page dumps if the monitor supports them
+    page_dump: bool,
+    /// Aborts reads, writes, and delays once cancelled
+    cancel: Option<CancelToken>,
+    /// Pacing of payloads sent with `write_memory`
+    flow_control: FlowControl,
+    /// Re-read corrupted memory dumps this many times
+    read_retries: usize,
+    /// Delay between key presses when typing
+    key_delay: Duration,
 }
 
-/// Start the MEGA65 CPU after being halted
-pub fn start_cpu(port: &mut dyn Write) -> Result<()> {
-    port.write_all("t0\r".as_bytes())?;
-    port.flush()?;
-    thread::sleep(DELAY_WRITE);
-    Ok(())
+/// Serial port with USB metadata, if available
+///
+/// Field names are stable as they are used for JSON output.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PortInfo {
+    /// Device name to pass to `--port`
+    pub name: String,
+    /// "usb", "pci", "bluetooth", or "unknown"
+    pub kind: String,
+    /// USB vendor id
+    pub vid: Option<u16>,
+    /// USB product id
+    pub pid: Option<u16>,
+    pub serial_number: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
 }
 
-/// Detect if in C65 mode
-pub fn is_c65_mode<T: Read + Write>(port: &mut T) -> Result<bool> {
-    let byte = peek(port, 0xffd3030)?;
-    Ok(byte == 0x64)
+impl From<serialport::SerialPortInfo> for PortInfo {
+    fn from(info: serialport::SerialPortInfo) -> PortInfo {
+        let mut port = PortInfo {
+            name: info.port_name,
+            kind: "unknown".to_string(),
+            vid: None,
+            pid: None,
+            serial_number: None,
+            manufacturer: None,
+            product: None,
+        };
+        match info.port_type {
+            SerialPortType::UsbPort(usb) => {
+                port.kind = "usb".to_string();
+                port.vid = Some(usb.vid);
+                port.pid = Some(usb.pid);
+                port.serial_number = usb.serial_number;
+                port.manufacturer = usb.manufacturer;
+                port.product = usb.product;
+            }
+            SerialPortType::PciPort => port.kind = "pci".to_string(),
+            SerialPortType::BluetoothPort => port.kind = "bluetooth".to_string(),
+            SerialPortType::Unknown => {}
+        }
+        port
+    }
+}
+
+impl fmt::Display for PortInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let (Some(vid), Some(pid)) = (self.vid, self.pid) {
+            write!(f, " [{:04x}:{:04x}]", vid, pid)?;
+        }
+        let description: Vec<&str> = [&self.manufacturer, &self.product]
+            .iter()
+            .filter_map(|text| text.as_deref())
+            .collect();
+        if !description.is_empty() {
+            write!(f, " {}", description.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Enumerate available serial ports
+pub fn available_ports() -> Result<Vec<PortInfo>> {
+    debug!("Detecting serial ports");
+    Ok(serialport::available_ports()?
+        .into_iter()
+        .map(PortInfo::from)
+        .collect())
+}
+
+/// USB vendor and product ids of the FTDI chips used by MEGA65 boards and JTAG adapters
+pub const MEGA65_USB_IDS: [(u16, u16); 2] = [(0x0403, 0x6010), (0x0403, 0x6001)];
+
+/// Pick the port of a single MEGA65 among `ports` by USB vendor and product id
+///
+/// Dual channel FTDI chips appear as two ports with the same serial
+/// number; the first channel is JTAG and the second the serial monitor,
+/// so the last port by name is used. Fails if there is no MEGA65 or more
+/// than one.
+///
+/// Example:
+/// ~~~
+/// use matrix65::serial::{select_mega65_port, PortInfo};
+/// let port = |name: &str, pid| PortInfo {
+///     name: name.to_string(),
+///     kind: "usb".to_string(),
+///     vid: Some(0x0403),
+///     pid: Some(pid),
+///     serial_number: Some("TE0790".to_string()),
+///     manufacturer: None,
+///     product: None,
+/// };
+/// let ports = [port("/dev/ttyUSB0", 0x6010), port("/dev/ttyUSB1", 0x6010)];
+/// assert_eq!(select_mega65_port(&ports).unwrap(), "/dev/ttyUSB1");
+/// assert!(select_mega65_port(&[port("/dev/ttyACM0", 0x1234)]).is_err());
+/// ~~~
+pub fn select_mega65_port(ports: &[PortInfo]) -> Result<String> {
+    let mut devices: Vec<(String, &str)> = Vec::new();
+    let candidates = ports.iter().filter(|port| match (port.vid, port.pid) {
+        (Some(vid), Some(pid)) => MEGA65_USB_IDS.contains(&(vid, pid)),
+        _ => false,
+    });
+    for port in candidates {
+        let device = port
+            .serial_number
+            .clone()
+            .unwrap_or_else(|| port.name.clone());
+        match devices.iter_mut().find(|(known, _)| *known == device) {
+            Some((_, name)) if port.name.as_str() > *name => *name = &port.name,
+            Some(_) => {}
+            None => devices.push((device, &port.name)),
+        }
+    }
+    let names: Vec<&str> = ports.iter().map(|port| port.name.as_str()).collect();
+    match devices.as_slice() {
+        [(_, name)] => Ok(name.to_string()),
+        [] => Err(Matrix65Error::Serial(format!(
+            "no MEGA65 found among serial ports [{}]; use --port",
+            names.join(", ")
+        ))),
+        _ => Err(Matrix65Error::Serial(format!(
+            "several MEGA65 ports found [{}]; choose one with --port",
+            devices
+                .iter()
+                .map(|(_, name)| *name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+    }
+}
+
+/// Find the serial port of the single connected MEGA65
+pub fn detect_port() -> Result<String> {
+    select_mega65_port(&available_ports()?)
 }
 
 /// Print available serial ports
+///
+/// Enumeration may fail, e.g. without udev permissions on Linux,
+/// in which case hints are printed instead of the ports.
 fn print_ports() {
-    debug!("Detecting serial ports");
-    serialport::available_ports()
-        .expect("No serial ports found!")
-        .iter()
-        .for_each(|port| println!("{}", port.port_name));
+    match available_ports() {
+        Ok(ports) if ports.is_empty() => {
+            eprintln!("No serial ports found. Is the MEGA65 connected and the driver installed?")
+        }
+        Ok(ports) => ports.iter().for_each(|port| println!("{}", port)),
+        Err(err) => {
+            eprintln!("Could not list serial ports: {}", err);
+            eprintln!("Check that the USB serial driver is installed and that you have");
+            eprintln!("permission to access serial devices. On Linux, this usually means");
+            eprintln!("adding yourself to the `dialout` group and logging in again:");
+            eprintln!("    sudo usermod -a -G dialout $USER");
+        }
+    }
     println!();
 }
 
+/// Check if a serial port could not be opened due to missing access rights
+fn is_permission_error(err: &serialport::Error) -> bool {
+    let description = err.description.to_lowercase();
+    matches!(
+        err.kind(),
+        serialport::ErrorKind::Io(std::io::ErrorKind::PermissionDenied)
+    ) || description.contains("permission denied")
+        || description.contains("access is denied")
+}
+
+/// Explain how to gain access to a serial port
+fn print_permission_help(name: &str) {
+    eprintln!("Permission denied when opening serial port {}.\n", name);
+    if cfg!(target_os = "linux") {
+        eprintln!("Add your user to the `dialout` group and log in again:");
+        eprintln!("    sudo usermod -a -G dialout $USER");
+    } else if cfg!(target_os = "windows") {
+        eprintln!("Make sure no other program, e.g. a terminal or m65connect,");
+        eprintln!("is using the port.");
+    } else {
+        eprintln!("Grant your user read and write access to the device,");
+        eprintln!("and make sure no other program is using the port.");
+    }
+}
+
 /// Open serial port - show available ports and stop if invalid
 pub fn open_port(name: &str, baud_rate: u32) -> Result<Box<dyn SerialPort>> {
     debug!("Opening serial port {}", name);
-    match serialport::new(name, baud_rate)
-        .timeout(Duration::from_millis(10))
+    let err = match serialport::new(name, baud_rate)
+        .timeout(DEFAULT_READ_TIMEOUT)
         .open()
     {
-        Ok(port) => Ok(port),
-        Err(err) => {
-            eprintln!("Invalid serial port, try one of these?\n");
-            print_ports();
-            Err(err.into())
-        }
+        Ok(port) => return Ok(port),
+        Err(err) => err,
+    };
+    if is_permission_error(&err) {
+        print_permission_help(name);
+    } else {
+        eprintln!("Invalid serial port, try one of these?\n");
+        print_ports();
     }
+    Err(Matrix65Error::Serial(format!(
+        "cannot open serial port {}: {}",
+        name, err
+    )))
 }
 
-/// Reset the MEGA65
-pub fn reset(port: &mut dyn Write) -> Result<()> {
-    debug!("Sending RESET signal");
-    port.write_all("!\n".as_bytes())?;
-    thread::sleep(Duration::from_secs(4));
-    Ok(())
+/// Check monitor reply for the `?` error prompt
+///
+/// Example with a captured reply to a malformed memory dump request:
+/// ~~~
+/// use matrix65::serial::check_monitor_reply;
+/// use matrix65::Matrix65Error;
+/// let reply = b"mxyz\r\n?\r\n.";
+/// assert!(matches!(
+///     check_monitor_reply(reply),
+///     Err(Matrix65Error::MonitorError(reply)) if reply == "mxyz\r\n?\r\n."
+/// ));
+/// assert!(check_monitor_reply(b"m0000800\r\n:00000800:A9").is_ok());
+/// ~~~
+pub fn check_monitor_reply(reply: &[u8]) -> Result<()> {
+    let is_error = reply
+        .split(|c| *c == b'\r' || *c == b'\n')
+        .any(|line| line.iter().find(|c| !c.is_ascii_whitespace()) == Some(&b'?'));
+    match is_error {
+        true => Err(Matrix65Error::MonitorError(
+            String::from_utf8_lossy(reply).trim().to_string(),
+        )),
+        false => Ok(()),
+    }
 }
 
-/// If not already there, go to C64 mode via key presses
-pub fn go64<T: Read + Write>(port: &mut T) -> Result<()> {
-    debug!("Sending GO64");
-    if is_c65_mode(port)? {
-        type_text(port, "go64\ry\r")?;
-        thread::sleep(Duration::from_secs(1));
+/// Parse a single line from a monitor memory dump
+///
+/// Lines have the form `:<address>:<hex bytes>`; anything else,
+/// e.g. command echo or the prompt, gives `None`.
+///
+/// Example:
+/// ~~~
+/// use matrix65::serial::parse_dump_line;
+/// let line = b":00000800:0102030405060708090A0B0C0D0E0F10";
+/// let (address, bytes) = parse_dump_line(line).unwrap();
+/// assert_eq!(address, 0x800);
+/// assert_eq!(bytes.len(), 16);
+/// assert_eq!(bytes[15], 0x10);
+/// assert!(parse_dump_line(b"M0000800").is_none());
+/// ~~~
+pub fn parse_dump_line(line: &[u8]) -> Option<(u32, Vec<u8>)> {
+    let line = line.strip_prefix(b":")?;
+    let separator = line.iter().position(|c| *c == b':')?;
+    let address = std::str::from_utf8(&line[..separator]).ok()?;
+    let address = u32::from_str_radix(address, 16).ok()?;
+    let hex_digits: Vec<u8> = line[separator + 1..]
+        .iter()
+        .copied()
+        .take_while(u8::is_ascii_hexdigit)
+        .collect();
+    if hex_digits.len() != DUMP_LINE_LENGTH * 2 {
+        return None;
     }
-    Ok(())
+    let bytes = Vec::from_hex(&hex_digits).ok()?;
+    Some((address, bytes))
 }
 
-/// If not already there, go to C65 mode via a reset
-pub fn go65<T: Read + Write>(port: &mut T) -> Result<()> {
-    if !is_c65_mode(port)? {
-        reset(port)?;
+/// Keyboard matrix codes of letters that cannot be typed
+pub const NO_KEY: (u8, u8) = (0x7f, 0x7f);
+
+/// Report characters skipped while typing, if any
+pub(crate) fn check_typed(unsupported: Vec<char>) -> Result<()> {
+    match unsupported.is_empty() {
+        true => Ok(()),
+        false => Err(Matrix65Error::UnsupportedKeys(unsupported)),
     }
-    Ok(())
 }
 
-/// Translate and type a single letter on MEGA65
-fn type_key(port: &mut dyn Write, mut key: char) -> Result<()> {
+/// Translate letter to the keyboard matrix codes written to $FFD3615-6
+///
+/// Unknown letters give `NO_KEY`.
+///
+/// Example:
+/// ~~~
+/// use matrix65::serial::{key_codes, NO_KEY};
+/// assert_eq!(key_codes('a'), (0x0a, 0x7f));
+/// assert_eq!(key_codes('~'), NO_KEY);
+/// assert_eq!(key_codes('A'), (0x0a, 0x0f));
+/// // every upper case letter is the lower case key with shift
+/// assert!(('A'..='Z').all(|c| key_codes(c) == (key_codes(c.to_ascii_lowercase()).0, 0x0f)));
+/// assert_eq!(key_codes('!'), (0x38, 0x0f));
+/// assert_eq!(key_codes('&'), (0x13, 0x0f));
+/// assert_eq!(key_codes('\n'), key_codes('\r'));
+/// // 'Ł' (U+0141) must not be typed as 'A' (0x41)
+/// assert_eq!(key_codes('\u{141}'), NO_KEY);
+/// ~~~
+pub fn key_codes(mut key: char) -> (u8, u8) {
+    // the lookup below works on single bytes, so wider characters would wrap around
+    if key > '\u{ff}' {
+        return NO_KEY;
+    }
     let mut c1: u8 = 0x7f;
     let mut c2 = match key {
+        // shifted letters are upper case
+        'A'..='Z' => {
+            key = key.to_ascii_lowercase();
+            0x0f
+        }
+        '\n' => {
+            key = '\r';
+            0x7f
+        }
         '!' => {
             key = '1';
             0x0f
@@ -130,6 +628,14 @@ fn type_key(port: &mut dyn Write, mut key: char) -> Result<()> {
             key = '5';
             0x0f
         }
+        '&' => {
+            key = '6';
+            0x0f
+        }
+        '\'' => {
+            key = '7';
+            0x0f
+        }
         '(' => {
             key = '8';
             0x0f
@@ -150,6 +656,14 @@ fn type_key(port: &mut dyn Write, mut key: char) -> Result<()> {
             key = '.';
             0x0f
         }
+        '[' => {
+            key = ':';
+            0x0f
+        }
+        ']' => {
+            key = ';';
+            0x0f
+        }
         _ => 0x7f,
     };
 
@@ -227,163 +741,779 @@ fn type_key(port: &mut dyn Write, mut key: char) -> Result<()> {
         _ => c1 = 0x7f,
     }
 
-    port.write_all(format!("sffd3615 {:02x} {:02x}\n", c1, c2).as_bytes())?;
-    thread::sleep(DELAY_KEYPRESS);
-    Ok(())
+    (c1, c2)
 }
 
-/// Call this when done typing
-fn stop_typing(port: &mut dyn Write) -> Result<()> {
-    port.write_all("sffd3615 7f 7f 7f \n".as_bytes())?;
-    thread::sleep(DELAY_WRITE);
-    Ok(())
-}
+impl M65Serial {
+    /// Create communicator from an already opened serial port
+    pub fn new(port: Box<dyn SerialPort>) -> Self {
+        M65Serial {
+            port,
+            capabilities: None,
+            timing: None,
+            dump_header: DumpHeader::default(),
+            no_halt: false,
+            page_dump: true,
+            cancel: None,
+            flow_control: FlowControl::None,
+            read_retries: DEFAULT_READ_RETRIES,
+            key_delay: DEFAULT_KEY_DELAY,
+        }
+    }
 
-/// Send array of key presses
-pub fn type_text(port: &mut dyn Write, text: &str) -> Result<()> {
-    // Manually translate user defined escape codes:
-    // https://stackoverflow.com/questions/72583983/interpreting-escape-characters-in-a-string-read-from-user-input
-    debug!("Typing text");
-    thread::sleep(DELAY_KEYPRESS);
-    text.replace("\\r", "\r")
-        .replace("\\n", "\r")
-        .chars()
-        .for_each(|key| type_key(port, key).unwrap_or(()));
-    stop_typing(port)?;
-    Ok(())
-}
+    /// Set flow control to avoid overrunning the monitor at high speeds
+    pub fn set_flow_control(&mut self, flow_control: FlowControl) -> Result<()> {
+        let port_flow_control = match flow_control {
+            FlowControl::Hardware => serialport::FlowControl::Hardware,
+            _ => serialport::FlowControl::None,
+        };
+        self.port.set_flow_control(port_flow_control)?;
+        self.flow_control = flow_control;
+        Ok(())
+    }
 
-/// Get MEGA65 info (@todo under construction)
-#[allow(dead_code)]
-fn mega65_info<T: Read + Write>(port: &mut T) -> Result<()> {
-    debug!("Requesting serial monitor info");
-    port.write_all("h\n".as_bytes())?;
-    thread::sleep(DELAY_WRITE);
+    /// Set how long reads wait for incoming bytes
+    ///
+    /// Flushing stops at the first read that times out, so increase this
+    /// if some USB adapters or long cables leave stale replies behind.
+    pub fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.port.set_timeout(timeout)?;
+        Ok(())
+    }
+
+    /// Change the serial speed in bits per second
+    pub fn set_baud(&mut self, baud_rate: u32) -> Result<()> {
+        self.port.set_baud_rate(baud_rate)?;
+        Ok(())
+    }
+
+    /// Leave the CPU running during memory reads, if the monitor supports it
+    ///
+    /// This disturbs running programs and interrupts less, but the
+    /// memory may change while being read. Falls back to halting the
+    /// CPU if the monitor cannot read from a running system.
+    pub fn set_no_halt(&mut self, no_halt: bool) {
+        self.no_halt = no_halt;
+    }
+
+    /// Set how many times a corrupted memory dump is re-read before failing
+    pub fn set_read_retries(&mut self, retries: usize) {
+        self.read_retries = retries;
+    }
 
-    let mut buffer = Vec::new();
-    buffer.resize(65, 0);
-    port.read_exact(&mut buffer)?;
-    let lines = buffer.split(|i| *i == b'\n');
-    for line in lines {
-        for i in line {
-            print!("{}", *i as char);
+    /// Set the expected memory dump header, e.g. for non-mainline cores
+    pub fn set_dump_header(&mut self, header: DumpHeader) {
+        self.dump_header = header;
+    }
+
+    /// Start recording time spent in each protocol step
+    ///
+    /// Returns a shared handle from which the breakdown can be printed
+    /// once the serial operations are done.
+    pub fn enable_timing(&mut self) -> Arc<Mutex<Timing>> {
+        let timing = Arc::new(Mutex::new(Timing::default()));
+        self.timing = Some(timing.clone());
+        timing
+    }
+
+    /// Add time since `start` to the given step, if tracing
+    fn record(&mut self, step: &'static str, start: Instant) {
+        if let Some(timing) = &self.timing {
+            if let Ok(mut timing) = timing.lock() {
+                timing.record(step, start.elapsed());
+            }
         }
     }
-    println!();
-    Ok(())
-}
 
-/// Load memory from MEGA65 starting at given address
-pub fn read_memory<T: Read + Write>(port: &mut T, address: u32, length: usize) -> Result<Vec<u8>> {
-    debug!("Loading {} bytes from 0x{:x}", length, address);
-    flush_monitor(port)?;
-    stop_cpu(port)?;
-    // request memory dump (MEMORY, "M" command)
-    port.write_all(format!("m{:07x}\r", address).as_bytes())?;
-    thread::sleep(DELAY_WRITE);
-
-    let mut buffer = Vec::new();
-    let mut bytes = Vec::new();
-    bytes.reserve(length);
-
-    // skip header
-    buffer.resize(27, 0);
-    port.read_exact(&mut buffer)?;
-
-    while bytes.len() < length {
-        // load 16 two-letter byte codes
-        buffer.resize(16 * 2, 0);
-        port.read_exact(&mut buffer)?;
-        // convert two-letter codes to bytes
-        let mut sixteen_bytes: Vec<u8> = Vec::from_hex(&buffer)?;
-        bytes.append(&mut sixteen_bytes);
-        // trigger next memory dump and ignore header
-        port.write_all("m\r".as_bytes())?;
-        thread::sleep(DELAY_WRITE);
-        buffer.resize(18, 0);
-        port.read_exact(&mut buffer)?;
-    }
-    bytes.truncate(length);
-    start_cpu(port)?;
-    Ok(bytes)
-}
+    /// True if the current operation should be aborted
+    fn is_cancelled(&self) -> bool {
+        matches!(&self.cancel, Some(token) if token.is_cancelled())
+    }
 
-/// Read single byte from MEGA65
-pub fn peek<T: Read + Write>(port: &mut T, address: u32) -> Result<u8> {
-    let bytes = read_memory(port, address, 1)?;
-    Ok(bytes[0])
-}
+    /// Write all bytes to the serial port
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Matrix65Error::Cancelled);
+        }
+        self.send(bytes)
+    }
 
-/// Try to empty the monitor by reading one byte until nothing more can be read
-///
-/// There must be more elegant ways to do this...
-pub fn flush_monitor<T: Read + Write>(port: &mut T) -> Result<()> {
-    port.write_all(&[0x15, b'#', b'\r'])?;
-    let mut byte = [0u8];
-    loop {
-        thread::sleep(DELAY_WRITE);
-        match port.read_exact(&mut byte) {
-            Ok(()) => continue,
-            Err(_) => break,
-        }
-    }
-    Ok(())
-}
+    /// Write all bytes to the serial port, also after cancellation
+    fn send(&mut self, bytes: &[u8]) -> Result<()> {
+        let start = Instant::now();
+        self.port.write_all(bytes)?;
+        self.port.flush()?;
+        trace!("sent {} byte(s): {}", bytes.len(), bytes.escape_ascii());
+        self.record("write", start);
+        Ok(())
+    }
 
-/// Write bytes to MEGA65
-pub fn write_memory<T: Read + Write>(port: &mut T, address: u16, bytes: &[u8]) -> Result<()> {
-    debug!("Writing {} byte(s) to address 0x{:x}", bytes.len(), address);
-    stop_cpu(port)?;
-    port.write_all(format!("l{:x} {:x}\r", address, address + bytes.len() as u16).as_bytes())?;
-    thread::sleep(DELAY_WRITE);
-    port.write_all(bytes)?;
-    thread::sleep(DELAY_WRITE);
-    start_cpu(port)?;
-    Ok(())
-}
+    /// Write memory payload, paced according to the flow control setting
+    fn write_payload(&mut self, bytes: &[u8]) -> Result<()> {
+        match self.flow_control {
+            FlowControl::Software => {
+                for burst in bytes.chunks(FLOW_BURST_SIZE) {
+                    self.write(burst)?;
+                    self.sleep(FLOW_BURST_PAUSE);
+                }
+                Ok(())
+            }
+            _ => self.write(bytes),
+        }
+    }
 
-/// Write single byte to MEGA65
-pub fn poke<T: Read + Write>(port: &mut T, destination: u16, value: u8) -> Result<()> {
-    write_memory(port, destination, &[value])
-}
+    /// Read exactly enough bytes to fill the buffer
+    ///
+    /// Waits up to `REPLY_TIMEOUT` for the bytes to arrive, so replies
+    /// can be read right after sending a command without a fixed delay.
+    fn read_exact(&mut self, buffer: &mut [u8]) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Matrix65Error::Cancelled);
+        }
+        let start = Instant::now();
+        let mut filled = 0;
+        let result = loop {
+            if filled == buffer.len() {
+                break Ok(());
+            }
+            let received = match self.port.read(&mut buffer[filled..]) {
+                Ok(n) => n,
+                Err(err) if err.kind() == ErrorKind::TimedOut => 0,
+                Err(err) => break Err(err.into()),
+            };
+            filled += received;
+            if received == 0 && (start.elapsed() > REPLY_TIMEOUT || self.is_cancelled()) {
+                break Err(Matrix65Error::Serial(format!(
+                    "timeout after receiving {} of {} expected bytes",
+                    filled,
+                    buffer.len()
+                )));
+            }
+        };
+        if filled > 0 {
+            trace!(
+                "received {} byte(s): {}",
+                filled,
+                buffer[..filled].escape_ascii()
+            );
+        }
+        self.record("read", start);
+        result
+    }
 
-/// Transfer to MEGA65 and optionally run PRG
-///
-/// C64/C65 modes are selected from the load address
-pub fn handle_prg_from_bytes<T: Read + Write>(
-    port: &mut T,
-    bytes: &[u8],
-    load_address: LoadAddress,
-    reset_before_run: bool,
-    run: bool,
-) -> Result<()> {
-    if reset_before_run {
-        reset(port)?;
-    }
-    match load_address {
-        LoadAddress::Commodore65 => go65(port)?,
-        LoadAddress::Commodore64 => go64(port)?,
-        _ => {
-            return Err(anyhow::Error::msg("unsupported load address"));
-        }
-    }
-    write_memory(port, load_address.value(), bytes)?;
-    if run {
-        type_text(port, "run\r")?;
-    }
-    Ok(())
+    /// Read whatever is available, up to the buffer size
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        if self.is_cancelled() {
+            return Err(std::io::Error::new(
+                ErrorKind::Interrupted,
+                "operation cancelled",
+            ));
+        }
+        let start = Instant::now();
+        let result = self.port.read(buffer);
+        if let Ok(received) = result {
+            trace!(
+                "received {} byte(s): {}",
+                received,
+                buffer[..received].escape_ascii()
+            );
+        }
+        self.record("read", start);
+        result
+    }
+
+    /// Wait for the MEGA65 to process a command
+    ///
+    /// Long delays are split up so that cancellation is noticed early.
+    fn sleep(&mut self, delay: Duration) {
+        let start = Instant::now();
+        while !self.is_cancelled() && start.elapsed() < delay {
+            thread::sleep(
+                delay
+                    .saturating_sub(start.elapsed())
+                    .min(CANCEL_POLL_INTERVAL),
+            );
+        }
+        self.record("sleep", start);
+    }
+
+    /// Open serial port and create communicator
+    pub fn open(name: &str, baud_rate: u32) -> Result<Self> {
+        Ok(Self::new(open_port(name, baud_rate)?))
+    }
+
+    /// Monitor features; probed on first call
+    pub fn capabilities(&mut self) -> Result<Capabilities> {
+        if let Some(capabilities) = self.capabilities {
+            return Ok(capabilities);
+        }
+        debug!("Probing monitor capabilities");
+        self.flush()?;
+        let running_read = self.read_page(0).is_ok();
+        self.flush()?;
+        self.stop_cpu()?;
+        let page_dump = running_read || self.read_page(0).is_ok();
+        self.start_cpu()?;
+        self.flush()?;
+        let capabilities = Capabilities {
+            page_dump,
+            running_read,
+        };
+        debug!("Detected {:?}", capabilities);
+        self.capabilities = Some(capabilities);
+        Ok(capabilities)
+    }
+
+    /// Translate and type a single letter on MEGA65
+    ///
+    /// Fails with `Matrix65Error::UnsupportedKeys` if there is no key for the letter.
+    fn type_key(&mut self, key: char) -> Result<()> {
+        let (c1, c2) = match key_codes(key) {
+            NO_KEY => return Err(Matrix65Error::UnsupportedKeys(vec![key])),
+            codes => codes,
+        };
+        self.write(format!("sffd3615 {:02x} {:02x}\n", c1, c2).as_bytes())?;
+        self.sleep(self.key_delay);
+        Ok(())
+    }
+
+    /// Call this when done typing
+    fn stop_typing(&mut self) -> Result<()> {
+        self.write("sffd3615 7f 7f 7f \n".as_bytes())?;
+        self.sleep(DELAY_WRITE);
+        Ok(())
+    }
+
+    /// Run `operation` with the CPU stopped if `halt` is set
+    ///
+    /// The CPU is started again also when the operation fails, e.g.
+    /// on timeouts, monitor errors, or cancellation; the error of the
+    /// operation takes precedence.
+    fn with_cpu_halted<T>(
+        &mut self,
+        halt: bool,
+        operation: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        if !halt {
+            return operation(self);
+        }
+        self.stop_cpu()?;
+        let result = operation(self);
+        let restarted = self.start_cpu();
+        let value = result?;
+        restarted?;
+        Ok(value)
+    }
+
+    /// Load memory 16 bytes at a time using the `m` command
+    fn read_memory_lines(
+        &mut self,
+        address: u32,
+        length: usize,
+        out: &mut dyn Write,
+    ) -> Result<()> {
+        self.flush()?;
+        // request memory dump (MEMORY, "M" command)
+        self.write(format!("m{:07x}\r", address).as_bytes())?;
+
+        let mut buffer = vec![0; DUMP_LINE_LENGTH * 2];
+        let mut written = 0;
+
+        self.skip_dump_header(true)?;
+
+        while written < length {
+            let more = length - written > DUMP_LINE_LENGTH;
+            let mut retries = 0;
+            let sixteen_bytes: Vec<u8> = loop {
+                // load 16 two-letter byte codes
+                self.read_exact(&mut buffer)?;
+                // request the next line while this one is decoded
+                if more {
+                    self.write("m\r".as_bytes())?;
+                }
+                // convert two-letter codes to bytes
+                match Vec::from_hex(&buffer) {
+                    Ok(bytes) => break bytes,
+                    Err(err) => {
+                        let mut reply = buffer.clone();
+                        reply.append(&mut self.read_pending()?);
+                        check_monitor_reply(&reply)?;
+                        if retries == self.read_retries {
+                            return Err(err.into());
+                        }
+                        retries += 1;
+                        // request the same line again
+                        let line_address = address + written as u32;
+                        debug!(
+                            "Corrupted memory dump at 0x{:07x}; retry {} of {}",
+                            line_address, retries, self.read_retries
+                        );
+                        self.flush()?;
+                        self.write(format!("m{:07x}\r", line_address).as_bytes())?;
+                        self.skip_dump_header(true)?;
+                    }
+                }
+            };
+            let count = sixteen_bytes.len().min(length - written);
+            out.write_all(&sixteen_bytes[..count])?;
+            written += count;
+            if more {
+                self.skip_dump_header(false)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Skip a number of header bytes, checking them for errors
+    fn skip_bytes(&mut self, length: usize) -> Result<()> {
+        let mut header = vec![0; length];
+        let result = self.read_exact(&mut header);
+        check_monitor_reply(&header)?;
+        result
+    }
+
+    /// Read whatever the monitor has sent so far without waiting
+    fn read_pending(&mut self) -> Result<Vec<u8>> {
+        let mut reply = vec![0; self.port.bytes_to_read()? as usize];
+        self.read_exact(&mut reply)?;
+        Ok(reply)
+    }
+
+    /// Skip echo and header preceding the hex digits of a memory dump
+    ///
+    /// `first` is true for the initial request and false for
+    /// continuation requests.
+    fn skip_dump_header(&mut self, first: bool) -> Result<()> {
+        match self.dump_header.clone() {
+            DumpHeader::Fixed { first: length, .. } if first => self.skip_bytes(length),
+            DumpHeader::Fixed { next: length, .. } => self.skip_bytes(length),
+            DumpHeader::Pattern(pattern) => {
+                let mut received = Vec::new();
+                let mut byte = [0u8];
+                while !ends_with_pattern(&received, &pattern) {
+                    if received.len() > MAX_HEADER_LENGTH {
+                        return Err(Matrix65Error::Serial(format!(
+                            "monitor reply does not match header pattern '{}'",
+                            pattern
+                        )));
+                    }
+                    self.read_exact(&mut byte)?;
+                    received.push(byte[0]);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Load memory 256 bytes at a time using the `M` command
+    fn read_memory_pages(
+        &mut self,
+        address: u32,
+        length: usize,
+        out: &mut dyn Write,
+    ) -> Result<()> {
+        self.flush()?;
+        let mut written = 0;
+        while written < length {
+            let page_address = address + written as u32;
+            let mut retries = 0;
+            let page = loop {
+                match self.read_page(page_address) {
+                    Ok(page) => break page,
+                    // errors reported by the monitor will not go away by retrying
+                    Err(err @ Matrix65Error::MonitorError(_)) => return Err(err),
+                    Err(err) if retries == self.read_retries || self.is_cancelled() => {
+                        return Err(err)
+                    }
+                    Err(err) => {
+                        retries += 1;
+                        debug!(
+                            "Failed page dump at 0x{:07x} ({}); retry {} of {}",
+                            page_address, err, retries, self.read_retries
+                        );
+                        self.flush()?;
+                    }
+                }
+            };
+            let count = page.len().min(length - written);
+            out.write_all(&page[..count])?;
+            written += count;
+        }
+        Ok(())
+    }
+
+    /// Check once whether the MEGA65 is ready after a reset
+    ///
+    /// With `running_read`, BASIC must show its prompt; otherwise the
+    /// monitor must answer the `r` command.
+    fn is_ready(&mut self, running_read: bool) -> Result<bool> {
+        if !running_read {
+            return self.read_registers().map(|_| true);
+        }
+        let mut screen = Vec::new();
+        self.read_memory_pages(BOOT_SCREEN, BOOT_SCREEN_SIZE, &mut screen)?;
+        Ok(screen
+            .windows(READY_PROMPT.len())
+            .any(|w| w == READY_PROMPT))
+    }
+
+    /// Request and parse a single 256 byte page dump
+    ///
+    /// The reply is read in chunks as it arrives and split into
+    /// lines; each dump line must continue where the previous one
+    /// ended. Unless the monitor supports reading from a running
+    /// system, this requires the CPU to be stopped.
+    fn read_page(&mut self, address: u32) -> Result<Vec<u8>> {
+        self.write(format!("M{:07x}\r", address).as_bytes())?;
+        let deadline = Instant::now() + PAGE_DUMP_TIMEOUT;
+        let mut pending = Vec::new();
+        let mut chunk = [0u8; 512];
+        let mut page = Vec::with_capacity(DUMP_LINE_LENGTH * DUMP_PAGE_LINES);
+        while page.len() < DUMP_LINE_LENGTH * DUMP_PAGE_LINES {
+            if Instant::now() > deadline {
+                return Err(Matrix65Error::Serial(
+                    "timeout waiting for memory page dump".to_string(),
+                ));
+            }
+            match self.read(&mut chunk) {
+                Ok(n) => pending.extend_from_slice(&chunk[..n]),
+                Err(err) if err.kind() == ErrorKind::TimedOut => continue,
+                Err(err) => return Err(err.into()),
+            }
+            while let Some(end) = pending.iter().position(|c| *c == b'\n' || *c == b'\r') {
+                let line: Vec<u8> = pending.drain(..=end).collect();
+                if let Some((line_address, mut bytes)) = parse_dump_line(&line[..end]) {
+                    if line_address != address + page.len() as u32 {
+                        return Err(Matrix65Error::Serial(
+                            "unexpected address in memory page dump".to_string(),
+                        ));
+                    }
+                    page.append(&mut bytes);
+                } else {
+                    check_monitor_reply(&line)?;
+                }
+            }
+        }
+        Ok(page)
+    }
 }
 
-/// Transfers and optionally run PRG to MEGA65
-///
-/// Here `file` can be a local file or a url. CBM disk images are allowed and
-/// C64/C65 modes are detected from load address.
-pub fn handle_prg<T: Read + Write>(
-    port: &mut T,
-    file: &str,
-    reset_before_run: bool,
-    run: bool,
-) -> Result<()> {
-    let (load_address, bytes) = io::load_prg(file)?;
-    handle_prg_from_bytes(port, &bytes, load_address, reset_before_run, run)
+impl M65Communicator for M65Serial {
+    /// Reset the MEGA65 and wait until it is ready
+    ///
+    /// If the monitor can read from a running system, the screen is
+    /// polled for the BASIC "READY." prompt, so that booting is not
+    /// disturbed by halting the CPU. Other monitors are polled with the
+    /// `r` command until they answer. Fails if the MEGA65 is not ready
+    /// within `RESET_TIMEOUT`.
+    ///
+    /// Example with a monitor showing the prompt at the top of the screen:
+    /// ~~~
+    /// use matrix65::serial::M65Serial;
+    /// use matrix65::test_support::MockSerialPort;
+    /// use matrix65::M65Communicator;
+    /// let port = MockSerialPort::new(|command| {
+    ///     let address = match command.strip_prefix(b"M") {
+    ///         Some(hex) => u32::from_str_radix(std::str::from_utf8(hex).unwrap(), 16).unwrap(),
+    ///         None => return Vec::new(),
+    ///     };
+    ///     let mut reply = String::new();
+    ///     for line in 0..16 {
+    ///         let line_address = address + line * 16;
+    ///         let mut bytes = [0x20u8; 16];
+    ///         if line_address == 0x0800 {
+    ///             bytes[..6].copy_from_slice(&[0x12, 0x05, 0x01, 0x04, 0x19, 0x2e]);
+    ///         }
+    ///         reply += &format!(":{:08X}:{}\r\n", line_address, hex::encode_upper(bytes));
+    ///     }
+    ///     reply.into_bytes()
+    /// });
+    /// let mut serial = M65Serial::new(Box::new(port.clone()));
+    /// serial.reset().unwrap();
+    /// // the CPU is not halted after the reset
+    /// let written = port.written();
+    /// assert!(!written[written.find('!').unwrap()..].contains("t1"));
+    /// ~~~
+    ///
+    /// Example with a monitor that cannot dump memory but shows registers:
+    /// ~~~
+    /// use matrix65::serial::M65Serial;
+    /// use matrix65::test_support::MockSerialPort;
+    /// use matrix65::M65Communicator;
+    /// let port = MockSerialPort::new(|command| match command.first() {
+    ///     Some(b'M') => b"?\r\n".to_vec(),
+    ///     Some(b'r') => b"PC   A  X  Y  Z  B  SP   MAPH MAPL LAST-OP     P  P-FLAGS\r\n\
+    ///         ,E4FF 00 00 00 00 00 01F8 0000 0000 00 00 00    20 ..E.....\r\n".to_vec(),
+    ///     _ => Vec::new(),
+    /// });
+    /// let mut serial = M65Serial::new(Box::new(port.clone()));
+    /// serial.reset().unwrap();
+    /// let written = port.written();
+    /// assert!(written[written.find('!').unwrap()..].contains("r\r"));
+    /// ~~~
+    fn reset(&mut self) -> Result<()> {
+        // probed before the reset, as the monitor does not answer while booting
+        let running_read = match self.capabilities() {
+            Ok(capabilities) => capabilities.running_read,
+            Err(err) => {
+                debug!("Cannot probe monitor before reset: {}", err);
+                false
+            }
+        };
+        debug!("Sending RESET signal");
+        self.write("!\n".as_bytes())?;
+        let deadline = Instant::now() + RESET_TIMEOUT;
+        self.sleep(RESET_SETTLE);
+        loop {
+            if self.is_cancelled() {
+                return Err(Matrix65Error::Cancelled);
+            }
+            match self.is_ready(running_read) {
+                Ok(true) => {
+                    debug!("MEGA65 ready after reset");
+                    return Ok(());
+                }
+                Ok(false) => {}
+                Err(err) => {
+                    debug!("Waiting for monitor after reset: {}", err);
+                    self.flush()?;
+                }
+            }
+            if Instant::now() > deadline {
+                return Err(Matrix65Error::Serial(format!(
+                    "MEGA65 not ready within {} seconds after reset",
+                    RESET_TIMEOUT.as_secs()
+                )));
+            }
+            self.sleep(RESET_POLL_INTERVAL);
+        }
+    }
+
+    /// Request help text with the `h` command
+    fn monitor_help(&mut self) -> Result<String> {
+        debug!("Requesting serial monitor help");
+        self.flush()?;
+        self.write("h\r".as_bytes())?;
+        self.sleep(HELP_TIMEOUT);
+        let reply = self.read_pending()?;
+        Ok(String::from_utf8_lossy(&reply).to_string())
+    }
+
+    /// Request registers with the `r` command
+    fn read_registers(&mut self) -> Result<Registers> {
+        debug!("Requesting CPU registers");
+        self.flush()?;
+        self.write("r\r".as_bytes())?;
+        self.sleep(HELP_TIMEOUT);
+        let reply = self.read_pending()?;
+        check_monitor_reply(&reply)?;
+        Registers::parse(&String::from_utf8_lossy(&reply))
+    }
+
+    /// In trace mode, an empty command steps one instruction and shows registers
+    fn step(&mut self) -> Result<Registers> {
+        debug!("Single-stepping CPU");
+        self.flush()?;
+        self.write("\r".as_bytes())?;
+        self.sleep(HELP_TIMEOUT);
+        let reply = self.read_pending()?;
+        check_monitor_reply(&reply)?;
+        Registers::parse(&String::from_utf8_lossy(&reply))
+    }
+
+    fn set_breakpoint(&mut self, address: u32) -> Result<()> {
+        debug!("Setting breakpoint at 0x{:x}", address);
+        self.write(format!("b{:x}\r", address).as_bytes())?;
+        self.sleep(DELAY_WRITE);
+        Ok(())
+    }
+
+    /// The `g` command sets the program counter
+    fn jump(&mut self, address: u32) -> Result<()> {
+        debug!("Jumping to 0x{:x}", address);
+        self.write(format!("g{:x}\r", address).as_bytes())?;
+        self.sleep(DELAY_WRITE);
+        self.start_cpu()
+    }
+
+    /// The `b` command without an address clears the breakpoint
+    fn clear_breakpoint(&mut self) -> Result<()> {
+        debug!("Clearing breakpoint");
+        self.write("b\r".as_bytes())?;
+        self.sleep(DELAY_WRITE);
+        Ok(())
+    }
+
+    fn poke_far(&mut self, destination: u32, value: u8) -> Result<()> {
+        self.write(format!("s{:07x} {:02x}\n", destination, value).as_bytes())?;
+        self.sleep(DELAY_WRITE);
+        Ok(())
+    }
+
+    fn warm_reset(&mut self) -> Result<()> {
+        debug!("Sending RUN/STOP + RESTORE");
+        self.write(format!("sffd3615 {:02x} {:02x}\n", KEY_RUN_STOP, KEY_RESTORE).as_bytes())?;
+        self.sleep(DELAY_WARM_RESET);
+        self.stop_typing()
+    }
+
+    /// Cancel any pending command line, then drain replies until the monitor goes quiet
+    ///
+    /// The monitor is considered quiet when two reads, `DELAY_WRITE` apart,
+    /// time out. Fails if it keeps sending beyond a time and byte budget.
+    fn flush(&mut self) -> Result<usize> {
+        self.write(&[0x15, b'#', b'\r'])?;
+        self.sleep(DELAY_WRITE);
+        let deadline = Instant::now() + FLUSH_TIMEOUT;
+        let mut buffer = [0u8; 512];
+        let mut drained = 0;
+        let mut quiet = false;
+        loop {
+            let received = match self.read(&mut buffer) {
+                Ok(n) => n,
+                Err(err) if err.kind() == ErrorKind::TimedOut => 0,
+                Err(err) => return Err(err.into()),
+            };
+            if received > 0 {
+                drained += received;
+                quiet = false;
+            } else if quiet {
+                break;
+            } else {
+                quiet = true;
+                self.sleep(DELAY_WRITE);
+            }
+            if drained > FLUSH_MAX_BYTES || Instant::now() > deadline {
+                return Err(Matrix65Error::Serial(format!(
+                    "monitor keeps sending; gave up flushing after {} bytes",
+                    drained
+                )));
+            }
+        }
+        debug!("Flushed {} byte(s) from monitor", drained);
+        Ok(drained)
+    }
+
+    fn set_key_delay(&mut self, delay: Duration) {
+        self.key_delay = delay;
+    }
+
+    fn set_cancel_token(&mut self, token: CancelToken) {
+        self.cancel = Some(token);
+    }
+
+    fn stop_cpu(&mut self) -> Result<()> {
+        let start = Instant::now();
+        self.write(b"t1\r")?;
+        self.sleep(DELAY_WRITE);
+        self.record("stop_cpu", start);
+        Ok(())
+    }
+
+    /// Also sent after cancellation, so that an aborted operation does
+    /// not leave the CPU halted.
+    ///
+    /// Example:
+    /// ~~~
+    /// use matrix65::serial::M65Serial;
+    /// use matrix65::test_support::MockSerialPort;
+    /// use matrix65::{CancelToken, M65Communicator};
+    /// let port = MockSerialPort::new(|_| Vec::new());
+    /// let mut serial = M65Serial::new(Box::new(port.clone()));
+    /// let token = CancelToken::default();
+    /// serial.set_cancel_token(token.clone());
+    /// token.cancel();
+    /// assert!(serial.stop_cpu().is_err());
+    /// serial.start_cpu().unwrap();
+    /// assert_eq!(port.written(), "t0\r");
+    /// ~~~
+    fn start_cpu(&mut self) -> Result<()> {
+        let start = Instant::now();
+        self.send(b"t0\r")?;
+        self.sleep(DELAY_WRITE);
+        self.record("start_cpu", start);
+        Ok(())
+    }
+
+    fn type_text(&mut self, text: &str) -> Result<()> {
+        // Manually translate user defined escape codes:
+        // https://stackoverflow.com/questions/72583983/interpreting-escape-characters-in-a-string-read-from-user-input
+        debug!("Typing text");
+        self.sleep(self.key_delay);
+        let mut unsupported = Vec::new();
+        for key in text.replace("\\r", "\r").replace("\\n", "\r").chars() {
+            if let Err(err) = self.type_key(key) {
+                match err {
+                    Matrix65Error::UnsupportedKeys(keys) => unsupported.extend(keys),
+                    err => return Err(err),
+                }
+            }
+        }
+        self.stop_typing()?;
+        check_typed(unsupported)
+    }
+
+    /// Load memory from MEGA65 starting at given address
+    ///
+    /// Larger regions are fetched a page at a time if the monitor
+    /// supports it, falling back to 16 bytes per request. The CPU is
+    /// halted during the read unless `no_halt` is set and supported.
+    /// Replies are always hex text: unlike `l` for writing, the monitor
+    /// has no command that sends memory as raw binary.
+    fn read_memory_to_writer(
+        &mut self,
+        address: u32,
+        length: usize,
+        out: &mut dyn Write,
+    ) -> Result<()> {
+        debug!("Loading {} bytes from 0x{:x}", length, address);
+        let halt = !(self.no_halt && self.capabilities()?.running_read);
+        if self.no_halt && halt {
+            warn!("Monitor cannot read from a running CPU; halting instead");
+        }
+        let page_dump =
+            self.page_dump && length > DUMP_LINE_LENGTH && self.capabilities()?.page_dump;
+        self.with_cpu_halted(halt, |serial| match page_dump {
+            true => serial.read_memory_pages(address, length, out),
+            false => serial.read_memory_lines(address, length, out),
+        })
+    }
+
+    /// Example with a monitor that only has the `m` command:
+    /// ~~~
+    /// use matrix65::serial::M65Serial;
+    /// use matrix65::test_support::MockSerialPort;
+    /// use matrix65::M65Communicator;
+    /// let port = MockSerialPort::new(|command| match command.first() {
+    ///     Some(b'M') => b"?\r\n".to_vec(),
+    ///     _ => Vec::new(),
+    /// });
+    /// let mut serial = M65Serial::new(Box::new(port));
+    /// assert!(serial.set_page_dump(true).is_err());
+    /// assert!(serial.set_page_dump(false).is_ok());
+    /// ~~~
+    fn set_page_dump(&mut self, enabled: bool) -> Result<()> {
+        if enabled && !self.capabilities()?.page_dump {
+            return Err(Matrix65Error::Unsupported(
+                "page dumps are not supported by this monitor",
+            ));
+        }
+        self.page_dump = enabled;
+        Ok(())
+    }
+
+    fn write_memory_far(&mut self, address: u32, bytes: &[u8]) -> Result<()> {
+        debug!("Writing {} byte(s) to address 0x{:x}", bytes.len(), address);
+        self.with_cpu_halted(true, |serial| {
+            let end = address + bytes.len() as u32;
+            serial.write(format!("l{:x} {:x}\r", address, end).as_bytes())?;
+            serial.sleep(DELAY_WRITE);
+            // a rejected load command would make the monitor interpret the payload as commands
+            let reply = serial.read_pending()?;
+            check_monitor_reply(&reply)?;
+            serial.write_payload(bytes)?;
+            serial.sleep(DELAY_WRITE);
+            Ok(())
+        })
+    }
 }
@@ -0,0 +1,244 @@
+// copyright 2022 mikael lund aka wombat
+//
+// licensed under the apache license, version 2.0 (the "license");
+// you may not use this file except in compliance with the license.
+// you may obtain a copy of the license at
+//
+//     http://www.apache.org/licenses/license-2.0
+//
+// unless required by applicable law or agreed to in writing, software
+// distributed under the license is distributed on an "as is" basis,
+// without warranties or conditions of any kind, either express or implied.
+// see the license for the specific language governing permissions and
+// limitations under the license.
+
+//! Play PSID and RSID tunes in C64 mode
+//!
+//! The tune is uploaded to its load address together with a small
+//! driver that calls the init routine and then the play routine from
+//! a raster interrupt, before returning to BASIC.
+
+use crate::{M65Communicator, Matrix65Error, Result};
+use std::fmt;
+
+/// Driver code is placed in the cassette buffer
+pub const DRIVER_ADDRESS: u16 = 0x033c;
+
+/// Size of the cassette buffer available for the driver
+const DRIVER_SPACE: usize = 192;
+
+/// Size of the header of version 1 files; later versions are longer
+const HEADER_SIZE_V1: usize = 0x76;
+
+/// Length of the name, author, and released fields
+const STRING_SIZE: usize = 32;
+
+/// Raster line at which the play routine is called
+const RASTER_LINE: u8 = 0;
+
+/// PSID or RSID tune with its header information
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SidTune {
+    /// RSID tunes require a real C64 environment and set up their own interrupts
+    pub rsid: bool,
+    pub load_address: u16,
+    pub init_address: u16,
+    /// Zero if the init routine installs its own interrupt handler
+    pub play_address: u16,
+    /// Number of songs in the tune
+    pub songs: u16,
+    /// Default song, starting from 1
+    pub start_song: u16,
+    pub name: String,
+    pub author: String,
+    pub released: String,
+    /// Tune data without the load address
+    pub data: Vec<u8>,
+}
+
+impl SidTune {
+    /// Parse PSID or RSID file contents
+    ///
+    /// Example:
+    /// ~~~
+    /// use matrix65::sid::SidTune;
+    /// let mut bytes = vec![0u8; 0x7c];
+    /// bytes[..4].copy_from_slice(b"PSID");
+    /// bytes[0x05] = 2; // version
+    /// bytes[0x07] = 0x7c; // data offset
+    /// bytes[0x0c..0x0e].copy_from_slice(&[0x10, 0x03]); // play at $1003
+    /// bytes[0x0f] = 3; // songs
+    /// bytes[0x11] = 2; // start song
+    /// bytes[0x16..0x1b].copy_from_slice(b"Tune!");
+    /// bytes.extend_from_slice(&[0x00, 0x10, 0x60, 0x60, 0x60, 0x60]); // $1000 and code
+    /// let tune = SidTune::parse(&bytes).unwrap();
+    /// assert!(!tune.rsid);
+    /// assert_eq!(tune.name, "Tune!");
+    /// assert_eq!((tune.load_address, tune.init_address, tune.play_address), (0x1000, 0x1000, 0x1003));
+    /// assert_eq!((tune.songs, tune.start_song), (3, 2));
+    /// assert_eq!(tune.data.len(), 4);
+    /// assert!(SidTune::parse(b"PRG").is_err());
+    /// ~~~
+    pub fn parse(bytes: &[u8]) -> Result<SidTune> {
+        let parse_error = |message: &str| Matrix65Error::Parse(message.to_string());
+        if bytes.len() < HEADER_SIZE_V1 {
+            return Err(parse_error("too few bytes for a SID header"));
+        }
+        let rsid = match &bytes[..4] {
+            b"PSID" => false,
+            b"RSID" => true,
+            _ => return Err(parse_error("not a PSID or RSID file")),
+        };
+        let word = |offset: usize| u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+        let text = |offset: usize| {
+            let field = &bytes[offset..offset + STRING_SIZE];
+            let length = field.iter().position(|c| *c == 0).unwrap_or(STRING_SIZE);
+            String::from_utf8_lossy(&field[..length]).trim().to_string()
+        };
+        let mut data = bytes
+            .get(word(0x06) as usize..)
+            .ok_or_else(|| parse_error("SID data offset beyond end of file"))?
+            .to_vec();
+        let load_address = match word(0x08) {
+            0 if data.len() >= 2 => u16::from_le_bytes([data.remove(0), data.remove(0)]),
+            0 => return Err(parse_error("too few bytes for a load address")),
+            address => address,
+        };
+        if load_address as usize + data.len() > 0x10000 {
+            return Err(parse_error("SID data extends beyond 64 KB"));
+        }
+        let init_address = match word(0x0a) {
+            0 => load_address,
+            address => address,
+        };
+        Ok(SidTune {
+            rsid,
+            load_address,
+            init_address,
+            play_address: if rsid { 0 } else { word(0x0c) },
+            songs: word(0x0e).max(1),
+            start_song: word(0x10).max(1),
+            name: text(0x16),
+            author: text(0x36),
+            released: text(0x56),
+            data,
+        })
+    }
+
+    /// Machine code that starts `song` (from 1) when placed at `address`
+    ///
+    /// Calls the init routine with interrupts disabled, hooks the play
+    /// routine into a raster interrupt via the KERNAL vector at $0314, and
+    /// returns to BASIC with a warm start.
+    ///
+    /// Example:
+    /// ~~~
+    /// use matrix65::sid::SidTune;
+    /// let mut tune = SidTune {
+    ///     rsid: false, load_address: 0x1000, init_address: 0x1000, play_address: 0x1003,
+    ///     songs: 2, start_song: 1, name: String::new(), author: String::new(),
+    ///     released: String::new(), data: vec![0x60; 6],
+    /// };
+    /// let code = tune.driver(0x033c, 2).unwrap();
+    /// assert_eq!(code[..6], [0x78, 0xa9, 0x01, 0x20, 0x00, 0x10]); // sei, lda #1, jsr $1000
+    /// assert_eq!(code[code.len() - 3..], [0x4c, 0x31, 0xea]); // jmp $ea31
+    /// assert!(tune.driver(0x033c, 3).is_err());
+    ///
+    /// // the init routine installs its own interrupt
+    /// tune.play_address = 0;
+    /// assert_eq!(tune.driver(0x033c, 1).unwrap(), [0x78, 0xa9, 0x00, 0x20, 0x00, 0x10, 0x58, 0x6c, 0x02, 0xa0]);
+    /// ~~~
+    pub fn driver(&self, address: u16, song: u16) -> Result<Vec<u8>> {
+        if song == 0 || song > self.songs {
+            return Err(Matrix65Error::Invalid(format!(
+                "song {} not found; the tune has songs 1-{}",
+                song, self.songs
+            )));
+        }
+        let [init_lo, init_hi] = self.init_address.to_le_bytes();
+        let init: &[&[u8]] = &[
+            &[0x78],                   // sei
+            &[0xa9, (song - 1) as u8], // lda #song
+            &[0x20, init_lo, init_hi], // jsr init
+        ];
+        let raster: &[&[u8]] = &[
+            &[0xa9, 0x7f],        // lda #$7f
+            &[0x8d, 0x0d, 0xdc],  // sta $dc0d: no CIA interrupts
+            &[0xad, 0x0d, 0xdc],  // lda $dc0d: acknowledge pending CIA interrupt
+            &[0xa9, 0x01],        // lda #$01
+            &[0x8d, 0x1a, 0xd0],  // sta $d01a: raster interrupt
+            &[0x8d, 0x19, 0xd0],  // sta $d019: acknowledge
+            &[0xa9, RASTER_LINE], // lda #line
+            &[0x8d, 0x12, 0xd0],  // sta $d012
+            &[0xad, 0x11, 0xd0],  // lda $d011
+            &[0x29, 0x7f],        // and #$7f
+            &[0x8d, 0x11, 0xd0],  // sta $d011: raster line bit 8
+        ];
+        let finish: &[&[u8]] = &[
+            &[0x58],             // cli
+            &[0x6c, 0x02, 0xa0], // jmp ($a002): BASIC warm start
+        ];
+        let mut code = init.concat();
+        if self.play_address == 0 {
+            code.extend(finish.concat());
+            return Ok(code);
+        }
+        code.extend(raster.concat());
+        // the interrupt handler follows 10 bytes of vector setup and the return to BASIC
+        let irq = address + code.len() as u16 + 10 + finish.concat().len() as u16;
+        let [irq_lo, irq_hi] = irq.to_le_bytes();
+        let [play_lo, play_hi] = self.play_address.to_le_bytes();
+        let vector_and_handler: &[&[u8]] = &[
+            &[0xa9, irq_lo],           // lda #<irq
+            &[0x8d, 0x14, 0x03],       // sta $0314
+            &[0xa9, irq_hi],           // lda #>irq
+            &[0x8d, 0x15, 0x03],       // sta $0315
+            &finish.concat(),          // cli, return to BASIC
+            &[0xa9, 0x01],             // irq: lda #$01
+            &[0x8d, 0x19, 0xd0],       // sta $d019: acknowledge
+            &[0x20, play_lo, play_hi], // jsr play
+            &[0x4c, 0x31, 0xea],       // jmp $ea31: KERNAL interrupt handler
+        ];
+        code.extend(vector_and_handler.concat());
+        Ok(code)
+    }
+
+    /// Go to C64 mode, upload tune and driver, and start `song` (from 1)
+    ///
+    /// The driver lives in the cassette buffer, so tunes overlapping
+    /// it cannot be played.
+    pub fn play(&self, comm: &mut dyn M65Communicator, song: u16) -> Result<()> {
+        let driver = self.driver(DRIVER_ADDRESS, song)?;
+        let tune = self.load_address as usize..self.load_address as usize + self.data.len();
+        let cassette = DRIVER_ADDRESS as usize..DRIVER_ADDRESS as usize + DRIVER_SPACE;
+        if tune.start < cassette.end && cassette.start < tune.end {
+            return Err(Matrix65Error::Invalid(format!(
+                "tune at 0x{:04x}-0x{:04x} overlaps the player in the cassette buffer",
+                tune.start,
+                tune.end - 1
+            )));
+        }
+        comm.go64()?;
+        comm.write_memory(self.load_address, &self.data)?;
+        comm.write_memory(DRIVER_ADDRESS, &driver)?;
+        comm.jump(DRIVER_ADDRESS as u32)
+    }
+}
+
+impl fmt::Display for SidTune {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Name:      {}", self.name)?;
+        writeln!(f, "Author:    {}", self.author)?;
+        writeln!(f, "Released:  {}", self.released)?;
+        writeln!(f, "Songs:     {} (default {})", self.songs, self.start_song)?;
+        write!(
+            f,
+            "Addresses: load 0x{:04x}, init 0x{:04x}, play ",
+            self.load_address, self.init_address
+        )?;
+        match self.play_address {
+            0 => write!(f, "from the tune's own interrupt"),
+            address => write!(f, "0x{:04x}", address),
+        }
+    }
+}
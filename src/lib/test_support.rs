@@ -0,0 +1,340 @@
+// copyright 2022 mikael lund aka wombat
+//
+// licensed under the apache license, version 2.0 (the "license");
+// you may not use this file except in compliance with the license.
+// you may obtain a copy of the license at
+//
+//     http://www.apache.org/licenses/license-2.0
+//
+// unless required by applicable law or agreed to in writing, software
+// distributed under the license is distributed on an "as is" basis,
+// without warranties or conditions of any kind, either express or implied.
+// see the license for the specific language governing permissions and
+// limitations under the license.
+
+//! Helpers for testing code that talks to the MEGA65, without a MEGA65
+//!
+//! Only available with the `testing` feature.
+
+use crate::M65Communicator;
+use crate::Result;
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Communicator backed by memory on the host, recording every call
+///
+/// Memory is a sparse 28-bit address space where unwritten bytes read
+/// as zero. Mode detection is controlled with `c65_mode`.
+///
+/// Example:
+/// ~~~
+/// use matrix65::test_support::MockCommunicator;
+/// use matrix65::{LoadAddress, M65Communicator};
+/// let mut mock = MockCommunicator::new();
+/// mock.c65_mode = true;
+/// let basic = [0x0b, 0x08, 0x0a, 0x00, 0x9e, 0x32, 0x30, 0x36, 0x31, 0x00, 0x00, 0x00];
+/// mock.handle_prg_from_bytes(&basic, LoadAddress::Commodore64, false, true, false)
+///     .unwrap();
+/// assert_eq!(mock.typed, ["go64\ry\r", "run\r"]);
+/// assert_eq!(mock.writes, [(0x0801, basic.to_vec())]);
+/// assert_eq!(mock.read_memory(0x0801, 2).unwrap(), [0x0b, 0x08]);
+///
+/// // C128 programs can be loaded, but not run
+/// assert!(mock.handle_prg_from_bytes(&basic, LoadAddress::Commodore128, false, true, false).is_err());
+/// assert!(mock.handle_prg_from_bytes(&basic, LoadAddress::Commodore128, false, false, false).is_ok());
+/// assert_eq!(mock.writes.last().unwrap().0, 0x1c01);
+///
+/// // banked programs are placed above 64 KB, but not run
+/// let banked = LoadAddress::Banked(0x40000);
+/// mock.handle_prg_from_bytes(&basic, banked, false, false, false).unwrap();
+/// assert_eq!(mock.writes.last().unwrap().0, 0x40000);
+/// assert!(mock.handle_prg_from_bytes(&basic, LoadAddress::Banked(0x40000), false, true, false).is_err());
+/// ~~~
+#[derive(Debug, Default)]
+pub struct MockCommunicator {
+    /// Sparse memory contents
+    pub memory: HashMap<u32, u8>,
+    /// Returned by `is_c65_mode()`
+    pub c65_mode: bool,
+    /// Whether the CPU is stopped
+    pub cpu_stopped: bool,
+    /// Number of calls to `reset()`
+    pub resets: usize,
+    /// Address and length of each `read_memory()` call
+    pub reads: Vec<(u32, usize)>,
+    /// Address and bytes of each `write_memory_far()` call, including
+    /// those made through `write_memory()` and `poke()`
+    pub writes: Vec<(u32, Vec<u8>)>,
+    /// Text of each `type_text()` call
+    pub typed: Vec<String>,
+    /// Address of each `jump()` call
+    pub jumps: Vec<u32>,
+}
+
+impl MockCommunicator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Place bytes in memory without recording a write
+    pub fn load(&mut self, address: u32, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.memory.insert(address + i as u32, *byte);
+        }
+    }
+}
+
+impl M65Communicator for MockCommunicator {
+    fn reset(&mut self) -> Result<()> {
+        self.resets += 1;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn jump(&mut self, address: u32) -> Result<()> {
+        self.jumps.push(address);
+        self.cpu_stopped = false;
+        Ok(())
+    }
+
+    fn stop_cpu(&mut self) -> Result<()> {
+        self.cpu_stopped = true;
+        Ok(())
+    }
+
+    fn start_cpu(&mut self) -> Result<()> {
+        self.cpu_stopped = false;
+        Ok(())
+    }
+
+    fn type_text(&mut self, text: &str) -> Result<()> {
+        self.typed.push(text.to_string());
+        Ok(())
+    }
+
+    fn read_memory_to_writer(
+        &mut self,
+        address: u32,
+        length: usize,
+        out: &mut dyn Write,
+    ) -> Result<()> {
+        self.reads.push((address, length));
+        let bytes: Vec<u8> = (0..length as u32)
+            .map(|i| self.memory.get(&(address + i)).copied().unwrap_or(0))
+            .collect();
+        Ok(out.write_all(&bytes)?)
+    }
+
+    fn write_memory_far(&mut self, address: u32, bytes: &[u8]) -> Result<()> {
+        self.writes.push((address, bytes.to_vec()));
+        self.load(address, bytes);
+        Ok(())
+    }
+
+    fn is_c65_mode(&mut self) -> Result<bool> {
+        Ok(self.c65_mode)
+    }
+}
+
+/// Answer to a monitor command line, without the line terminator
+pub type Responder = fn(&[u8]) -> Vec<u8>;
+
+/// Bytes passed through a `MockSerialPort`
+#[derive(Debug, Default)]
+struct PortTraffic {
+    written: Vec<u8>,
+    line: Vec<u8>,
+    pending: VecDeque<u8>,
+}
+
+/// Serial port that answers monitor commands with a function, recording what is written
+///
+/// Every line written, ended by a carriage return or newline, is passed to
+/// the responder and its answer becomes available for reading. Reads time
+/// out at once when nothing is pending. Clones share the recorded traffic,
+/// so keep a clone to inspect it after handing the port to `M65Serial`.
+///
+/// Example with a monitor that rejects memory dumps:
+/// ~~~
+/// use matrix65::serial::M65Serial;
+/// use matrix65::test_support::MockSerialPort;
+/// use matrix65::{M65Communicator, Matrix65Error};
+/// let port = MockSerialPort::new(|command| match command.first() {
+///     Some(b'm') | Some(b'M') => b"?\r\n".to_vec(),
+///     _ => Vec::new(),
+/// });
+/// let mut serial = M65Serial::new(Box::new(port.clone()));
+/// let err = serial.read_memory(0x1000, 4).unwrap_err();
+/// assert!(matches!(err, Matrix65Error::MonitorError(_)));
+/// // the CPU was halted for the read and started again
+/// assert!(port.written().contains("t1\r"));
+/// assert!(port.written().ends_with("t0\r"));
+/// ~~~
+#[derive(Clone)]
+pub struct MockSerialPort {
+    traffic: Arc<Mutex<PortTraffic>>,
+    responder: Responder,
+    timeout: Duration,
+}
+
+impl MockSerialPort {
+    pub fn new(responder: Responder) -> Self {
+        MockSerialPort {
+            traffic: Arc::default(),
+            responder,
+            timeout: Duration::ZERO,
+        }
+    }
+
+    /// Everything written to the port so far
+    pub fn written(&self) -> String {
+        String::from_utf8_lossy(&self.traffic.lock().unwrap().written).to_string()
+    }
+}
+
+impl Write for MockSerialPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut traffic = self.traffic.lock().unwrap();
+        traffic.written.extend_from_slice(buf);
+        for byte in buf {
+            match byte {
+                b'\r' | b'\n' => {
+                    let line = std::mem::take(&mut traffic.line);
+                    let reply = (self.responder)(&line);
+                    traffic.pending.extend(reply);
+                }
+                _ => traffic.line.push(*byte),
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for MockSerialPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut traffic = self.traffic.lock().unwrap();
+        let count = buf.len().min(traffic.pending.len());
+        if count == 0 {
+            // let callers waiting for a reply spin slowly
+            std::thread::sleep(Duration::from_millis(1));
+            return Err(io::Error::new(ErrorKind::TimedOut, "no reply pending"));
+        }
+        for (byte, pending) in buf.iter_mut().zip(traffic.pending.drain(..count)) {
+            *byte = pending;
+        }
+        Ok(count)
+    }
+}
+
+impl SerialPort for MockSerialPort {
+    fn name(&self) -> Option<String> {
+        Some("mock".to_string())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(crate::serial::DEFAULT_BAUD_RATE)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, _baud_rate: u32) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _data_bits: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _flow_control: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _parity: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _stop_bits: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(self.traffic.lock().unwrap().pending.len() as u32)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, _buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
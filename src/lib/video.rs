@@ -0,0 +1,242 @@
+// copyright 2022 mikael lund aka wombat
+//
+// licensed under the apache license, version 2.0 (the "license");
+// you may not use this file except in compliance with the license.
+// you may obtain a copy of the license at
+//
+//     http://www.apache.org/licenses/license-2.0
+//
+// unless required by applicable law or agreed to in writing, software
+// distributed under the license is distributed on an "as is" basis,
+// without warranties or conditions of any kind, either express or implied.
+// see the license for the specific language governing permissions and
+// limitations under the license.
+
+//! Symbolic access to the VIC-IV video mode registers
+
+use crate::{M65Communicator, Matrix65Error, Result};
+use std::fmt;
+
+/// VIC-IV registers in the 28-bit address space ($D000 in the I/O area)
+pub const VIC_REGISTERS: u32 = 0xffd3000;
+
+/// Number of registers needed to decode the video mode
+pub const VIC_REGISTER_COUNT: usize = 0x80;
+
+/// Screen background colour register ($D021)
+pub const BACKGROUND_COLOR: u32 = VIC_REGISTERS + 0x21;
+
+/// Palette red, green, and blue registers for all 256 colours
+const PALETTE_RED: u32 = 0xffd3100;
+const PALETTE_GREEN: u32 = 0xffd3200;
+const PALETTE_BLUE: u32 = 0xffd3300;
+
+/// Writing these values to $D02F unlocks the VIC-IV registers
+const VIC_IV_KNOCK: [u8; 2] = [0x47, 0x53];
+
+/// Register offsets from $D000
+const SCROLY: usize = 0x11;
+const SCROLX: usize = 0x16;
+const VICDIS: usize = 0x31;
+const LINESTEP: usize = 0x58;
+const CHRCOUNT: usize = 0x5e;
+const SCRNPTR: usize = 0x60;
+const COLPTR: usize = 0x64;
+const CHARPTR: usize = 0x68;
+const DISPROWS: usize = 0x7b;
+
+/// Current display configuration of the VIC-IV
+///
+/// Only the most commonly needed settings are covered; all other
+/// register bits are left untouched when writing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VideoMode {
+    /// 640 pixels wide (80 column text)
+    pub h640: bool,
+    /// 400 pixels tall (50 row text)
+    pub v400: bool,
+    /// Bitmap instead of text mode
+    pub bitmap: bool,
+    /// Multicolour text or bitmap
+    pub multicolor: bool,
+    /// Extended background colour text
+    pub extended_background: bool,
+    /// Characters per text row
+    pub columns: u16,
+    /// Number of text rows
+    pub rows: u8,
+    /// Bytes per text row in screen RAM
+    pub line_step: u16,
+    /// Start of screen RAM (28-bit)
+    pub screen_address: u32,
+    /// Start of the character set (24-bit)
+    pub charset_address: u32,
+    /// Offset into colour RAM
+    pub color_offset: u16,
+}
+
+impl VideoMode {
+    /// Decode video mode from registers $D000 onwards
+    ///
+    /// Example:
+    /// ~~~
+    /// use matrix65::video::{VideoMode, VIC_REGISTER_COUNT};
+    /// let mut registers = [0u8; VIC_REGISTER_COUNT];
+    /// registers[0x31] = 0x80; // H640
+    /// registers[0x5e] = 80; // columns
+    /// registers[0x61] = 0x08; // screen at $0800
+    /// registers[0x7b] = 25; // rows
+    /// let mode = VideoMode::from_registers(&registers).unwrap();
+    /// assert!(mode.h640 && !mode.v400 && !mode.bitmap);
+    /// assert_eq!(mode.resolution(), (640, 200));
+    /// assert_eq!(mode.screen_address, 0x0800);
+    /// assert_eq!((mode.columns, mode.rows), (80, 25));
+    /// ~~~
+    pub fn from_registers(registers: &[u8]) -> Result<VideoMode> {
+        if registers.len() < VIC_REGISTER_COUNT {
+            return Err(Matrix65Error::Parse("too few VIC registers".to_string()));
+        }
+        let word = |offset: usize| u16::from_le_bytes([registers[offset], registers[offset + 1]]);
+        Ok(VideoMode {
+            h640: registers[VICDIS] & 0x80 != 0,
+            v400: registers[VICDIS] & 0x08 != 0,
+            bitmap: registers[SCROLY] & 0x20 != 0,
+            multicolor: registers[SCROLX] & 0x10 != 0,
+            extended_background: registers[SCROLY] & 0x40 != 0,
+            columns: registers[CHRCOUNT] as u16 | ((registers[SCRNPTR + 3] as u16 & 0x30) << 4),
+            rows: registers[DISPROWS],
+            line_step: word(LINESTEP),
+            screen_address: u32::from_le_bytes([
+                registers[SCRNPTR],
+                registers[SCRNPTR + 1],
+                registers[SCRNPTR + 2],
+                registers[SCRNPTR + 3] & 0x0f,
+            ]),
+            charset_address: u32::from_le_bytes([
+                registers[CHARPTR],
+                registers[CHARPTR + 1],
+                registers[CHARPTR + 2],
+                0,
+            ]),
+            color_offset: word(COLPTR),
+        })
+    }
+
+    /// Registers that must change to set this mode, given the current registers
+    ///
+    /// Returns offsets from $D000 and new values; bits not covered by
+    /// `VideoMode` keep their current value.
+    ///
+    /// Example:
+    /// ~~~
+    /// use matrix65::video::{VideoMode, VIC_REGISTER_COUNT};
+    /// let registers = [0u8; VIC_REGISTER_COUNT];
+    /// let mut mode = VideoMode::from_registers(&registers).unwrap();
+    /// mode.v400 = true;
+    /// mode.screen_address = 0x12345;
+    /// let changes = mode.changed_registers(&registers);
+    /// assert_eq!(changes, [(0x31, 0x08), (0x60, 0x45), (0x61, 0x23), (0x62, 0x01)]);
+    /// ~~~
+    pub fn changed_registers(&self, registers: &[u8]) -> Vec<(usize, u8)> {
+        let mut new = registers[..VIC_REGISTER_COUNT].to_vec();
+        let set_bit = |byte: &mut u8, mask: u8, on: bool| match on {
+            true => *byte |= mask,
+            false => *byte &= !mask,
+        };
+        set_bit(&mut new[VICDIS], 0x80, self.h640);
+        set_bit(&mut new[VICDIS], 0x08, self.v400);
+        set_bit(&mut new[SCROLY], 0x20, self.bitmap);
+        set_bit(&mut new[SCROLY], 0x40, self.extended_background);
+        set_bit(&mut new[SCROLX], 0x10, self.multicolor);
+        new[LINESTEP..LINESTEP + 2].copy_from_slice(&self.line_step.to_le_bytes());
+        new[CHRCOUNT] = self.columns as u8;
+        new[COLPTR..COLPTR + 2].copy_from_slice(&self.color_offset.to_le_bytes());
+        new[CHARPTR..CHARPTR + 3].copy_from_slice(&self.charset_address.to_le_bytes()[..3]);
+        let screen = self.screen_address.to_le_bytes();
+        new[SCRNPTR..SCRNPTR + 3].copy_from_slice(&screen[..3]);
+        new[SCRNPTR + 3] =
+            (new[SCRNPTR + 3] & 0xc0) | ((self.columns >> 4) as u8 & 0x30) | (screen[3] & 0x0f);
+        new[DISPROWS] = self.rows;
+        new.iter()
+            .zip(registers)
+            .enumerate()
+            .filter(|(_, (new, old))| new != old)
+            .map(|(offset, (new, _))| (offset, *new))
+            .collect()
+    }
+
+    /// Read the current video mode from the MEGA65
+    pub fn read(comm: &mut dyn M65Communicator) -> Result<VideoMode> {
+        let registers = comm.read_memory(VIC_REGISTERS, VIC_REGISTER_COUNT)?;
+        VideoMode::from_registers(&registers)
+    }
+
+    /// Apply video mode to the MEGA65
+    ///
+    /// The VIC-IV registers are unlocked first, then only the
+    /// registers that differ from the current ones are written.
+    pub fn write(&self, comm: &mut dyn M65Communicator) -> Result<()> {
+        let registers = comm.read_memory(VIC_REGISTERS, VIC_REGISTER_COUNT)?;
+        let changes = self.changed_registers(&registers);
+        if changes.is_empty() {
+            return Ok(());
+        }
+        for key in VIC_IV_KNOCK {
+            comm.poke_far(VIC_REGISTERS + 0x2f, key)?;
+        }
+        for (offset, value) in changes {
+            comm.poke_far(VIC_REGISTERS + offset as u32, value)?;
+        }
+        Ok(())
+    }
+
+    /// Width and height in pixels
+    pub const fn resolution(&self) -> (u16, u16) {
+        let width = if self.h640 { 640 } else { 320 };
+        let height = if self.v400 { 400 } else { 200 };
+        (width, height)
+    }
+}
+
+/// Read the first `count` palette entries as RGB
+///
+/// The palette registers store each colour component with its nibbles swapped.
+pub fn read_palette(comm: &mut dyn M65Communicator, count: usize) -> Result<Vec<[u8; 3]>> {
+    let red = comm.read_memory(PALETTE_RED, count)?;
+    let green = comm.read_memory(PALETTE_GREEN, count)?;
+    let blue = comm.read_memory(PALETTE_BLUE, count)?;
+    let component = |value: u8| value.rotate_left(4);
+    Ok((0..count)
+        .map(|i| [component(red[i]), component(green[i]), component(blue[i])])
+        .collect())
+}
+
+impl fmt::Display for VideoMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let on_off = |flag: bool| if flag { "on" } else { "off" };
+        let (width, height) = self.resolution();
+        writeln!(
+            f,
+            "Resolution:  {}x{} (H640 {}, V400 {})",
+            width,
+            height,
+            on_off(self.h640),
+            on_off(self.v400)
+        )?;
+        writeln!(
+            f,
+            "Mode:        {} (multicolour {}, extended background {})",
+            if self.bitmap { "bitmap" } else { "text" },
+            on_off(self.multicolor),
+            on_off(self.extended_background)
+        )?;
+        writeln!(
+            f,
+            "Text:        {} columns, {} rows, {} bytes per row",
+            self.columns, self.rows, self.line_step
+        )?;
+        writeln!(f, "Screen RAM:  0x{:07x}", self.screen_address)?;
+        writeln!(f, "Charset:     0x{:07x}", self.charset_address)?;
+        write!(f, "Colour RAM:  offset 0x{:04x}", self.color_offset)
+    }
+}
@@ -0,0 +1,196 @@
+// copyright 2022 mikael lund aka wombat
+//
+// licensed under the apache license, version 2.0 (the "license");
+// you may not use this file except in compliance with the license.
+// you may obtain a copy of the license at
+//
+//     http://www.apache.org/licenses/license-2.0
+//
+// unless required by applicable law or agreed to in writing, software
+// distributed under the license is distributed on an "as is" basis,
+// without warranties or conditions of any kind, either express or implied.
+// see the license for the specific language governing permissions and
+// limitations under the license.
+
+//! Communicator for the Xemu emulator
+//!
+//! Xemu provides the matrix mode monitor protocol over TCP when
+//! started with e.g. `xemu-xmega65 -uartmon :4510`.
+
+use crate::cpu::Registers;
+use crate::monitor::{is_timeout, MonitorLink, TextMonitor};
+use crate::serial::{check_monitor_reply, DELAY_WARM_RESET, KEY_RESTORE, KEY_RUN_STOP};
+use crate::{CancelToken, M65Communicator, Matrix65Error, Result};
+use log::debug;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+/// Wait this long for more data before considering a reply complete
+const READ_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Xemu's monitor takes commands ending with a newline
+impl MonitorLink for TcpStream {
+    fn send_line(&mut self, line: &str) -> Result<()> {
+        self.write_all(format!("{}\n", line).as_bytes())?;
+        Ok(())
+    }
+
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<Option<usize>> {
+        match self.read(buffer) {
+            Ok(0) => Err(Matrix65Error::Serial(
+                "Xemu closed the connection".to_string(),
+            )),
+            Ok(n) => Ok(Some(n)),
+            Err(err) if is_timeout(&err) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Communicator for Xemu's remote monitor (uartmon) over TCP
+///
+/// Example with a local listener standing in for Xemu, which dumps
+/// memory filled with the low byte of each address and rejects writes
+/// to the last address:
+/// ~~~
+/// use matrix65::xemu::M65Xemu;
+/// use matrix65::{M65Communicator, Matrix65Error};
+/// use std::io::{BufRead, BufReader, Write};
+/// use std::net::TcpListener;
+/// let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+/// let address = listener.local_addr().unwrap().to_string();
+/// std::thread::spawn(move || {
+///     let (stream, _) = listener.accept().unwrap();
+///     let mut xemu = stream.try_clone().unwrap();
+///     for command in BufReader::new(stream).lines() {
+///         let command = command.unwrap();
+///         let mut reply = format!("{}\r\n", command);
+///         if let Some(address) = command.strip_prefix('M') {
+///             let address = u32::from_str_radix(address, 16).unwrap();
+///             for line in (address..address + 256).step_by(16) {
+///                 let bytes: String = (line..line + 16).map(|a| format!("{:02X}", a as u8)).collect();
+///                 reply += &format!(":{:08X}:{}\r\n", line, bytes);
+///             }
+///         } else if command.starts_with("sfffffff") {
+///             reply += "?\r\n";
+///         }
+///         xemu.write_all(reply.as_bytes()).unwrap();
+///     }
+/// });
+/// let mut xemu = M65Xemu::connect(&address).unwrap();
+/// assert_eq!(xemu.read_memory(0x1010, 4).unwrap(), [0x10, 0x11, 0x12, 0x13]);
+/// xemu.write_memory_far(0x2000, &[1, 2]).unwrap();
+/// let err = xemu.poke_far(0xfffffff, 0).unwrap_err();
+/// assert!(matches!(err, Matrix65Error::MonitorError(_)));
+/// ~~~
+pub struct M65Xemu {
+    monitor: TextMonitor<TcpStream>,
+}
+
+impl M65Xemu {
+    /// Connect to Xemu's monitor at `host:port`
+    pub fn connect(address: &str) -> Result<Self> {
+        debug!("Connecting to Xemu at {}", address);
+        let stream = TcpStream::connect(address).map_err(|err| {
+            Matrix65Error::Serial(format!(
+                "cannot connect to Xemu at {}: {}. Was it started with -uartmon?",
+                address, err
+            ))
+        })?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        Ok(M65Xemu {
+            monitor: TextMonitor::new(stream),
+        })
+    }
+}
+
+impl M65Communicator for M65Xemu {
+    fn reset(&mut self) -> Result<()> {
+        debug!("Sending RESET to Xemu");
+        self.monitor.command("!")
+    }
+
+    fn poke_far(&mut self, destination: u32, value: u8) -> Result<()> {
+        self.monitor
+            .command(&format!("s{:x} {:02x}", destination, value))
+    }
+
+    fn warm_reset(&mut self) -> Result<()> {
+        debug!("Sending RUN/STOP + RESTORE to Xemu");
+        self.monitor.command(&format!(
+            "sffd3615 {:02x} {:02x}",
+            KEY_RUN_STOP, KEY_RESTORE
+        ))?;
+        thread::sleep(DELAY_WARM_RESET);
+        self.monitor.command("sffd3615 7f 7f 7f")
+    }
+
+    fn read_registers(&mut self) -> Result<Registers> {
+        let reply = self.monitor.query("r")?;
+        check_monitor_reply(&reply)?;
+        Registers::parse(&String::from_utf8_lossy(&reply))
+    }
+
+    /// In trace mode, an empty command steps one instruction and shows registers
+    fn step(&mut self) -> Result<Registers> {
+        let reply = self.monitor.query("")?;
+        check_monitor_reply(&reply)?;
+        Registers::parse(&String::from_utf8_lossy(&reply))
+    }
+
+    fn set_breakpoint(&mut self, address: u32) -> Result<()> {
+        self.monitor.command(&format!("b{:x}", address))
+    }
+
+    fn clear_breakpoint(&mut self) -> Result<()> {
+        self.monitor.command("b")
+    }
+
+    fn jump(&mut self, address: u32) -> Result<()> {
+        self.monitor.command(&format!("g{:x}", address))?;
+        self.start_cpu()
+    }
+
+    /// Read and discard everything until Xemu goes quiet
+    fn flush(&mut self) -> Result<usize> {
+        self.monitor.flush()
+    }
+
+    fn set_key_delay(&mut self, delay: Duration) {
+        self.monitor.set_key_delay(delay);
+    }
+
+    fn set_cancel_token(&mut self, token: CancelToken) {
+        self.monitor.set_cancel_token(token);
+    }
+
+    fn stop_cpu(&mut self) -> Result<()> {
+        self.monitor.command("t1")
+    }
+
+    fn start_cpu(&mut self) -> Result<()> {
+        self.monitor.command("t0")
+    }
+
+    fn type_text(&mut self, text: &str) -> Result<()> {
+        self.monitor.type_text(text)
+    }
+
+    /// Load memory a page at a time using the `M` command
+    fn read_memory_to_writer(
+        &mut self,
+        address: u32,
+        length: usize,
+        out: &mut dyn Write,
+    ) -> Result<()> {
+        self.monitor.read_memory_to_writer(address, length, out)
+    }
+
+    /// Write memory in small chunks using the `s` command
+    fn write_memory_far(&mut self, address: u32, bytes: &[u8]) -> Result<()> {
+        self.monitor.write_memory_far(address, bytes)
+    }
+}
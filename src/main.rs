@@ -14,12 +14,15 @@
 
 use anyhow::Result;
 use clap::Parser;
-use matrix65::{filehost, serial};
+use matrix65::{dryrun, serial, xemu, M65Communicator};
 use pretty_env_logger::env_logger::DEFAULT_FILTER_ENV;
 
 mod commands;
+mod config;
+mod doctor;
 mod input;
 mod repl;
+mod state;
 mod textui;
 
 fn main() {
@@ -30,37 +33,253 @@ fn main() {
 }
 
 fn do_main() -> Result<()> {
-    let args = input::Args::parse();
+    let mut args = input::Args::parse();
 
     if args.verbose && std::env::var(DEFAULT_FILTER_ENV).is_err() {
         std::env::set_var(DEFAULT_FILTER_ENV, "Debug");
     }
     pretty_env_logger::init();
 
-    let mut port = serial::open_port(&args.port, args.baud)?;
+    // Precedence: command line > environment > profile > config file > built-in default
+    let config = config::Config::load()?;
+    args.apply_env()?;
+    if let Some(name) = &args.profile {
+        args.apply_profile(config.profile(name)?);
+    }
+    args.apply_config(&config);
+
+    if args.no_cache {
+        matrix65::cache::set_enabled(false);
+    }
+
+    // Commands that do not talk to the MEGA65
+    if let input::Commands::List {
+        filter,
+        sort,
+        limit,
+        offset,
+    } = &args.command
+    {
+        return commands::list(filter.as_deref(), sort, *limit, *offset);
+    }
+    if let input::Commands::Mkdisk {
+        image,
+        files,
+        force,
+    } = &args.command
+    {
+        return commands::mkdisk(image, files, *force);
+    }
+    if let input::Commands::Dir { image } = &args.command {
+        return commands::dir(image);
+    }
+    if let input::Commands::CrtInfo {
+        file, write: false, ..
+    } = &args.command
+    {
+        return commands::crt_info(file);
+    }
+    if let input::Commands::Ports {} = &args.command {
+        return commands::ports(args.json);
+    }
+
+    if let input::Commands::Doctor {} = &args.command {
+        return doctor::doctor(args.port.as_deref(), args.baud(), &mut |port, baud| {
+            connect(port, baud, &args)
+        });
+    }
+
+    let mut timing = None;
+    let port = match &args.port {
+        Some(port) => port.clone(),
+        None if args.dry_run => "MEGA65".to_string(),
+        None => {
+            let port = serial::detect_port()?;
+            eprintln!("Using detected MEGA65 on {}", port);
+            port
+        }
+    };
+    let mut comm: Box<dyn M65Communicator> = match port.strip_prefix("xemu:") {
+        Some(address) if !args.dry_run => Box::new(xemu::M65Xemu::connect(address)?),
+        _ => {
+            let mut serial = open_serial(&port, args.baud(), &args)?;
+            timing = args.trace_timing.then(|| serial.enable_timing());
+            Box::new(serial)
+        }
+    };
+
+    if let Some(delay) = args.keydelay {
+        comm.set_key_delay(std::time::Duration::from_millis(delay));
+    }
 
-    match args.command {
-        input::Commands::Reset { c64 } => commands::reset(&mut port, c64)?,
-        input::Commands::Filehost {} => commands::filehost(&mut port)?,
-        input::Commands::Cmd {} => repl::start_repl(&mut port)?,
-        input::Commands::Type { text } => {
-            serial::type_text(&mut port, text.as_str())?;
+    let output = commands::Output {
+        color: args.color(),
+        json: args.json,
+    };
+    let result = execute(args.command, comm.as_mut(), output);
+    if let Some(timing) = timing {
+        eprintln!("\nSerial timing breakdown:\n{}", timing.lock().unwrap());
+    }
+    result
+}
+
+/// Open serial port or connect to Xemu
+fn connect(port: &str, baud: u32, args: &input::Args) -> Result<Box<dyn M65Communicator>> {
+    match port.strip_prefix("xemu:") {
+        Some(address) => Ok(Box::new(xemu::M65Xemu::connect(address)?)),
+        None => Ok(Box::new(open_serial(port, baud, args)?)),
+    }
+}
+
+/// Open serial port and apply the serial specific options
+fn open_serial(port: &str, baud: u32, args: &input::Args) -> Result<serial::M65Serial> {
+    let mut serial = match args.dry_run {
+        true => serial::M65Serial::new(Box::new(dryrun::DryRunPort::new(port, baud))),
+        false => serial::M65Serial::open(port, baud)?,
+    };
+    serial.set_dump_header(args.monitor.as_deref().unwrap_or("mainline").parse()?);
+    serial.set_no_halt(args.no_halt);
+    serial.set_read_retries(args.read_retries);
+    serial.set_timeout(std::time::Duration::from_millis(args.read_timeout))?;
+    serial.set_flow_control(args.flow_control.as_deref().unwrap_or("none").parse()?)?;
+    Ok(serial)
+}
+
+/// Run subcommand on the MEGA65
+fn execute(
+    command: input::Commands,
+    comm: &mut dyn M65Communicator,
+    output: commands::Output,
+) -> Result<()> {
+    match command {
+        input::Commands::Reset { c64, warm } => commands::reset(comm, c64, warm)?,
+        input::Commands::Filehost {} => commands::filehost(comm)?,
+        input::Commands::List { .. }
+        | input::Commands::Mkdisk { .. }
+        | input::Commands::Dir { .. }
+        | input::Commands::CrtInfo { write: false, .. }
+        | input::Commands::Ports {}
+        | input::Commands::Doctor {} => unreachable!("handled before opening the port"),
+        input::Commands::Info {} => commands::info(comm, output.json)?,
+        input::Commands::Regs {} => commands::regs(comm, output.json)?,
+        input::Commands::Screenshot { outfile } => commands::screenshot(comm, &outfile)?,
+        input::Commands::Video {} => commands::video(comm)?,
+        input::Commands::Sid { file, song } => commands::sid(comm, &file, song)?,
+        input::Commands::CrtInfo {
+            file,
+            write: true,
+            yes,
+        } => commands::crt_write(comm, &file, yes)?,
+        input::Commands::Kbtest { yes, delay } => commands::kbtest(comm, yes, delay)?,
+        input::Commands::Cmd {
+            exec,
+            file,
+            keep_going,
+        } => match (exec, file) {
+            (Some(script), _) => repl::run_script(comm, &script, keep_going)?,
+            (None, Some(file)) => {
+                let script = std::fs::read_to_string(file)?;
+                repl::run_script(comm, &script, keep_going)?
+            }
+            (None, None) => {
+                let history = config::Config::load()?.history_path();
+                if let Some(dir) = history.as_ref().and_then(|path| path.parent()) {
+                    std::fs::create_dir_all(dir)?;
+                }
+                repl::start_repl(comm, history)?
+            }
+        },
+        input::Commands::Upload {
+            file,
+            address,
+            raw,
+            stub,
+        } => commands::upload(comm, &file, address.as_deref(), raw, stub)?,
+        input::Commands::Go { address } => commands::go(comm, &address)?,
+        input::Commands::Bench { size, address } => commands::bench(comm, &address, size)?,
+        input::Commands::Basic {
+            file,
+            auto_number,
+            line_delay,
+        } => commands::basic(comm, &file, auto_number, line_delay)?,
+        input::Commands::Type { text, keydelay } => {
+            if let Some(delay) = keydelay {
+                comm.set_key_delay(std::time::Duration::from_millis(delay));
+            }
+            comm.type_text(text.as_str())?;
         }
-        input::Commands::Prg { file, reset, run } => {
-            serial::handle_prg(&mut port, &file, reset, run)?;
+        input::Commands::Prg {
+            file,
+            last: _,
+            reset,
+            run,
+            sys,
+            verify,
+            address,
+            watch,
+        } => {
+            // without a file, `--last` is given
+            let file = match file {
+                Some(file) => file,
+                None => {
+                    let file = state::last_prg()?;
+                    eprintln!("Loading {}", file);
+                    file
+                }
+            };
+            // watched programs are always started
+            let run = run || (watch && sys.is_none());
+            let load = |comm: &mut dyn M65Communicator, reset: bool| -> Result<()> {
+                match &address {
+                    Some(address) => commands::prg_at(comm, &file, address, reset, run, verify)?,
+                    None => comm.handle_prg(&file, reset, run, verify)?,
+                }
+                if let Some(address) = &sys {
+                    commands::sys(comm, address)?;
+                }
+                Ok(())
+            };
+            load(comm, reset)?;
+            if let Err(err) = state::remember_prg(&file) {
+                eprintln!("Warning: cannot remember {} for --last: {}", file, err);
+            }
+            if watch {
+                commands::watch_file(&file, || load(comm, true))?;
+            }
         }
         input::Commands::Peek {
             address,
             length,
             outfile,
             disassemble,
-        } => commands::peek(&mut port, address, length, outfile, disassemble)?,
+            end,
+            follow,
+            max_instructions,
+            watch,
+            interval,
+        } => {
+            let length = match &end {
+                Some(end) if !follow => commands::length_to_end(&address, end)?,
+                _ => length,
+            };
+            if follow {
+                commands::follow(comm, address, end, max_instructions)?
+            } else if watch {
+                let interval = std::time::Duration::from_millis(interval);
+                commands::watch(comm, &address, length, disassemble, output.color, interval)?
+            } else {
+                commands::peek(comm, address, length, outfile, disassemble, output.color)?
+            }
+        }
 
         input::Commands::Poke {
             address,
             file,
             value,
-        } => commands::poke(file, value, address, &mut port)?,
+            bytes,
+            warn_critical,
+            force,
+        } => commands::poke(file, value, bytes, address, warn_critical && !force, comm)?,
     }
     Ok(())
 }
@@ -1,42 +1,179 @@
 use crate::commands;
-use crate::serial;
+use matrix65::{parse_address, M65Communicator};
 use reedline_repl_rs::clap::{Arg, ArgMatches, Command};
 use reedline_repl_rs::{Repl, Result};
-use serialport::SerialPort;
+use std::path::PathBuf;
+
+/// Number of commands kept in the history file
+const HISTORY_SIZE: usize = 1000;
 
 /// Provide a state to be passed to each command.
-/// Main funtion is to store the serial port
+/// Main funtion is to store the MEGA65 communicator
 struct Context<'a> {
-    pub port: &'a mut Box<dyn SerialPort>,
+    pub comm: &'a mut dyn M65Communicator,
+    /// Address of the single monitor breakpoint, if set
+    pub breakpoint: Option<u32>,
 }
 
-pub fn start_repl(port: &mut Box<dyn SerialPort>) -> Result<()> {
-    let context = Context { port };
-    let mut repl = Repl::new(context)
-        .with_name("matrix65")
-        .with_version(env!("CARGO_PKG_VERSION"))
-        .with_description(env!("CARGO_PKG_DESCRIPTION"))
-        .with_banner("Welcome to matrix65!")
-        .with_command(Command::new("reset").about("Reset MEGA65"), reset)
-        .with_command(Command::new("go64").about("Go to C64 mode"), go64)
-        .with_command(Command::new("stop").about("Halt CPU"), stop)
-        .with_command(Command::new("start").about("Resume CPU"), start)
-        .with_command(
+/// Signature shared by all REPL command wrappers
+type Callback<'a> = fn(ArgMatches, &mut Context<'a>) -> Result<Option<String>>;
+
+/// Commands available both in the interactive shell and in scripts
+fn command_set<'a>() -> Vec<(Command<'static>, Callback<'a>)> {
+    vec![
+        (Command::new("reset").about("Reset MEGA65"), reset),
+        (Command::new("go64").about("Go to C64 mode"), go64),
+        (Command::new("regs").about("Show CPU registers"), regs),
+        (
+            Command::new("step")
+                .about("Execute instructions one at a time (halt CPU first)")
+                .arg(Arg::new("count").default_value("1")),
+            step,
+        ),
+        (
+            Command::new("break")
+                .about("Halt CPU at address; replaces any previous breakpoint")
+                .arg(Arg::new("address").required(true)),
+            set_breakpoint,
+        ),
+        (
+            Command::new("unbreak").about("Clear breakpoint"),
+            clear_breakpoint,
+        ),
+        (
+            Command::new("continue").about("Resume CPU and wait for the breakpoint"),
+            resume,
+        ),
+        (
+            Command::new("go")
+                .about("Jump to machine code at address and run it")
+                .arg(Arg::new("address").required(true)),
+            go,
+        ),
+        (Command::new("stop").about("Halt CPU"), stop),
+        (Command::new("start").about("Resume CPU"), start),
+        (
             Command::new("dasm")
-                .about("Disassemble memory (prefix hex values w. 0x....)")
+                .about("Disassemble memory (prefix hex values w. 0x or $)")
                 .arg(Arg::new("address").required(true))
                 .arg(Arg::new("length").required(true)),
-            peek,
-        )
-        .with_command(
+            dasm,
+        ),
+        (
+            Command::new("mem")
+                .about("Hexdump memory (prefix hex values w. 0x or $)")
+                .arg(Arg::new("address").required(true))
+                .arg(Arg::new("length").default_value("16")),
+            mem,
+        ),
+        (
+            Command::new("poke")
+                .about("Write bytes to memory (prefix hex values w. 0x or $)")
+                .arg(Arg::new("address").required(true))
+                .arg(Arg::new("value").required(true).multiple_values(true)),
+            poke,
+        ),
+        (
+            Command::new("load")
+                .about("Load PRG file or URL into memory")
+                .arg(Arg::new("file").required(true))
+                .arg(Arg::new("run").long("run").help("Run after loading")),
+            load,
+        ),
+        (
+            Command::new("keydelay")
+                .about("Set delay between typed keys in milliseconds")
+                .arg(Arg::new("ms").required(true)),
+            keydelay,
+        ),
+        (
             Command::new("filehost").about("Start the filehost"),
             filehost,
-        );
+        ),
+    ]
+}
+
+/// Start interactive shell, with history saved to `history` if given
+///
+/// The directory of the history file must exist.
+pub fn start_repl(comm: &mut dyn M65Communicator, history: Option<PathBuf>) -> Result<()> {
+    let context = Context {
+        comm,
+        breakpoint: None,
+    };
+    let mut repl = Repl::new(context)
+        .with_name("matrix65")
+        .with_version(env!("CARGO_PKG_VERSION"))
+        .with_description(env!("CARGO_PKG_DESCRIPTION"))
+        .with_banner("Welcome to matrix65!");
+    if let Some(path) = history {
+        repl = repl.with_history(path, HISTORY_SIZE);
+    }
+    for (command, callback) in command_set() {
+        repl = repl.with_command(command, callback);
+    }
     repl.run()
 }
 
+/// Run REPL commands non-interactively, then exit
+///
+/// Commands are separated by newlines or semicolons and lines
+/// starting with `#` are ignored. Each command is reported as
+/// succeeded or failed; by default execution stops at the first
+/// failure unless `keep_going` is set.
+pub fn run_script(
+    comm: &mut dyn M65Communicator,
+    script: &str,
+    keep_going: bool,
+) -> core::result::Result<(), anyhow::Error> {
+    let mut context = Context {
+        comm,
+        breakpoint: None,
+    };
+    let commands = command_set();
+    let mut failures = 0;
+    let lines = script
+        .split(['\n', ';'])
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'));
+    for line in lines {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let result = match commands
+            .iter()
+            .find(|(command, _)| command.get_name() == words[0])
+        {
+            Some((command, callback)) => match command.clone().try_get_matches_from(&words) {
+                Ok(matches) => callback(matches, &mut context).map_err(|err| err.to_string()),
+                Err(err) => Err(err.to_string()),
+            },
+            None => Err(format!("unknown command '{}'", words[0])),
+        };
+        match result {
+            Ok(output) => {
+                println!("[ok] {}", line);
+                if let Some(text) = output {
+                    println!("{}", text);
+                }
+            }
+            Err(err) => {
+                eprintln!("[failed] {}: {}", line, err);
+                failures += 1;
+                if !keep_going {
+                    break;
+                }
+            }
+        }
+    }
+    match failures {
+        0 => Ok(()),
+        n => Err(anyhow::anyhow!("{} command(s) failed", n)),
+    }
+}
+
 /// Helper function to convert error type
-fn handle_result(result: core::result::Result<(), anyhow::Error>) -> Result<Option<String>> {
+fn handle_result<E: std::fmt::Display>(
+    result: core::result::Result<(), E>,
+) -> Result<Option<String>> {
     match result {
         Err(err) => Err(reedline_repl_rs::Error::IllegalDefaultError(
             err.to_string(),
@@ -45,39 +182,142 @@ fn handle_result(result: core::result::Result<(), anyhow::Error>) -> Result<Opti
     }
 }
 
-/// Wrap peek command
-fn peek(_args: ArgMatches, context: &mut Context) -> Result<Option<String>> {
-    let address = _args.value_of("address").unwrap().to_string();
-    let length = _args
+/// Wrap peek command, either disassembling or hexdumping memory
+fn peek(args: &ArgMatches, context: &mut Context, disassemble: bool) -> Result<Option<String>> {
+    let address = args.value_of("address").unwrap().to_string();
+    let length = args
         .value_of("length")
         .unwrap_or("1")
         .to_string()
         .parse::<usize>()?;
-    let result = commands::peek(context.port, address, length, None, true);
+    let result = commands::peek(context.comm, address, length, None, disassemble, false);
     handle_result(result)
 }
 
+/// Wrap dasm command
+fn dasm(_args: ArgMatches, context: &mut Context) -> Result<Option<String>> {
+    peek(&_args, context, true)
+}
+
+/// Wrap mem command
+fn mem(_args: ArgMatches, context: &mut Context) -> Result<Option<String>> {
+    peek(&_args, context, false)
+}
+
+/// Wrap poke command
+fn poke(_args: ArgMatches, context: &mut Context) -> Result<Option<String>> {
+    let address = _args.value_of("address").unwrap().to_string();
+    let mut hex_bytes = Vec::new();
+    for value in _args.values_of("value").unwrap() {
+        match parse_address(value).map(u8::try_from) {
+            Ok(Ok(byte)) => hex_bytes.push(format!("{:02x}", byte)),
+            _ => {
+                return handle_result(Err(anyhow::anyhow!(
+                    "invalid byte value '{}'; use 0-255, 0x00-0xff, or $00-$ff",
+                    value
+                )))
+            }
+        }
+    }
+    let result = commands::poke(
+        None,
+        None,
+        Some(hex_bytes.join(" ")),
+        address,
+        false,
+        context.comm,
+    );
+    handle_result(result)
+}
+
+/// Wrap load command
+fn load(_args: ArgMatches, context: &mut Context) -> Result<Option<String>> {
+    let file = _args.value_of("file").unwrap();
+    let run = _args.is_present("run");
+    handle_result(context.comm.handle_prg(file, false, run, false))
+}
+
 /// Wrap reset command
 fn reset(_args: ArgMatches, context: &mut Context) -> Result<Option<String>> {
-    handle_result(commands::reset(context.port, false))
+    handle_result(commands::reset(context.comm, false, false))
 }
 
 /// Wrap go64 command
 fn go64(_args: ArgMatches, context: &mut Context) -> Result<Option<String>> {
-    handle_result(serial::go64(context.port))
+    handle_result(context.comm.go64())
+}
+
+/// Wrap regs command
+fn regs(_args: ArgMatches, context: &mut Context) -> Result<Option<String>> {
+    match context.comm.read_registers() {
+        Ok(registers) => Ok(Some(registers.to_string())),
+        Err(err) => Err(reedline_repl_rs::Error::IllegalDefaultError(
+            err.to_string(),
+        )),
+    }
+}
+
+/// Wrap step command
+fn step(_args: ArgMatches, context: &mut Context) -> Result<Option<String>> {
+    let count = _args.value_of("count").unwrap().parse::<usize>()?;
+    handle_result(commands::step(context.comm, count))
+}
+
+/// Wrap break command
+fn set_breakpoint(_args: ArgMatches, context: &mut Context) -> Result<Option<String>> {
+    let address = _args.value_of("address").unwrap();
+    let address = match parse_address(address) {
+        Ok(address) => address,
+        Err(err) => return handle_result(Err(err)),
+    };
+    handle_result(context.comm.set_breakpoint(address))?;
+    context.breakpoint = Some(address);
+    Ok(None)
+}
+
+/// Wrap unbreak command
+fn clear_breakpoint(_args: ArgMatches, context: &mut Context) -> Result<Option<String>> {
+    handle_result(context.comm.clear_breakpoint())?;
+    context.breakpoint = None;
+    Ok(None)
+}
+
+/// Wrap continue command
+fn resume(_args: ArgMatches, context: &mut Context) -> Result<Option<String>> {
+    match context.breakpoint {
+        Some(address) => handle_result(commands::resume(context.comm, address)),
+        None => handle_result(Err(anyhow::Error::msg(
+            "no breakpoint set; use 'break' first",
+        ))),
+    }
+}
+
+/// Wrap go command
+fn go(_args: ArgMatches, context: &mut Context) -> Result<Option<String>> {
+    let address = _args.value_of("address").unwrap();
+    handle_result(commands::go(context.comm, address))
 }
 
 /// Wrap stop cpu command
 fn stop(_args: ArgMatches, context: &mut Context) -> Result<Option<String>> {
-    handle_result(serial::stop_cpu(context.port))
+    handle_result(context.comm.stop_cpu())
 }
 
 /// Wrap start cpu command
 fn start(_args: ArgMatches, context: &mut Context) -> Result<Option<String>> {
-    handle_result(serial::start_cpu(context.port))
+    handle_result(context.comm.start_cpu())
+}
+
+/// Wrap key delay setting
+fn keydelay(_args: ArgMatches, context: &mut Context) -> Result<Option<String>> {
+    let delay = _args.value_of("ms").unwrap().parse::<u64>()?;
+    context
+        .comm
+        .set_key_delay(std::time::Duration::from_millis(delay));
+    Ok(None)
 }
 
 /// Wrap filehost command
 fn filehost(_args: ArgMatches, context: &mut Context) -> Result<Option<String>> {
-    handle_result(commands::filehost(context.port))
+    handle_result(commands::filehost(context.comm))
 }
@@ -0,0 +1,52 @@
+// copyright 2022 mikael lund aka wombat
+//
+// licensed under the apache license, version 2.0 (the "license");
+// you may not use this file except in compliance with the license.
+// you may obtain a copy of the license at
+//
+//     http://www.apache.org/licenses/license-2.0
+//
+// unless required by applicable law or agreed to in writing, software
+// distributed under the license is distributed on an "as is" basis,
+// without warranties or conditions of any kind, either express or implied.
+// see the license for the specific language governing permissions and
+// limitations under the license.
+
+//! State kept between runs, e.g. the last loaded program for `prg --last`
+
+use anyhow::Result;
+use directories::ProjectDirs;
+use std::path::PathBuf;
+
+/// File holding the last loaded program, e.g. `~/.local/share/matrix65/last-prg.txt` on Linux
+fn last_prg_path() -> Option<PathBuf> {
+    ProjectDirs::from("", "", "matrix65").map(|dirs| dirs.data_dir().join("last-prg.txt"))
+}
+
+/// File or URL of the program loaded by the previous `prg` command
+pub fn last_prg() -> Result<String> {
+    last_prg_path()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .map(|file| file.trim().to_string())
+        .filter(|file| !file.is_empty())
+        .ok_or_else(|| anyhow::Error::msg("no previous program; load one with `prg <file>` first"))
+}
+
+/// Remember program for `prg --last`
+///
+/// Local files are stored with their absolute path so that they are
+/// found from any directory.
+pub fn remember_prg(file: &str) -> Result<()> {
+    let path = last_prg_path().ok_or_else(|| anyhow::Error::msg("no data directory"))?;
+    let file = match file.starts_with("http") {
+        true => file.to_string(),
+        false => std::fs::canonicalize(file)
+            .map(|path| path.display().to_string())
+            .unwrap_or_else(|_| file.to_string()),
+    };
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, file)?;
+    Ok(())
+}
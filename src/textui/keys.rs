@@ -0,0 +1,204 @@
+// copyright 2022 mikael lund aka wombat
+//
+// licensed under the apache license, version 2.0 (the "license");
+// you may not use this file except in compliance with the license.
+// you may obtain a copy of the license at
+//
+//     http://www.apache.org/licenses/license-2.0
+//
+// unless required by applicable law or agreed to in writing, software
+// distributed under the license is distributed on an "as is" basis,
+// without warranties or conditions of any kind, either express or implied.
+// see the license for the specific language governing permissions and
+// limitations under the license.
+
+//! Key bindings of the TUI, optionally remapped in the config file, e.g.
+//!
+//! ~~~ toml
+//! [keys]
+//! quit = "x"
+//! run = "space"
+//! ~~~
+
+use anyhow::Result;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use std::collections::{BTreeMap, HashMap};
+
+/// Action triggered by a key press
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Quit,
+    Help,
+    /// Reset the MEGA65
+    Reset,
+    /// Cycle sort order
+    Sort,
+    /// Reverse sort direction
+    ReverseSort,
+    /// Start typing a search filter
+    Search,
+    /// Select the highlighted item, e.g. to run it
+    Run,
+    /// Close popup, or cancel the current transfer
+    Back,
+    Up,
+    Down,
+}
+
+/// Actions with their name in the config file and default key
+const DEFAULT_BINDINGS: [(Action, &str, KeyCode); 10] = [
+    (Action::Quit, "quit", KeyCode::Char('q')),
+    (Action::Help, "help", KeyCode::Char('h')),
+    (Action::Reset, "reset", KeyCode::Char('R')),
+    (Action::Sort, "sort", KeyCode::Char('s')),
+    (Action::ReverseSort, "reverse_sort", KeyCode::Char('S')),
+    (Action::Search, "search", KeyCode::Char('/')),
+    (Action::Run, "run", KeyCode::Enter),
+    (Action::Back, "back", KeyCode::Esc),
+    (Action::Up, "up", KeyCode::Up),
+    (Action::Down, "down", KeyCode::Down),
+];
+
+/// Named keys accepted in the config file, in addition to single characters
+const KEY_NAMES: [(&str, KeyCode); 12] = [
+    ("enter", KeyCode::Enter),
+    ("esc", KeyCode::Esc),
+    ("up", KeyCode::Up),
+    ("down", KeyCode::Down),
+    ("left", KeyCode::Left),
+    ("right", KeyCode::Right),
+    ("tab", KeyCode::Tab),
+    ("backspace", KeyCode::Backspace),
+    ("space", KeyCode::Char(' ')),
+    ("home", KeyCode::Home),
+    ("end", KeyCode::End),
+    ("delete", KeyCode::Delete),
+];
+
+/// Map from keys to actions
+#[derive(Debug, Clone)]
+pub struct KeyMap {
+    actions: HashMap<KeyCode, Action>,
+}
+
+impl Default for KeyMap {
+    fn default() -> KeyMap {
+        KeyMap {
+            actions: DEFAULT_BINDINGS
+                .iter()
+                .map(|(action, _, key)| (*key, *action))
+                .collect(),
+        }
+    }
+}
+
+impl KeyMap {
+    /// Default bindings with the given `action = "key"` pairs from the config file
+    ///
+    /// A remapped action no longer responds to its default key.
+    pub fn with_bindings(bindings: &BTreeMap<String, String>) -> Result<KeyMap> {
+        let mut keymap = KeyMap::default();
+        for (name, key) in bindings {
+            let action = DEFAULT_BINDINGS
+                .iter()
+                .find(|(_, action_name, _)| action_name == name)
+                .map(|(action, _, _)| *action)
+                .ok_or_else(|| {
+                    let names: Vec<&str> = DEFAULT_BINDINGS.iter().map(|(_, n, _)| *n).collect();
+                    anyhow::anyhow!(
+                        "unknown key binding '{}'; use one of {}",
+                        name,
+                        names.join(", ")
+                    )
+                })?;
+            let key = parse_key(key)?;
+            keymap.actions.retain(|_, bound| *bound != action);
+            keymap.actions.insert(key, action);
+        }
+        Ok(keymap)
+    }
+
+    /// Action bound to a key, if any
+    pub fn action(&self, key: KeyCode) -> Option<Action> {
+        self.actions.get(&key).copied()
+    }
+
+    /// Name of the key bound to an action, for help texts
+    pub fn key_name(&self, action: Action) -> String {
+        let key = self
+            .actions
+            .iter()
+            .find(|(_, bound)| **bound == action)
+            .map(|(key, _)| *key);
+        match key {
+            Some(KeyCode::Char(' ')) => "space".to_string(),
+            Some(KeyCode::Char(c)) => c.to_string(),
+            Some(key) => KEY_NAMES
+                .iter()
+                .find(|(_, named)| *named == key)
+                .map_or_else(|| format!("{:?}", key), |(name, _)| name.to_string()),
+            None => "unbound".to_string(),
+        }
+    }
+}
+
+/// Whether the key is Ctrl-C, which raw mode delivers as a key instead of a signal
+///
+/// It cannot be remapped and cancels a transfer, or else quits.
+pub fn is_ctrl_c(key: &KeyEvent) -> bool {
+    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL)
+}
+
+/// Parse single character or key name, e.g. "q" or "enter"
+fn parse_key(text: &str) -> Result<KeyCode> {
+    let mut chars = text.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Ok(KeyCode::Char(c));
+    }
+    let lower = text.to_lowercase();
+    if let Some((_, key)) = KEY_NAMES.iter().find(|(name, _)| *name == lower) {
+        return Ok(*key);
+    }
+    match lower.strip_prefix('f').map(str::parse::<u8>) {
+        Some(Ok(n)) if (1..=12).contains(&n) => Ok(KeyCode::F(n)),
+        _ => Err(anyhow::anyhow!(
+            "unknown key '{}'; use a single character, f1-f12, or one of {}",
+            text,
+            KEY_NAMES
+                .iter()
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remapped_action_replaces_default_key() {
+        let bindings = BTreeMap::from([
+            ("quit".to_string(), "x".to_string()),
+            ("run".to_string(), "Space".to_string()),
+        ]);
+        let keymap = KeyMap::with_bindings(&bindings).unwrap();
+        assert_eq!(keymap.action(KeyCode::Char('x')), Some(Action::Quit));
+        assert_eq!(keymap.action(KeyCode::Char('q')), None);
+        assert_eq!(keymap.action(KeyCode::Char(' ')), Some(Action::Run));
+        assert_eq!(keymap.action(KeyCode::Char('h')), Some(Action::Help));
+        assert_eq!(keymap.key_name(Action::Run), "space");
+        assert_eq!(keymap.key_name(Action::Back), "esc");
+        assert_eq!(parse_key("F5").unwrap(), KeyCode::F(5));
+        assert!(parse_key("hyper").is_err());
+        let unknown = BTreeMap::from([("fly".to_string(), "f".to_string())]);
+        assert!(KeyMap::with_bindings(&unknown).is_err());
+        let ctrl_c = KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL);
+        assert!(is_ctrl_c(&ctrl_c));
+        assert!(!is_ctrl_c(&KeyEvent::new(
+            KeyCode::Char('c'),
+            KeyModifiers::NONE
+        )));
+    }
+}
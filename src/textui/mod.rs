@@ -12,48 +12,310 @@
 // see the license for the specific language governing permissions and
 // limitations under the license.
 
+mod keys;
 pub mod terminal;
 mod ui;
+mod widgets;
 
 use anyhow::Result;
+use keys::KeyMap;
 use matrix65::filehost;
-use matrix65::{io, serial};
-use serialport::SerialPort;
-use ui::{StatefulList, StatefulTable};
+use matrix65::{io, LoadAddress, M65Communicator};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tui::layout::Rect;
+use widgets::{StatefulList, StatefulTable};
 
 /// Specified the currently active widget of the TUI
-#[derive(PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum AppWidgets {
     FileSelector,
     FileAction,
     CBMBrowser,
     Help,
+    /// Typing a FileHost search filter
+    Search,
 }
 
+/// Sort order of the files in the CBM disk browser
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CbmSort {
+    /// Order of the disk directory
+    Directory,
+    Name,
+    Type,
+    Size,
+}
+
+impl CbmSort {
+    /// Next sort order when cycling through them
+    fn next(self) -> CbmSort {
+        match self {
+            CbmSort::Directory => CbmSort::Name,
+            CbmSort::Name => CbmSort::Type,
+            CbmSort::Type => CbmSort::Size,
+            CbmSort::Size => CbmSort::Directory,
+        }
+    }
+}
+
+impl fmt::Display for CbmSort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            CbmSort::Directory => "directory order",
+            CbmSort::Name => "name",
+            CbmSort::Type => "type",
+            CbmSort::Size => "size",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+/// Sort key of the FileHost table
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FilehostSort {
+    /// Most recently published first
+    Newest,
+    Title,
+    Author,
+    Type,
+    /// Highest rating first
+    Rating,
+    /// Most downloaded first
+    Downloads,
+}
+
+impl FilehostSort {
+    /// Next sort key when cycling through them
+    fn next(self) -> FilehostSort {
+        match self {
+            FilehostSort::Newest => FilehostSort::Title,
+            FilehostSort::Title => FilehostSort::Author,
+            FilehostSort::Author => FilehostSort::Type,
+            FilehostSort::Type => FilehostSort::Rating,
+            FilehostSort::Rating => FilehostSort::Downloads,
+            FilehostSort::Downloads => FilehostSort::Newest,
+        }
+    }
+
+    /// Whether the natural order, before reversing, is ascending
+    const fn ascending(self) -> bool {
+        matches!(
+            self,
+            FilehostSort::Title | FilehostSort::Author | FilehostSort::Type
+        )
+    }
+
+    /// Compare two records by this key in ascending order
+    fn compare(self, a: &filehost::Record, b: &filehost::Record) -> Ordering {
+        match self {
+            FilehostSort::Newest => a.sortdate.cmp(&b.sortdate),
+            FilehostSort::Title => a.title.to_lowercase().cmp(&b.title.to_lowercase()),
+            FilehostSort::Author => a.author.to_lowercase().cmp(&b.author.to_lowercase()),
+            FilehostSort::Type => a.kind.to_lowercase().cmp(&b.kind.to_lowercase()),
+            FilehostSort::Rating => compare_numeric(&a.rating, &b.rating),
+            FilehostSort::Downloads => compare_numeric(&a.downloads, &b.downloads),
+        }
+    }
+}
+
+impl fmt::Display for FilehostSort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            FilehostSort::Newest => "date",
+            FilehostSort::Title => "title",
+            FilehostSort::Author => "author",
+            FilehostSort::Type => "type",
+            FilehostSort::Rating => "rating",
+            FilehostSort::Downloads => "downloads",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+/// Compare text fields as numbers where possible, otherwise lexically
+///
+/// Other text orders before numbers, so the ordering stays consistent
+/// and non-numeric values end up last in the natural, descending order.
+fn compare_numeric(a: &str, b: &str) -> Ordering {
+    match (a.trim().parse::<f64>(), b.trim().parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.total_cmp(&b),
+        (Ok(_), Err(_)) => Ordering::Greater,
+        (Err(_), Ok(_)) => Ordering::Less,
+        (Err(_), Err(_)) => a.cmp(b),
+    }
+}
+
+/// Progress of a long running operation, shown while busy
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Progress {
+    /// What is being done, e.g. "Transferring"
+    pub label: String,
+    /// Percent done (0-100) or `None` if unknown
+    pub percent: Option<u16>,
+    /// Processed and total count, e.g. bytes, if known
+    pub count: Option<(usize, usize)>,
+}
+
+impl Progress {
+    /// Progress with unknown completion
+    pub fn indeterminate(label: &str) -> Progress {
+        Progress {
+            label: label.to_string(),
+            percent: None,
+            count: None,
+        }
+    }
+
+    /// Progress from a count of processed items, e.g. bytes
+    pub fn from_count(label: &str, done: usize, total: usize) -> Progress {
+        let percent = match total {
+            0 => 100,
+            _ => (done.min(total) * 100 / total) as u16,
+        };
+        Progress {
+            label: label.to_string(),
+            percent: Some(percent),
+            count: Some((done.min(total), total)),
+        }
+    }
+}
+
+/// Blocking MEGA65 operation carried out by the worker thread
+pub enum Job {
+    /// Reset the MEGA65
+    Reset,
+    /// Download PRG file, then transfer and run it
+    RunPrg { url: String, reset_before_run: bool },
+    /// Transfer and run an already loaded program
+    RunBytes {
+        load_address: LoadAddress,
+        bytes: Vec<u8>,
+        reset_before_run: bool,
+    },
+    /// Download file and save it without overwriting an existing file
+    Download { url: String, filename: String },
+}
+
+impl Job {
+    /// Carry out job, returning an optional status message
+    pub fn execute(
+        self,
+        comm: &mut dyn M65Communicator,
+        progress: &mut dyn FnMut(Progress),
+    ) -> Result<Option<String>> {
+        match self {
+            Job::Reset => {
+                comm.reset()?;
+                Ok(Some("Reset MEGA65".to_string()))
+            }
+            Job::RunPrg {
+                url,
+                reset_before_run,
+            } => {
+                progress(Progress::indeterminate("Downloading"));
+                let (load_address, bytes) = io::load_prg(&url)?;
+                let job = Job::RunBytes {
+                    load_address,
+                    bytes,
+                    reset_before_run,
+                };
+                job.execute(comm, progress)
+            }
+            Job::RunBytes {
+                load_address,
+                bytes,
+                reset_before_run,
+            } => {
+                comm.handle_prg_from_bytes_with_progress(
+                    &bytes,
+                    load_address,
+                    reset_before_run,
+                    true,
+                    false,
+                    &mut |done, total| progress(Progress::from_count("Transferring", done, total)),
+                )?;
+                Ok(None)
+            }
+            Job::Download { url, filename } => {
+                progress(Progress::indeterminate("Downloading"));
+                let bytes = io::load_bytes_url(&url)?;
+                let mut file = std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&filename)
+                    .map_err(|err| match err.kind() {
+                        std::io::ErrorKind::AlreadyExists => {
+                            anyhow::anyhow!("{} already exists; not overwriting", filename)
+                        }
+                        _ => anyhow::anyhow!("cannot create {}: {}", filename, err),
+                    })?;
+                file.write_all(&bytes)?;
+                Ok(Some(format!("Saved {} ({} bytes)", filename, bytes.len())))
+            }
+        }
+    }
+}
+
+/// Two clicks on the same row within this time open the file actions
+const DOUBLE_CLICK_TIME: Duration = Duration::from_millis(400);
+
 pub struct App {
     /// Holds the active widget
     active_widget: AppWidgets,
     /// Set to true when UI is unresponsive
     busy: bool,
-    /// Browser for files CBM disk images (d81 etc)
+    /// Progress shown while busy
+    progress: Option<Progress>,
+    /// Browser for files in CBM disk images (d81 etc) and tape archives (t64)
     cbm_browser: StatefulList<String>,
+    /// Files on the selected CBM disk in directory order
+    cbm_entries: Vec<io::DirEntry>,
+    /// Directory index of each row in `cbm_browser`
+    cbm_index: Vec<usize>,
+    /// Sort order of the CBM browser
+    cbm_sort: CbmSort,
     /// Selected CBM disk
     cbm_disk: Option<Box<dyn cbm::disk::Disk>>,
+    /// Files of the selected T64 tape archive
+    tape_files: Option<Vec<io::TapeFile>>,
+    /// Disk image or tape archive to download in the background and browse
+    image_to_open: Option<String>,
     /// Browser for actions on a single file
     file_action: StatefulList<String>,
     /// FileHost file browser
     filetable: StatefulTable<filehost::Record>,
+    /// All FileHost records; `filetable` holds those matching `filter`
+    all_items: Vec<filehost::Record>,
+    /// Case-insensitive search in title and author
+    filter: String,
+    /// Screen area of the filehost table, as last drawn
+    files_area: Rect,
+    /// Index of the first visible row of the filehost table
+    files_offset: usize,
+    /// Row and time of the previous mouse click, to detect double-clicks
+    last_click: Option<(usize, Instant)>,
+    /// Key bindings
+    keys: KeyMap,
+    /// Show FileHost screenshots; needs a terminal with 24-bit colour
+    show_screenshots: bool,
+    /// Screenshots by URL; `None` while loading or if unavailable
+    screenshots: HashMap<String, Option<image::RgbImage>>,
     /// Status messages presented in the UI
     messages: Vec<String>,
-    /// Serial port to communicate on
-    port: Box<dyn SerialPort>,
-    /// Determines how to sort the filehost table
-    toggle_sort: bool,
+    /// Sort key of the filehost table
+    filehost_sort: FilehostSort,
+    /// Reverse the natural order of the sort key
+    filehost_reversed: bool,
 }
 
 impl App {
-    fn new(port: &mut Box<dyn SerialPort>, filehost_items: &[filehost::Record]) -> App {
-        App {
+    fn new(filehost_items: &[filehost::Record]) -> App {
+        let mut app = App {
             messages: vec![
                 "Matrix65 welcomes you to the FileHost!".to_string(),
                 "Press 'h' for help".to_string(),
@@ -63,44 +325,162 @@ impl App {
                 "Run".to_string(),
                 "Reset and Run".to_string(),
                 "Open CBM disk...".to_string(),
+                "Download to disk".to_string(),
                 "Cancel".to_string(),
             ]),
             busy: false,
+            progress: None,
             filetable: StatefulTable::with_items(filehost_items.to_vec()),
-            port: port.try_clone().unwrap(),
-            toggle_sort: false,
+            all_items: filehost_items.to_vec(),
+            filter: String::new(),
+            files_area: Rect::default(),
+            files_offset: 0,
+            last_click: None,
+            keys: KeyMap::default(),
+            show_screenshots: false,
+            screenshots: HashMap::new(),
+            filehost_sort: FilehostSort::Newest,
+            filehost_reversed: false,
             cbm_disk: None,
+            tape_files: None,
+            image_to_open: None,
             cbm_browser: StatefulList::with_items(Vec::<String>::new()),
-        }
+            cbm_entries: Vec::new(),
+            cbm_index: Vec::new(),
+            cbm_sort: CbmSort::Directory,
+        };
+        app.sort_filehost();
+        app
     }
 
     pub fn set_current_widget(&mut self, widget: AppWidgets) {
         self.active_widget = widget;
     }
 
-    /// Populate and activate CBM disk browser
-    fn activate_cbm_browser(&mut self) -> Result<()> {
-        self.busy = false;
+    /// Populate and activate CBM disk browser from the downloaded `image` at `url`
+    ///
+    /// Files are initially listed in directory order, i.e. the same
+    /// order used by `io::cbm_load_file()` to pick the file to load.
+    fn activate_cbm_browser(&mut self, url: &str, image: &[u8]) -> Result<()> {
         self.set_current_widget(AppWidgets::CBMBrowser);
-        let url = self.selected_url();
-        self.cbm_disk = Some(io::cbm_open(&url)?);
-        if self.cbm_disk.is_some() {
-            let dir = self.cbm_disk.as_ref().unwrap().directory()?;
-            let files: Vec<String> = dir
-                .iter()
-                .map(|i| format!("{}.{}", i.filename.to_string(), i.file_attributes.file_type))
-                .collect();
-            self.cbm_browser.items = files;
+        if url.to_lowercase().ends_with(".t64") {
+            let files = io::t64_files(image)?;
+            self.cbm_entries = files.iter().map(io::DirEntry::from).collect();
+            self.tape_files = Some(files);
+            self.cbm_sort = CbmSort::Directory;
+            self.update_cbm_browser();
+            return Ok(());
+        }
+        self.cbm_disk = Some(io::cbm_open_bytes(url, image)?);
+        if let Some(disk) = &self.cbm_disk {
+            self.cbm_entries = io::cbm_disk_directory(disk.as_ref())?;
+            self.cbm_sort = CbmSort::Directory;
+            self.update_cbm_browser();
         }
         Ok(())
     }
 
+    /// Fill CBM browser from the disk directory using the current sort order
+    ///
+    /// The displayed rows may be reordered, but `cbm_index` always maps
+    /// each row back to its position in the directory.
+    fn update_cbm_browser(&mut self) {
+        let entries = &self.cbm_entries;
+        let mut order: Vec<usize> = (0..entries.len()).collect();
+        match self.cbm_sort {
+            CbmSort::Directory => {}
+            CbmSort::Name => order.sort_by(|a, b| entries[*a].name.cmp(&entries[*b].name)),
+            CbmSort::Type => {
+                order.sort_by(|a, b| entries[*a].file_type.cmp(&entries[*b].file_type))
+            }
+            CbmSort::Size => order.sort_by_key(|i| entries[*i].blocks),
+        }
+        // keep the same file highlighted after sorting
+        let selected = self
+            .cbm_browser
+            .state
+            .selected()
+            .and_then(|row| self.cbm_index.get(row).copied());
+        self.cbm_browser.items = order.iter().map(|i| entries[*i].to_string()).collect();
+        if let Some(index) = selected {
+            let row = order.iter().position(|i| *i == index);
+            self.cbm_browser.state.select(row);
+        }
+        self.cbm_index = order;
+    }
+
+    /// Cycle sort order of the CBM browser
+    fn sort_cbm_browser(&mut self) {
+        self.cbm_sort = self.cbm_sort.next();
+        self.update_cbm_browser();
+    }
+
+    /// Filehost row shown at a screen position, if any
+    ///
+    /// Rows are one line high and start below the border and header.
+    pub fn filehost_row_at(&self, column: u16, row: u16) -> Option<usize> {
+        let area = self.files_area;
+        let first_row = area.y + 2;
+        let inside = column > area.x
+            && column + 1 < area.x + area.width
+            && row >= first_row
+            && row + 1 < area.y + area.height;
+        let index = self.files_offset + (row.checked_sub(first_row)? as usize);
+        (inside && index < self.filetable.items.len()).then_some(index)
+    }
+
+    /// Select filehost row by mouse; a double-click opens the file actions
+    pub fn click(&mut self, column: u16, row: u16) -> Result<()> {
+        if self.active_widget != AppWidgets::FileSelector {
+            return Ok(());
+        }
+        let index = match self.filehost_row_at(column, row) {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        self.filetable.state.select(Some(index));
+        match self.last_click.take() {
+            Some((previous, time)) if previous == index && time.elapsed() < DOUBLE_CLICK_TIME => {
+                self.select_filehost_item()
+            }
+            _ => {
+                self.last_click = Some((index, Instant::now()));
+                Ok(())
+            }
+        }
+    }
+
+    /// Cycle sort order of items in the current widget
+    pub fn sort(&mut self) {
+        match self.active_widget {
+            AppWidgets::CBMBrowser => self.sort_cbm_browser(),
+            _ => {
+                self.filehost_sort = self.filehost_sort.next();
+                self.filehost_reversed = false;
+                self.sort_filehost();
+            }
+        }
+    }
+
+    /// Reverse sort direction of the FileHost table
+    pub fn reverse_sort(&mut self) {
+        if self.active_widget != AppWidgets::CBMBrowser {
+            self.filehost_reversed = !self.filehost_reversed;
+            self.sort_filehost();
+        }
+    }
+
+    /// Whether the FileHost table is currently sorted in ascending order
+    pub fn filehost_ascending(&self) -> bool {
+        self.filehost_sort.ascending() != self.filehost_reversed
+    }
+
     /// Go to previous item in current widget (typically when pressing arrow up)
     pub fn previous_item(&mut self) {
         match self.active_widget {
             AppWidgets::CBMBrowser => self.cbm_browser.previous(),
             AppWidgets::FileAction => self.file_action.previous(),
-            AppWidgets::FileSelector => self.filetable.previous(),
+            AppWidgets::FileSelector | AppWidgets::Search => self.filetable.previous(),
             _ => {}
         }
     }
@@ -110,7 +490,7 @@ impl App {
         match self.active_widget {
             AppWidgets::CBMBrowser => self.cbm_browser.next(),
             AppWidgets::FileAction => self.file_action.next(),
-            AppWidgets::FileSelector => self.filetable.next(),
+            AppWidgets::FileSelector | AppWidgets::Search => self.filetable.next(),
             _ => {}
         }
     }
@@ -122,6 +502,9 @@ impl App {
 
     /// Select currently highlighted file in FileHost browser
     fn select_filehost_item(&mut self) -> Result<(), anyhow::Error> {
+        if self.filetable.items.is_empty() {
+            return Err(anyhow::Error::msg("No files match the search"));
+        }
         // when selecting file, go to file action widget
         self.active_widget = AppWidgets::FileAction;
         if !self.file_action.is_selected() {
@@ -131,29 +514,44 @@ impl App {
     }
 
     /// Select currently highlighted action in file action widget
-    fn select_file_action(&mut self) -> Result<(), anyhow::Error> {
+    ///
+    /// Returns a job if the action requires the MEGA65.
+    fn select_file_action(&mut self) -> Result<Option<Job>, anyhow::Error> {
         // when done, return to filehost browser
         self.set_current_widget(AppWidgets::FileSelector);
-        match self.file_action.state.selected() {
-            Some(0) => self.run(false)?, // run
-            Some(1) => self.run(true)?,  // reset, then run
-            Some(2) => self.activate_cbm_browser()?,
-            _ => {}
+        let job = match self.file_action.state.selected() {
+            Some(0) => Some(self.run(false)?), // run
+            Some(1) => Some(self.run(true)?),  // reset, then run
+            Some(2) => {
+                // downloaded by the event loop, which then opens the browser
+                self.image_to_open = Some(self.selected_url());
+                None
+            }
+            Some(3) => Some(self.download()?),
+            _ => None,
         };
         self.file_action.unselect();
-        Ok(())
+        Ok(job)
     }
 
     /// Select currently highlighted item in CBM browser
-    fn select_cbm_item(&mut self) -> Result<(), anyhow::Error> {
+    ///
+    /// Only PRG files can be run; other file types are listed for
+    /// inspection only and leave the browser open.
+    fn select_cbm_item(&mut self) -> Result<Option<Job>, anyhow::Error> {
         match self.cbm_browser.state.selected() {
+            Some(row) if !self.cbm_entries[self.cbm_index[row]].is_prg() => {
+                let entry = &self.cbm_entries[self.cbm_index[row]];
+                let message = format!("Cannot run {} file {}", entry.file_type, entry.name);
+                self.add_message(&message);
+                Ok(None)
+            }
             Some(_) => {
-                self.run(false)?;
-                self.busy = false;
+                let job = self.run(false)?;
                 self.active_widget = AppWidgets::FileSelector;
                 self.cbm_browser.unselect();
                 self.file_action.unselect();
-                Ok(())
+                Ok(Some(job))
             }
             None => Err(anyhow::Error::msg("No CBM file selected")),
         }
@@ -185,15 +583,86 @@ impl App {
         //self.messages.clear();
     }
 
-    /// Toggles filehost file sorting by date or title
+    /// Sort filehost records by the current key and direction
     fn sort_filehost(&mut self) {
-        if self.toggle_sort {
-            self.filetable.items.sort_by_key(|i| i.published.clone());
-            self.filetable.items.reverse();
-        } else {
-            self.filetable.items.sort_by_key(|i| i.title.clone());
+        let sort = self.filehost_sort;
+        match self.filehost_ascending() {
+            true => self.all_items.sort_by(|a, b| sort.compare(a, b)),
+            false => self.all_items.sort_by(|a, b| sort.compare(b, a)),
+        }
+        self.apply_filter();
+    }
+
+    /// Start typing a search filter, continuing any current filter
+    pub fn start_search(&mut self) {
+        self.set_current_widget(AppWidgets::Search);
+    }
+
+    /// Add character to the search filter
+    pub fn push_search(&mut self, c: char) {
+        self.filter.push(c);
+        self.apply_filter();
+    }
+
+    /// Remove last character of the search filter
+    pub fn pop_search(&mut self) {
+        self.filter.pop();
+        self.apply_filter();
+    }
+
+    /// Stop typing and keep the filter
+    pub fn finish_search(&mut self) {
+        self.set_current_widget(AppWidgets::FileSelector);
+    }
+
+    /// Stop typing and show all files again
+    pub fn clear_search(&mut self) {
+        self.filter.clear();
+        self.apply_filter();
+        self.set_current_widget(AppWidgets::FileSelector);
+    }
+
+    /// Show only FileHost records matching the filter
+    ///
+    /// The selection is clamped to the, possibly shorter, filtered list.
+    fn apply_filter(&mut self) {
+        let filter = self.filter.to_lowercase();
+        self.filetable.items = self
+            .all_items
+            .iter()
+            .filter(|item| {
+                item.title.to_lowercase().contains(&filter)
+                    || item.author.to_lowercase().contains(&filter)
+            })
+            .cloned()
+            .collect();
+        let selected = match self.filetable.items.len() {
+            0 => None,
+            len => Some(self.filetable.state.selected().unwrap_or(0).min(len - 1)),
+        };
+        self.filetable.state.select(selected);
+    }
+
+    /// Screenshot of the selected file, if loaded
+    fn selected_screenshot(&self) -> Option<&image::RgbImage> {
+        let url = self.selected_record()?.screenshot_url()?;
+        self.screenshots.get(&url)?.as_ref()
+    }
+
+    /// Screenshot URL of the selected file that has not been requested yet
+    pub fn missing_screenshot(&self) -> Option<String> {
+        if !self.show_screenshots {
+            return None;
         }
-        self.toggle_sort = !self.toggle_sort;
+        self.selected_record()?
+            .screenshot_url()
+            .filter(|url| !self.screenshots.contains_key(url))
+    }
+
+    /// Highlighted FileHost record, if any match the search
+    fn selected_record(&self) -> Option<&filehost::Record> {
+        let sel = self.filetable.state.selected().unwrap_or(0);
+        self.filetable.items.get(sel)
     }
 
     pub fn selected_url(&self) -> String {
@@ -202,35 +671,83 @@ impl App {
         format!("https://files.mega65.org/{}", &item.location)
     }
 
-    /// Transfer and run selected file
-    pub fn run(&mut self, reset_before_run: bool) -> Result<()> {
+    /// Prepare job to save the selected file to the current directory
+    pub fn download(&self) -> Result<Job> {
+        let sel = self.filetable.state.selected().unwrap_or(0);
+        // only keep the final path component of the remote file name
+        let filename = Path::new(&self.filetable.items[sel].filename)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow::Error::msg("Selection has no valid file name"))?;
+        Ok(Job::Download {
+            url: self.selected_url(),
+            filename: filename.to_string(),
+        })
+    }
+
+    /// Prepare job to transfer and run selected file
+    ///
+    /// Files inside CBM disk images are extracted right away as the
+    /// disk has already been downloaded.
+    pub fn run(&mut self, reset_before_run: bool) -> Result<Job> {
         let url = self.selected_url();
         if url.ends_with(".prg") {
-            serial::handle_prg(&mut self.port, &url, reset_before_run, true)?;
-        } else if url.ends_with(".d81") & self.cbm_disk.is_some() & self.cbm_browser.is_selected() {
-            let selected_file = self.cbm_browser.state.selected().unwrap();
+            Ok(Job::RunPrg {
+                url,
+                reset_before_run,
+            })
+        } else if io::DiskFormat::from_filename(&url).is_some()
+            & self.cbm_disk.is_some()
+            & self.cbm_browser.is_selected()
+        {
+            let selected_row = self.cbm_browser.state.selected().unwrap();
+            let selected_file = self.cbm_index[selected_row];
             let (load_address, bytes) =
                 io::cbm_load_file(self.cbm_disk.as_ref().unwrap().as_ref(), selected_file)?;
-            serial::handle_prg_from_bytes(
-                &mut self.port,
-                &bytes,
+            self.cbm_browser.unselect();
+            self.cbm_disk = None;
+            Ok(Job::RunBytes {
                 load_address,
+                bytes,
                 reset_before_run,
-                true,
-            )?;
+            })
+        } else if url.to_lowercase().ends_with(".t64")
+            & self.tape_files.is_some()
+            & self.cbm_browser.is_selected()
+        {
+            let selected_row = self.cbm_browser.state.selected().unwrap();
+            let file = self
+                .tape_files
+                .take()
+                .unwrap()
+                .swap_remove(self.cbm_index[selected_row]);
             self.cbm_browser.unselect();
-            self.cbm_disk = None;
+            Ok(Job::RunBytes {
+                load_address: LoadAddress::new(file.load_address),
+                bytes: file.bytes,
+                reset_before_run,
+            })
         } else {
-            return Err(anyhow::Error::msg("Cannot run selection"));
+            Err(anyhow::Error::msg("Cannot run selection"))
         }
-        Ok(())
     }
 
-    /// Send reset signal to MEGA65
-    pub fn reset(&mut self) -> Result<()> {
-        crate::serial::reset(&mut self.port)?;
-        self.add_message("Reset MEGA65");
-        Ok(())
+    /// Show outcome of a finished job
+    fn finish_job(&mut self, result: Result<Option<String>>) {
+        self.busy = false;
+        self.progress = None;
+        match result {
+            Ok(Some(message)) => self.add_message(&message),
+            Ok(None) => {}
+            Err(error) => self.show_error(&error),
+        }
+    }
+
+    /// Show error in the message widget and return to the file selector
+    fn show_error(&mut self, error: &anyhow::Error) {
+        self.add_message(error.to_string().as_str());
+        self.active_widget = AppWidgets::FileSelector;
+        self.unselect_all();
     }
 
     /// Unselect any selected CBM and file action
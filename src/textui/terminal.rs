@@ -13,26 +13,46 @@
 // limitations under the license.
 
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
+    event::{
+        self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, MouseButton, MouseEventKind,
+    },
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 
-use crate::textui::{ui, App, AppWidgets};
+use crate::textui::keys::{is_ctrl_c, Action, KeyMap};
+use crate::textui::{ui, App, AppWidgets, Job, Progress};
 use anyhow::Result;
 use matrix65::filehost;
-use serialport::SerialPort;
+use matrix65::{CancelToken, M65Communicator};
+use std::collections::BTreeMap;
 use std::io;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
 use tui::{
     backend::{Backend, CrosstermBackend},
     Terminal,
 };
 
+/// How often to redraw and check for worker updates while waiting for keys
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Messages from the worker thread to the UI
+enum WorkerEvent {
+    Progress(Progress),
+    Done(Result<Option<String>>),
+}
+
 /// This is the first entry for the TUI
+///
+/// `key_bindings` remaps actions to keys, e.g. "quit" to "x".
 pub fn start_tui(
-    port: &mut Box<dyn SerialPort>,
+    comm: &mut dyn M65Communicator,
     filehost_items: &[filehost::Record],
+    key_bindings: &BTreeMap<String, String>,
 ) -> Result<()> {
+    let keys = KeyMap::with_bindings(key_bindings)?;
     // setup terminal
     enable_raw_mode()?;
     let mut stdout = io::stdout();
@@ -40,9 +60,19 @@ pub fn start_tui(
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
-    // create app and run it
-    let app = App::new(port, filehost_items);
-    let res = run_app(&mut terminal, app);
+    // create app and run it while a worker thread handles the MEGA65
+    let cancel = CancelToken::default();
+    comm.set_cancel_token(cancel.clone());
+    let mut app = App::new(filehost_items);
+    app.keys = keys;
+    app.show_screenshots = supports_true_color();
+    let res = thread::scope(|scope| {
+        let (job_sender, jobs) = mpsc::channel();
+        let (event_sender, events) = mpsc::channel();
+        scope.spawn(move || worker(comm, jobs, event_sender));
+        run_app(&mut terminal, app, &job_sender, &events, &cancel)
+    });
+    cancel.reset();
 
     // restore terminal
     disable_raw_mode()?;
@@ -59,46 +89,184 @@ pub fn start_tui(
     Ok(())
 }
 
-fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> Result<()> {
+/// Whether the terminal claims 24-bit colour support, needed to draw screenshots
+fn supports_true_color() -> bool {
+    matches!(
+        std::env::var("COLORTERM").as_deref(),
+        Ok("truecolor") | Ok("24bit")
+    )
+}
+
+/// Carry out jobs one at a time until the UI hangs up
+fn worker(comm: &mut dyn M65Communicator, jobs: Receiver<Job>, events: Sender<WorkerEvent>) {
+    for job in jobs {
+        let result = job.execute(comm, &mut |progress| {
+            let _ = events.send(WorkerEvent::Progress(progress));
+        });
+        if events.send(WorkerEvent::Done(result)).is_err() {
+            break;
+        }
+    }
+}
+
+/// Event loop of the TUI
+///
+/// Blocking MEGA65 operations are sent as jobs to the worker thread
+/// and disk images are downloaded on a thread of their own, so that the
+/// UI stays responsive; while busy, Esc or Ctrl-C cancels the job.
+fn run_app<B: Backend>(
+    terminal: &mut Terminal<B>,
+    mut app: App,
+    jobs: &Sender<Job>,
+    events: &Receiver<WorkerEvent>,
+    cancel: &CancelToken,
+) -> Result<()> {
+    let (screenshot_sender, screenshots) = mpsc::channel();
+    let (image_sender, images) = mpsc::channel();
+    // Image being downloaded for the CBM browser
+    let mut downloading: Option<String> = None;
     loop {
+        while let Ok(event) = events.try_recv() {
+            match event {
+                WorkerEvent::Progress(progress) => app.progress = Some(progress),
+                WorkerEvent::Done(result) => app.finish_job(result),
+            }
+        }
+
+        // Screenshots are fetched in the background and skipped if that fails
+        if let Some(url) = app.missing_screenshot() {
+            app.screenshots.insert(url.clone(), None);
+            let sender = screenshot_sender.clone();
+            thread::spawn(move || {
+                let image = filehost::get_screenshot(&url).ok();
+                let _ = sender.send((url, image));
+            });
+        }
+        while let Ok((url, image)) = screenshots.try_recv() {
+            app.screenshots.insert(url, image);
+        }
+
+        // Cancelled downloads finish in the background and are then ignored
+        if let Some(url) = app.image_to_open.take() {
+            app.busy = true;
+            app.progress = Some(Progress::indeterminate("Downloading"));
+            cancel.reset();
+            downloading = Some(url.clone());
+            let sender = image_sender.clone();
+            thread::spawn(move || {
+                let image = matrix65::io::load_bytes(&url).map_err(anyhow::Error::from);
+                let _ = sender.send((url, image));
+            });
+        }
+        if downloading.is_some() && cancel.is_cancelled() {
+            downloading = None;
+            app.finish_job(Ok(Some("Download cancelled".to_string())));
+        }
+        while let Ok((url, image)) = images.try_recv() {
+            if downloading.as_ref() == Some(&url) {
+                downloading = None;
+                app.finish_job(Ok(None));
+                if let Err(error) = image.and_then(|image| app.activate_cbm_browser(&url, &image)) {
+                    app.show_error(&error);
+                }
+            }
+        }
+
         terminal.draw(|f| ui::ui(f, &mut app))?;
 
-        if let Event::Key(key) = event::read()? {
-            match key.code {
-                KeyCode::Char('q') => return Ok(()),
-                KeyCode::Char('h') => app.toggle_help(),
-                KeyCode::Char('s') => app.sort_filehost(),
-                KeyCode::Esc => app.return_to_filehost(),
-                KeyCode::Up => app.previous_item(),
-                KeyCode::Down => app.next_item(),
-                KeyCode::Enter => {
-                    if app.cbm_browser.is_selected() | app.file_action.is_selected() {
-                        app.busy = true;
-                        terminal.draw(|f| ui::ui(f, &mut app))?;
-                    } else {
-                        app.busy = false;
+        if !event::poll(POLL_INTERVAL)? {
+            continue;
+        }
+        let event = event::read()?;
+        if let Event::Mouse(mouse) = event {
+            if !app.busy {
+                match mouse.kind {
+                    MouseEventKind::Down(MouseButton::Left) => {
+                        if let Err(error) = app.click(mouse.column, mouse.row) {
+                            app.show_error(&error);
+                        }
                     }
+                    MouseEventKind::ScrollDown => app.next_item(),
+                    MouseEventKind::ScrollUp => app.previous_item(),
+                    _ => {}
+                }
+            }
+            continue;
+        }
+        if let Event::Key(key) = event {
+            let action = match (is_ctrl_c(&key), app.busy) {
+                (true, true) => Some(Action::Back),
+                (true, false) => return Ok(()),
+                _ => app.keys.action(key.code),
+            };
+            if app.busy {
+                match action {
+                    Some(Action::Back) => {
+                        cancel.cancel();
+                        app.add_message("Cancelling...");
+                    }
+                    Some(Action::Quit) => {
+                        cancel.cancel();
+                        return Ok(());
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            if app.active_widget == AppWidgets::Search {
+                match key.code {
+                    KeyCode::Char(c) => app.push_search(c),
+                    KeyCode::Backspace => app.pop_search(),
+                    KeyCode::Enter => app.finish_search(),
+                    KeyCode::Esc => app.clear_search(),
+                    KeyCode::Up => app.previous_item(),
+                    KeyCode::Down => app.next_item(),
+                    _ => {}
+                }
+                continue;
+            }
+            match action {
+                Some(Action::Quit) => return Ok(()),
+                Some(Action::Help) => app.toggle_help(),
+                Some(Action::Search) => app.start_search(),
+                Some(Action::Sort) => app.sort(),
+                Some(Action::ReverseSort) => app.reverse_sort(),
+                Some(Action::Back) => app.return_to_filehost(),
+                Some(Action::Up) => app.previous_item(),
+                Some(Action::Down) => app.next_item(),
+                Some(Action::Run)
+                    if app.cbm_browser.is_selected() | app.file_action.is_selected() =>
+                {
+                    app.busy = true;
+                    terminal.draw(|f| ui::ui(f, &mut app))?;
                 }
                 _ => {}
             }
             // These operations *may* fail (invalid port, corrupted file etc.)
-            let result = match key.code {
-                KeyCode::Char('R') => app.reset(),
-                KeyCode::Enter => match app.active_widget {
-                    AppWidgets::FileSelector => app.select_filehost_item(),
+            let result = match action {
+                Some(Action::Reset) => Ok(Some(Job::Reset)),
+                Some(Action::Run) => match app.active_widget {
+                    AppWidgets::FileSelector => app.select_filehost_item().map(|_| None),
                     AppWidgets::FileAction => app.select_file_action(),
                     AppWidgets::CBMBrowser => app.select_cbm_item(),
-                    _ => Ok(()),
+                    _ => Ok(None),
                 },
-                _ => Ok(()),
+                _ => Ok(None),
             };
-            // Gracefully recover and show error in the msg widget
+            // Hand over to the worker or gracefully recover and show error in the msg widget
             match result {
-                Ok(()) => {}
+                Ok(Some(job)) => {
+                    app.busy = true;
+                    app.progress = Some(Progress::indeterminate("Working"));
+                    cancel.reset();
+                    jobs.send(job)
+                        .map_err(|_| anyhow::Error::msg("worker thread has stopped"))?;
+                }
+                Ok(None) => app.busy = false,
                 Err(error) => {
-                    app.add_message(error.to_string().as_str());
-                    app.active_widget = AppWidgets::FileSelector;
-                    app.unselect_all();
+                    app.busy = false;
+                    app.progress = None;
+                    app.show_error(&error);
                 }
             }
         }
@@ -18,13 +18,14 @@ use tui::{
     style::{Color, Modifier, Style},
     text::{Span, Spans},
     widgets::{
-        Block, BorderType, Borders, Cell, Clear, List, ListItem, ListState, Paragraph, Row, Table,
-        TableState,
+        Block, BorderType, Borders, Cell, Clear, Gauge, List, ListItem, Paragraph, Row, Table,
     },
     Frame,
 };
 
-use crate::textui::{App, AppWidgets};
+use crate::textui::keys::{Action, KeyMap};
+use crate::textui::widgets::{StatefulList, StatefulTable};
+use crate::textui::{App, AppWidgets, CbmSort, FilehostSort, Progress};
 use matrix65::filehost;
 
 pub fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
@@ -33,7 +34,29 @@ pub fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
         .constraints([Constraint::Min(4), Constraint::Length(8)].as_ref())
         .split(f.size());
 
-    let files_widget = make_files_widget(&app.filetable.items);
+    let arrow = match app.filehost_ascending() {
+        true => "▲",
+        false => "▼",
+    };
+    let title = match (app.active_widget, app.filter.is_empty()) {
+        (AppWidgets::Search, _) => format!("🔍 Search: {}_", app.filter),
+        (_, true) => format!("🌈 Filehost entries by {} {}", app.filehost_sort, arrow),
+        (_, false) => format!(
+            "🌈 Filehost entries matching '{}' by {} {}",
+            app.filter, app.filehost_sort, arrow
+        ),
+    };
+    let header = [
+        (FilehostSort::Title, "Title"),
+        (FilehostSort::Type, "Type"),
+        (FilehostSort::Author, "Author"),
+    ]
+    .map(|(sort, label)| match sort == app.filehost_sort {
+        true => format!("{} {}", label, arrow),
+        false => label.to_string(),
+    });
+    track_files_offset(app, chunks[0]);
+    let files_widget = make_files_widget(&app.filetable.items, header, title);
     f.render_stateful_widget(files_widget, chunks[0], &mut app.filetable.state);
 
     let chunks = Layout::default()
@@ -41,14 +64,26 @@ pub fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
         .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
         .split(chunks[1]);
 
+    let fileinfo_area = match app.selected_screenshot() {
+        Some(screenshot) => {
+            let (widget, width) = make_screenshot_widget(screenshot, chunks[0]);
+            let areas = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Min(0), Constraint::Length(width)].as_ref())
+                .split(chunks[0]);
+            f.render_widget(widget, areas[1]);
+            areas[0]
+        }
+        None => chunks[0],
+    };
     let fileinfo_widget = make_fileinfo_widget(&app.filetable);
-    f.render_widget(fileinfo_widget, chunks[0]);
+    f.render_widget(fileinfo_widget, fileinfo_area);
 
     let messages_widget = make_messages_widget(&app.messages);
     f.render_widget(messages_widget, chunks[1]);
 
     if app.active_widget == AppWidgets::Help {
-        render_help_widget(f);
+        render_help_widget(f, &app.keys);
     }
 
     if app.active_widget == AppWidgets::FileAction {
@@ -56,7 +91,55 @@ pub fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
     }
 
     if app.active_widget == AppWidgets::CBMBrowser {
-        render_cbm_selector_widget(f, &mut app.cbm_browser, app.busy);
+        render_cbm_selector_widget(f, &mut app.cbm_browser, app.cbm_sort, app.busy);
+    }
+
+    if app.busy {
+        if let Some(progress) = &app.progress {
+            render_progress_widget(f, progress, &app.keys.key_name(Action::Back));
+        }
+    }
+}
+
+/// Popup widget with the progress of a transfer or other busy operation
+///
+/// Shows a gauge if the completion is known, otherwise just the label.
+pub fn render_progress_widget<B: Backend>(f: &mut Frame<B>, progress: &Progress, cancel_key: &str) {
+    let area = centered_rect(40, 3, f.size());
+    let block = Block::default()
+        .title(Span::styled(
+            format!("{} ({} or ctrl-c to cancel)", progress.label, cancel_key),
+            Style::default()
+                .add_modifier(Modifier::BOLD)
+                .fg(Color::White),
+        ))
+        .style(Style::default().bg(Color::Blue))
+        .borders(Borders::ALL)
+        .border_type(BorderType::Rounded);
+    f.render_widget(Clear, area);
+    match progress.percent {
+        Some(percent) => {
+            let gauge = Gauge::default()
+                .block(block)
+                .gauge_style(Style::default().fg(Color::White).bg(Color::DarkGray))
+                .percent(percent.min(100));
+            let gauge = match progress.count {
+                Some((done, total)) => gauge.label(format!(
+                    "{} / {} bytes ({}%)",
+                    done,
+                    total,
+                    percent.min(100)
+                )),
+                None => gauge,
+            };
+            f.render_widget(gauge, area);
+        }
+        None => {
+            let paragraph = Paragraph::new("Please wait...")
+                .block(block)
+                .alignment(Alignment::Center);
+            f.render_widget(paragraph, area);
+        }
     }
 }
 
@@ -77,9 +160,27 @@ fn make_messages_widget(app_messages: &[String]) -> List {
     )))
 }
 
+/// Keep track of the first visible filehost row, used to map mouse clicks to rows
+///
+/// This mirrors how `Table` scrolls to keep the selected row visible,
+/// as the offset in `TableState` is private.
+fn track_files_offset(app: &mut App, area: Rect) {
+    app.files_area = area;
+    let visible = area.height.saturating_sub(3).max(1) as usize;
+    let selected = app.filetable.state.selected().unwrap_or(0);
+    let offset = app
+        .files_offset
+        .min(app.filetable.items.len().saturating_sub(1));
+    app.files_offset = if selected >= offset + visible {
+        selected + 1 - visible
+    } else {
+        offset.min(selected)
+    };
+}
+
 /// Popup widget with helful information
-fn render_help_widget<B: Backend>(f: &mut Frame<B>) {
-    let area = centered_rect(50, 10, f.size());
+fn render_help_widget<B: Backend>(f: &mut Frame<B>, keys: &KeyMap) {
+    let area = centered_rect(50, 12, f.size());
     let block = Block::default()
         .title(Span::styled(
             "Help",
@@ -91,7 +192,7 @@ fn render_help_widget<B: Backend>(f: &mut Frame<B>) {
         .style(Style::default().bg(Color::Blue))
         .borders(Borders::ALL)
         .border_type(BorderType::Rounded);
-    let text = vec![
+    let mut text = vec![
         Spans::from(Span::styled(
             "Matrix Mode Serial Communicator for MEGA65\n",
             Style::default()
@@ -103,24 +204,28 @@ fn render_help_widget<B: Backend>(f: &mut Frame<B>) {
             Style::default().fg(Color::White),
         )),
         Spans::from(Span::styled("", Style::default().fg(Color::White))),
-        Spans::from(Span::styled(
-            "Select item (enter)",
-            Style::default().fg(Color::White),
-        )),
-        Spans::from(Span::styled(
-            "Toggle sorting by title or date (s)",
-            Style::default().fg(Color::White),
-        )),
-        Spans::from(Span::styled(
-            "Toggle help (h)",
-            Style::default().fg(Color::White),
-        )),
-        Spans::from(Span::styled(
-            "Reset MEGA65 (R)",
-            Style::default().fg(Color::White),
-        )),
-        Spans::from(Span::styled("Quit (q)", Style::default().fg(Color::White))),
     ];
+    let key = |action| keys.key_name(action);
+    let help = [
+        format!("Select item ({})", key(Action::Run)),
+        format!(
+            "Cycle sorting ({}), reverse direction ({})",
+            key(Action::Sort),
+            key(Action::ReverseSort)
+        ),
+        format!(
+            "Search title and author ({}), clear (esc)",
+            key(Action::Search)
+        ),
+        format!("Toggle help ({})", key(Action::Help)),
+        format!("Reset MEGA65 ({})", key(Action::Reset)),
+        format!("Cancel transfer ({} or ctrl-c)", key(Action::Back)),
+        format!("Quit ({})", key(Action::Quit)),
+    ];
+    text.extend(
+        help.into_iter()
+            .map(|line| Spans::from(Span::styled(line, Style::default().fg(Color::White)))),
+    );
     let paragraph = Paragraph::new(text.clone())
         .block(block)
         .alignment(Alignment::Center);
@@ -130,54 +235,39 @@ fn render_help_widget<B: Backend>(f: &mut Frame<B>) {
 }
 
 /// helper function to create a centered rectangle of given width and height
+///
+/// The rectangle is clamped to fit inside `r`, so popups shrink on small
+/// terminals instead of extending beyond the frame.
 fn centered_rect(width: u16, height: u16, r: Rect) -> Rect {
-    let ymargin = match r.height > height {
-        true => (r.height - height) / 2,
-        false => 1,
-    };
-    let xmargin = match r.width > width {
-        true => (r.width - width) / 2,
-        false => 1,
-    };
-    let popup_layout = Layout::default()
-        .direction(Direction::Vertical)
-        .constraints(
-            [
-                Constraint::Length(ymargin),
-                Constraint::Length(height),
-                Constraint::Length(ymargin),
-            ]
-            .as_ref(),
-        )
-        .split(r);
-
-    Layout::default()
-        .direction(Direction::Horizontal)
-        .constraints(
-            [
-                Constraint::Length(xmargin),
-                Constraint::Length(width),
-                Constraint::Length(xmargin),
-            ]
-            .as_ref(),
-        )
-        .split(popup_layout[1])[1]
+    let width = width.min(r.width);
+    let height = height.min(r.height);
+    Rect::new(
+        r.x + (r.width - width) / 2,
+        r.y + (r.height - height) / 2,
+        width,
+        height,
+    )
 }
 
 /// Widget for selecting files inside CBM disk images
 fn render_cbm_selector_widget<B: Backend>(
     f: &mut Frame<B>,
     file_list: &mut StatefulList<String>,
+    sort: CbmSort,
     busy: bool,
 ) {
     let background_color = match busy {
         true => Color::DarkGray,
         false => Color::Blue,
     };
-    let area = centered_rect(35, 10, f.size());
+    let area = centered_rect(40, 12, f.size());
+    let title = match sort {
+        CbmSort::Directory => "Select file (esc: back)".to_string(),
+        _ => format!("Select file by {} (esc: back)", sort),
+    };
     let block = Block::default()
         .title(Span::styled(
-            "Select file on CBM disk",
+            title,
             Style::default()
                 .add_modifier(Modifier::BOLD)
                 .fg(Color::White),
@@ -244,18 +334,56 @@ fn render_prg_widget<B: Backend>(
     f.render_stateful_widget(list, area, &mut action_list.state);
 }
 
+/// Screenshot drawn with half blocks, two pixels per cell, and its width in cells
+///
+/// The image is scaled to the height of `area` keeping its aspect
+/// ratio, but takes up at most half the width.
+fn make_screenshot_widget(screenshot: &image::RgbImage, area: Rect) -> (Paragraph<'_>, u16) {
+    let rows = area.height.saturating_sub(2).max(1) as u32;
+    let aspect = screenshot.width() as f32 / screenshot.height().max(1) as f32;
+    let columns = ((2 * rows) as f32 * aspect).round() as u32;
+    let columns = columns.clamp(1, (area.width / 2).saturating_sub(2).max(1) as u32);
+    let pixels = image::imageops::resize(
+        screenshot,
+        columns,
+        2 * rows,
+        image::imageops::FilterType::Triangle,
+    );
+    let color = |x, y| {
+        let image::Rgb([r, g, b]) = *pixels.get_pixel(x, y);
+        Color::Rgb(r, g, b)
+    };
+    let text: Vec<Spans> = (0..rows)
+        .map(|row| {
+            let cells = (0..columns).map(|x| {
+                let style = Style::default()
+                    .fg(color(x, 2 * row))
+                    .bg(color(x, 2 * row + 1));
+                Span::styled("▀", style)
+            });
+            Spans::from(cells.collect::<Vec<_>>())
+        })
+        .collect();
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .border_type(BorderType::Rounded);
+    (Paragraph::new(text).block(block), columns as u16 + 2)
+}
+
 /// Widget showing details about a selected filehost item
 fn make_fileinfo_widget(filetable: &StatefulTable<filehost::Record>) -> Paragraph {
     let sel = filetable.state.selected().unwrap_or(0);
-    let item = &filetable.items[sel];
-    let fileinfo_text = vec![
-        Spans::from(format!("Title:     {}", item.title)),
-        Spans::from(format!("Filename:  {}", item.filename)),
-        Spans::from(format!("Category:  {} - {}", item.category, item.kind)),
-        Spans::from(format!("Author:    {}", item.author)),
-        Spans::from(format!("Published: {}", item.published)),
-        Spans::from(format!("Rating:    {}", item.rating)),
-    ];
+    let fileinfo_text = match filetable.items.get(sel) {
+        Some(item) => vec![
+            Spans::from(format!("Title:     {}", item.title)),
+            Spans::from(format!("Filename:  {}", item.filename)),
+            Spans::from(format!("Category:  {} - {}", item.category, item.kind)),
+            Spans::from(format!("Author:    {}", item.author)),
+            Spans::from(format!("Published: {}", item.published)),
+            Spans::from(format!("Rating:    {}", item.rating)),
+        ],
+        None => vec![Spans::from("No matching files")],
+    };
     let block = Block::default()
         .title(Span::styled(
             "File Info",
@@ -269,12 +397,16 @@ fn make_fileinfo_widget(filetable: &StatefulTable<filehost::Record>) -> Paragrap
 }
 
 /// Table with all FileHost records
-fn make_files_widget(filehost_items: &[filehost::Record]) -> Table {
+fn make_files_widget(
+    filehost_items: &[filehost::Record],
+    header: [String; 3],
+    title: String,
+) -> Table {
     let selected_style = Style::default().add_modifier(Modifier::REVERSED);
     let normal_style = Style::default().bg(Color::Blue);
-    let header_cells = ["Title", "Type", "Author"]
-        .iter()
-        .map(|h| Cell::from(*h).style(Style::default().fg(Color::Yellow)));
+    let header_cells = header
+        .into_iter()
+        .map(|h| Cell::from(h).style(Style::default().fg(Color::Yellow)));
     let header = Row::new(header_cells)
         .style(normal_style)
         .height(1)
@@ -297,7 +429,7 @@ fn make_files_widget(filehost_items: &[filehost::Record]) -> Table {
                 .borders(Borders::ALL)
                 .border_type(BorderType::Rounded)
                 .title(Span::styled(
-                    "🌈 Filehost entries",
+                    title,
                     Style::default().add_modifier(Modifier::BOLD),
                 )),
         )
@@ -311,104 +443,149 @@ fn make_files_widget(filehost_items: &[filehost::Record]) -> Table {
     table
 }
 
-pub struct StatefulList<T> {
-    pub state: ListState,
-    pub items: Vec<T>,
-}
-
-impl<T> StatefulList<T> {
-    pub fn with_items(items: Vec<T>) -> StatefulList<T> {
-        StatefulList {
-            state: ListState::default(),
-            items,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tui::{backend::TestBackend, Terminal};
+
+    const SIZES: [(u16, u16); 4] = [(0, 0), (1, 1), (5, 5), (80, 24)];
+
+    #[test]
+    fn centered_rect_fits_inside_area() {
+        for (width, height) in SIZES {
+            let area = Rect::new(2, 3, width, height);
+            let rect = centered_rect(50, 11, area);
+            assert_eq!(rect.intersection(area), rect);
+            assert_eq!(rect.width, width.min(50));
+            assert_eq!(rect.height, height.min(11));
         }
+        assert_eq!(
+            centered_rect(40, 4, Rect::new(0, 0, 80, 24)),
+            Rect::new(20, 10, 40, 4)
+        );
     }
 
-    pub fn next(&mut self) {
-        let i = match self.state.selected() {
-            Some(i) => {
-                if i >= self.items.len() - 1 {
-                    0
-                } else {
-                    i + 1
+    #[test]
+    fn popups_render_at_any_size() {
+        let records = vec![filehost::Record {
+            title: "Plasma".to_string(),
+            ..Default::default()
+        }];
+        let mut app = App::new(&records);
+        app.cbm_browser.items = vec!["PLASMA.PRG".to_string()];
+        app.filetable.items[0].screenshot = Some("plasma.png".to_string());
+        app.screenshots.insert(
+            app.filetable.items[0].screenshot_url().unwrap(),
+            Some(image::RgbImage::new(320, 200)),
+        );
+        let widgets = [
+            AppWidgets::FileSelector,
+            AppWidgets::FileAction,
+            AppWidgets::CBMBrowser,
+            AppWidgets::Help,
+            AppWidgets::Search,
+        ];
+        let progresses = [
+            None,
+            Some(Progress::indeterminate("Downloading")),
+            Some(Progress::from_count("Transferring", 1, 2)),
+        ];
+        for (width, height) in SIZES {
+            let mut terminal = Terminal::new(TestBackend::new(width, height)).unwrap();
+            for widget in widgets {
+                for progress in &progresses {
+                    app.set_current_widget(widget);
+                    app.busy = progress.is_some();
+                    app.progress = progress.clone();
+                    terminal.draw(|f| ui(f, &mut app)).unwrap();
                 }
             }
-            None => 0,
-        };
-        self.state.select(Some(i));
+        }
     }
 
-    pub fn previous(&mut self) {
-        let i = match self.state.selected() {
-            Some(i) => {
-                if i == 0 {
-                    self.items.len() - 1
-                } else {
-                    i - 1
-                }
-            }
-            None => 0,
+    #[test]
+    fn search_filters_and_clamps_selection() {
+        let record = |title: &str, author: &str| filehost::Record {
+            title: title.to_string(),
+            author: author.to_string(),
+            ..Default::default()
         };
-        self.state.select(Some(i));
-    }
-
-    pub fn is_selected(&self) -> bool {
-        self.state.selected().is_some()
+        let records = vec![
+            record("Plasma", "wombat"),
+            record("Snake", "Someone"),
+            record("Commando", "WOMBAT"),
+        ];
+        let mut app = App::new(&records);
+        app.filetable.state.select(Some(2));
+        app.start_search();
+        "womb".chars().for_each(|c| app.push_search(c));
+        assert_eq!(app.filetable.items.len(), 2);
+        assert_eq!(app.filetable.state.selected(), Some(1));
+        app.push_search('x');
+        assert!(app.filetable.items.is_empty());
+        assert_eq!(app.filetable.state.selected(), None);
+        let mut terminal = Terminal::new(TestBackend::new(80, 24)).unwrap();
+        terminal.draw(|f| ui(f, &mut app)).unwrap();
+        app.next_item();
+        app.clear_search();
+        assert_eq!(app.filetable.items.len(), 3);
+        assert_eq!(app.filetable.state.selected(), Some(0));
     }
 
-    pub fn unselect(&mut self) {
-        self.state.select(None);
+    #[test]
+    fn navigate_empty_cbm_browser() {
+        let mut app = App::new(&[]);
+        app.set_current_widget(AppWidgets::CBMBrowser);
+        app.next_item();
+        app.previous_item();
+        assert_eq!(app.cbm_browser.state.selected(), None);
+        app.set_current_widget(AppWidgets::FileSelector);
+        app.next_item();
+        assert_eq!(app.filetable.state.selected(), None);
     }
-}
-
-pub struct StatefulTable<T> {
-    pub state: TableState,
-    pub items: Vec<T>,
-}
 
-impl<T> StatefulTable<T> {
-    pub fn with_items(items: Vec<T>) -> StatefulTable<T> {
-        StatefulTable {
-            state: TableState::default(),
-            items,
-        }
+    #[test]
+    fn mouse_position_maps_to_filehost_row() {
+        let records: Vec<_> = (0..40)
+            .map(|i| filehost::Record {
+                title: format!("File {}", i),
+                ..Default::default()
+            })
+            .collect();
+        let mut app = App::new(&records);
+        let mut terminal = Terminal::new(TestBackend::new(80, 24)).unwrap();
+        terminal.draw(|f| ui(f, &mut app)).unwrap();
+        assert_eq!(app.filehost_row_at(5, 0), None); // border
+        assert_eq!(app.filehost_row_at(5, 1), None); // header
+        assert_eq!(app.filehost_row_at(5, 2), Some(0));
+        assert_eq!(app.filehost_row_at(5, 4), Some(2));
+        app.filetable.state.select(Some(30));
+        terminal.draw(|f| ui(f, &mut app)).unwrap();
+        let last_row = app.files_area.y + app.files_area.height - 2;
+        assert_eq!(app.filehost_row_at(5, last_row), Some(30));
     }
 
-    pub fn next(&mut self) {
-        let i = match self.state.selected() {
-            Some(i) => {
-                if i >= self.items.len() - 1 {
-                    0
-                } else {
-                    i + 1
-                }
-            }
-            None => 0,
+    #[test]
+    fn sort_downloads_numerically_and_reverse() {
+        let record = |title: &str, downloads: &str| filehost::Record {
+            title: title.to_string(),
+            downloads: downloads.to_string(),
+            ..Default::default()
         };
-        self.state.select(Some(i));
-    }
-
-    pub fn previous(&mut self) {
-        let i = match self.state.selected() {
-            Some(i) => {
-                if i == 0 {
-                    self.items.len() - 1
-                } else {
-                    i - 1
-                }
-            }
-            None => 0,
+        let records = vec![record("a", "9"), record("b", "n/a"), record("c", "10")];
+        let mut app = App::new(&records);
+        while app.filehost_sort != FilehostSort::Downloads {
+            app.sort();
+        }
+        let titles = |app: &App| -> Vec<String> {
+            app.filetable
+                .items
+                .iter()
+                .map(|i| i.title.clone())
+                .collect()
         };
-        self.state.select(Some(i));
-    }
-
-    #[allow(dead_code)]
-    pub fn is_selected(&self) -> bool {
-        self.state.selected().is_some()
-    }
-
-    #[allow(dead_code)]
-    pub fn unselect(&mut self) {
-        self.state.select(None);
+        assert_eq!(titles(&app), ["c", "a", "b"]);
+        app.reverse_sort();
+        assert_eq!(titles(&app), ["b", "a", "c"]);
     }
 }
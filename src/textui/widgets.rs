@@ -0,0 +1,131 @@
+// copyright 2022 mikael lund aka wombat
+//
+// licensed under the apache license, version 2.0 (the "license");
+// you may not use this file except in compliance with the license.
+// you may obtain a copy of the license at
+//
+//     http://www.apache.org/licenses/license-2.0
+//
+// unless required by applicable law or agreed to in writing, software
+// distributed under the license is distributed on an "as is" basis,
+// without warranties or conditions of any kind, either express or implied.
+// see the license for the specific language governing permissions and
+// limitations under the license.
+
+//! Lists and tables that keep track of the selected item
+
+use tui::widgets::{ListState, TableState};
+
+/// Index after `selected`, wrapping to the first item; `None` if there are no items
+fn next_index(selected: Option<usize>, len: usize) -> Option<usize> {
+    match (selected, len) {
+        (_, 0) => None,
+        (Some(i), _) if i + 1 < len => Some(i + 1),
+        _ => Some(0),
+    }
+}
+
+/// Index before `selected`, wrapping to the last item; `None` if there are no items
+fn previous_index(selected: Option<usize>, len: usize) -> Option<usize> {
+    match (selected, len) {
+        (_, 0) => None,
+        (Some(i), _) if i > 0 && i < len => Some(i - 1),
+        (Some(_), _) => Some(len - 1),
+        (None, _) => Some(0),
+    }
+}
+
+pub struct StatefulList<T> {
+    pub state: ListState,
+    pub items: Vec<T>,
+}
+
+impl<T> StatefulList<T> {
+    pub fn with_items(items: Vec<T>) -> StatefulList<T> {
+        StatefulList {
+            state: ListState::default(),
+            items,
+        }
+    }
+
+    pub fn next(&mut self) {
+        self.state
+            .select(next_index(self.state.selected(), self.items.len()));
+    }
+
+    pub fn previous(&mut self) {
+        self.state
+            .select(previous_index(self.state.selected(), self.items.len()));
+    }
+
+    pub fn is_selected(&self) -> bool {
+        self.state.selected().is_some()
+    }
+
+    pub fn unselect(&mut self) {
+        self.state.select(None);
+    }
+}
+
+pub struct StatefulTable<T> {
+    pub state: TableState,
+    pub items: Vec<T>,
+}
+
+impl<T> StatefulTable<T> {
+    pub fn with_items(items: Vec<T>) -> StatefulTable<T> {
+        StatefulTable {
+            state: TableState::default(),
+            items,
+        }
+    }
+
+    pub fn next(&mut self) {
+        self.state
+            .select(next_index(self.state.selected(), self.items.len()));
+    }
+
+    pub fn previous(&mut self) {
+        self.state
+            .select(previous_index(self.state.selected(), self.items.len()));
+    }
+
+    #[allow(dead_code)]
+    pub fn is_selected(&self) -> bool {
+        self.state.selected().is_some()
+    }
+
+    #[allow(dead_code)]
+    pub fn unselect(&mut self) {
+        self.state.select(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_and_previous_wrap_around() {
+        let mut empty = StatefulList::<u8>::with_items(vec![]);
+        empty.next();
+        empty.previous();
+        assert_eq!(empty.state.selected(), None);
+
+        let mut single = StatefulTable::with_items(vec![1]);
+        single.next();
+        assert_eq!(single.state.selected(), Some(0));
+        single.next();
+        assert_eq!(single.state.selected(), Some(0));
+        single.previous();
+        assert_eq!(single.state.selected(), Some(0));
+
+        let mut list = StatefulList::with_items(vec![1, 2, 3]);
+        list.previous();
+        assert_eq!(list.state.selected(), Some(0));
+        list.previous();
+        assert_eq!(list.state.selected(), Some(2));
+        list.next();
+        assert_eq!(list.state.selected(), Some(0));
+    }
+}